@@ -0,0 +1,252 @@
+//! Collateralization of USN.
+//!
+//! The collateral is NEAR reserves valued at the cached oracle price, plus USDT reserves
+//! and stablecoins of the peg stability module at par, plus NEAR of vaults up to their debt.
+//! Below the minimum ratio, non-collateralized mints are blocked, and buys follow the configured policy.
+
+use near_sdk::require;
+
+use crate::pool::extend_decimals;
+use crate::*;
+
+/// The collateral ratio has 6 decimals: 1_000_000 is 100%.
+const COLLATERAL_RATIO_DECIMAL: u8 = 6;
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BuyPolicy {
+    /// Buys keep working, because they bring NEAR collateral.
+    Allow,
+    /// Buys are blocked.
+    Block,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct CollateralPolicy {
+    /// 0 disables the check.
+    min_ratio: u128,
+    buy_policy: BuyPolicy,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollateralPolicyView {
+    pub min_ratio: U128,
+    pub buy_policy: BuyPolicy,
+}
+
+impl Default for CollateralPolicy {
+    fn default() -> Self {
+        Self {
+            min_ratio: 0,
+            buy_policy: BuyPolicy::Allow,
+        }
+    }
+}
+
+impl Contract {
+    /// USN value of the collateral, if there is a cached oracle price.
+    pub(crate) fn collateral_value(&self) -> Option<Balance> {
+        let near_value = self.near_reserve_value()?;
+
+        let stable_value = self.psm.values().fold(0, |value: Balance, asset| {
+            value + extend_decimals(asset.reserve, TOKEN_DECIMAL - asset.decimals)
+        });
+
+        let vault_value = self.vault_collateral_value()?;
+
+        Some(near_value + self.usdt_reserve_value() + stable_value + vault_value)
+    }
+
+    /// Checks whether the collateral ratio is below the minimum.
+    /// The unknown ratio, e.g. without a cached price, counts as below the minimum.
+    pub(crate) fn is_undercollateralized(&self) -> bool {
+        let min_ratio = self.collateral_policy.min_ratio;
+        if min_ratio == 0 || self.token.total_supply == 0 {
+            return false;
+        }
+
+        match self.collateral_ratio() {
+            Some(ratio) => ratio.0 < min_ratio,
+            None => true,
+        }
+    }
+
+    /// Panics if new USN can't be minted without collateral.
+    pub(crate) fn assert_collateralized(&self) {
+        require!(
+            !self.is_undercollateralized(),
+            "The collateral ratio is below the minimum"
+        );
+    }
+
+    pub(crate) fn assert_buy_allowed(&self) {
+        if self.collateral_policy.buy_policy == BuyPolicy::Block {
+            self.assert_collateralized();
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns the collateral value divided by the USN supply with 6 decimals.
+    /// Returns `None` if there is no cached oracle price or no USN supply.
+    pub fn collateral_ratio(&self) -> Option<U128> {
+        let supply = self.token.total_supply;
+        if supply == 0 {
+            return None;
+        }
+
+        self.collateral_value().map(|value| {
+            let ratio = U256::from(value) * U256::from(10u128.pow(COLLATERAL_RATIO_DECIMAL as u32))
+                / U256::from(supply);
+            U128(ratio.as_u128())
+        })
+    }
+
+    pub fn collateral_policy(&self) -> CollateralPolicyView {
+        CollateralPolicyView {
+            min_ratio: self.collateral_policy.min_ratio.into(),
+            buy_policy: self.collateral_policy.buy_policy,
+        }
+    }
+
+    /// Sets the minimum collateral ratio (0 disables it) and the buy policy below the minimum.
+    pub fn set_collateral_policy(&mut self, min_ratio: U128, buy_policy: BuyPolicy) {
+        self.assert_owner();
+
+        self.collateral_policy = CollateralPolicy {
+            min_ratio: min_ratio.0,
+            buy_policy,
+        };
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::pool::RefFinanceHandler;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    /// Buys USN for 100 NEAR, then mints the same USN amount without collateral: the ratio is 50%.
+    fn undercollateralized_contract() -> Contract {
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+
+        let rate = ExchangeRate::test_fresh_rate();
+        contract.finish_buy(accounts(2), accounts(2), 100 * ONE_NEAR, None, rate);
+        contract
+            .token
+            .internal_deposit(&accounts(3), 1114390000000000000000);
+        contract
+    }
+
+    #[test]
+    fn test_collateral_ratio() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        assert!(contract.collateral_ratio().is_none());
+
+        contract.set_fixed_spread(10000.into()); // 1%
+        let rate = ExchangeRate::test_fresh_rate();
+        contract.finish_buy(accounts(2), accounts(2), 100 * ONE_NEAR, None, rate);
+
+        // 99 NEAR of reserves back $1103.2461 of USN.
+        assert_eq!(contract.collateral_ratio().unwrap().0, 1_000_000);
+
+        contract
+            .token
+            .internal_deposit(&accounts(3), 1103246100000000000000);
+        assert_eq!(contract.collateral_ratio().unwrap().0, 500_000);
+
+        contract.set_collateral_policy(600_000.into(), BuyPolicy::Allow);
+        assert!(contract.is_undercollateralized());
+        contract.assert_buy_allowed();
+    }
+
+    #[test]
+    fn test_psm_collateral() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.oracle.last_report = Some(ExchangeRate::test_fresh_rate());
+
+        // 1000 USDT are held against 900 USN: the reserve counts at par, not the debt.
+        contract.psm_add_asset(accounts(4), 6, 0.into(), U128(u128::MAX));
+        let mut asset = contract.psm.get(&accounts(4)).unwrap();
+        asset.debt = 900 * 10u128.pow(TOKEN_DECIMAL as u32);
+        asset.reserve = 1000 * 10u128.pow(6);
+        contract.psm.insert(&accounts(4), &asset);
+        contract.token.internal_deposit(&accounts(2), asset.debt);
+
+        assert_eq!(
+            contract.collateral_value().unwrap(),
+            1000 * 10u128.pow(TOKEN_DECIMAL as u32)
+        );
+    }
+
+    #[test]
+    fn test_unknown_collateral_ratio() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_collateral_policy(600_000.into(), BuyPolicy::Block);
+        assert!(!contract.is_undercollateralized());
+
+        // USN without a cached oracle price.
+        contract.token.internal_deposit(&accounts(2), ONE_NEAR);
+        assert!(contract.collateral_ratio().is_none());
+        assert!(contract.is_undercollateralized());
+
+        contract.set_collateral_policy(0.into(), BuyPolicy::Block);
+        assert!(!contract.is_undercollateralized());
+    }
+
+    #[test]
+    #[should_panic(expected = "The collateral ratio is below the minimum")]
+    fn test_buy_blocked() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = undercollateralized_contract();
+        contract.set_collateral_policy(600_000.into(), BuyPolicy::Block);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_NEAR)
+            .build());
+        contract.buy(None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The collateral ratio is below the minimum")]
+    fn test_pool_mint_blocked() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = undercollateralized_contract();
+        contract.set_collateral_policy(600_000.into(), BuyPolicy::Allow);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(b"\"1000000000\"".to_vec())],
+        );
+        contract.handle_transfer_then_mint(0, vec![1_000_000_000.into()]);
+    }
+}
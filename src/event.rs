@@ -1,8 +1,23 @@
 pub mod emit {
     use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+    use near_sdk::serde_json::{json, Value};
 
     use crate::*;
 
+    const EVENT_STANDARD: &str = "usn";
+    const EVENT_VERSION: &str = "1.0.0";
+
+    /// Emits a NEP-297 event, which is specific to the USN contract.
+    fn usn_event(event: &str, data: Value) {
+        let event = json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_VERSION,
+            "event": event,
+            "data": [data],
+        });
+        env::log_str(&format!("EVENT_JSON:{}", event));
+    }
+
     pub fn ft_mint(owner_id: &AccountId, amount: Balance, memo: Option<&str>) {
         (FtMint {
             owner_id: owner_id,
@@ -20,4 +35,172 @@ pub mod emit {
         })
         .emit();
     }
+
+    pub fn psm_mint(account_id: &AccountId, asset_id: &AccountId, deposit: Balance, fee: Balance) {
+        usn_event(
+            "psm_mint",
+            json!({
+                "account_id": account_id,
+                "asset_id": asset_id,
+                "deposit": U128(deposit),
+                "fee": U128(fee),
+            }),
+        );
+    }
+
+    pub fn psm_redeem(account_id: &AccountId, asset_id: &AccountId, amount: Balance, fee: Balance) {
+        usn_event(
+            "psm_redeem",
+            json!({
+                "account_id": account_id,
+                "asset_id": asset_id,
+                "amount": U128(amount),
+                "fee": U128(fee),
+            }),
+        );
+    }
+
+    pub fn payout_failed(account_id: &AccountId, amount: Balance) {
+        usn_event(
+            "payout_failed",
+            json!({
+                "account_id": account_id,
+                "amount": U128(amount),
+            }),
+        );
+    }
+
+    pub fn payout_claimed(account_id: &AccountId, receiver_id: &AccountId, amount: Balance) {
+        usn_event(
+            "payout_claimed",
+            json!({
+                "account_id": account_id,
+                "receiver_id": receiver_id,
+                "amount": U128(amount),
+            }),
+        );
+    }
+
+    pub fn redemption_queued(id: u64, account_id: &AccountId, usn: Balance, near: Balance) {
+        usn_event(
+            "redemption_queued",
+            json!({
+                "id": U64(id),
+                "account_id": account_id,
+                "usn": U128(usn),
+                "near": U128(near),
+            }),
+        );
+    }
+
+    pub fn redemption_filled(id: u64, account_id: &AccountId, near: Balance) {
+        usn_event(
+            "redemption_filled",
+            json!({
+                "id": U64(id),
+                "account_id": account_id,
+                "near": U128(near),
+            }),
+        );
+    }
+
+    pub fn global_settlement(near: Balance, supply: Balance) {
+        usn_event(
+            "global_settlement",
+            json!({
+                "near": U128(near),
+                "supply": U128(supply),
+            }),
+        );
+    }
+
+    pub fn settlement_redeemed(account_id: &AccountId, usn: Balance, near: Balance) {
+        usn_event(
+            "settlement_redeemed",
+            json!({
+                "account_id": account_id,
+                "usn": U128(usn),
+                "near": U128(near),
+            }),
+        );
+    }
+
+    pub fn flash_mint(borrower_id: &AccountId, amount: Balance, fee: Balance, unpaid: Balance) {
+        usn_event(
+            "flash_mint",
+            json!({
+                "borrower_id": borrower_id,
+                "amount": U128(amount),
+                "fee": U128(fee),
+                "unpaid": U128(unpaid),
+            }),
+        );
+    }
+
+    pub fn vault_liquidated(
+        account_id: &AccountId,
+        keeper_id: &AccountId,
+        debt: Balance,
+        collateral: Balance,
+    ) {
+        usn_event(
+            "vault_liquidated",
+            json!({
+                "account_id": account_id,
+                "keeper_id": keeper_id,
+                "debt": U128(debt),
+                "collateral": U128(collateral),
+            }),
+        );
+    }
+
+    pub fn savings_deposit(account_id: &AccountId, usn: Balance, shares: Balance) {
+        usn_event(
+            "savings_deposit",
+            json!({
+                "account_id": account_id,
+                "usn": U128(usn),
+                "shares": U128(shares),
+            }),
+        );
+    }
+
+    pub fn savings_withdraw(account_id: &AccountId, usn: Balance, shares: Balance) {
+        usn_event(
+            "savings_withdraw",
+            json!({
+                "account_id": account_id,
+                "usn": U128(usn),
+                "shares": U128(shares),
+            }),
+        );
+    }
+
+    pub fn peg_update(keeper_id: &AccountId, mint: bool, amount: Balance) {
+        usn_event(
+            "peg_update",
+            json!({
+                "keeper_id": keeper_id,
+                "action": if mint { "mint" } else { "burn" },
+                "amount": U128(amount),
+            }),
+        );
+    }
+
+    pub fn stable_liquidity_withdrawn(pool_id: u64, usn: Balance, tokens: &[(AccountId, Balance)]) {
+        usn_event(
+            "stable_liquidity_withdrawn",
+            json!({
+                "pool_id": pool_id,
+                "usn": U128(usn),
+                "tokens": tokens
+                    .iter()
+                    .map(|(token_id, amount)| json!({
+                        "token_id": token_id,
+                        "amount": U128(*amount),
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+        );
+    }
 }
@@ -0,0 +1,398 @@
+//! Flash mints.
+//!
+//! A borrower contract, allowed by the owner, mints USN to itself, gets `on_flash_mint` and can use the USN within the promise chain,
+//! e.g. to restore the peg of the stable pool. Then the callback takes the amount plus the fee back
+//! from the borrower balance: the amount is burned, and the fee goes to the contract account.
+//! A shortfall fails the flash mint: the minted amount is clawed back without the fee, and the part,
+//! which can't be clawed back, is recorded as the debt of the borrower, blocking its flash mints until it's repaid.
+//! Only reviewed borrowers are allowed: a borrower, which gives the minted USN away, leaves it unbacked.
+
+use near_sdk::collections::{LookupMap, UnorderedSet};
+use near_sdk::{require, IntoStorageKey};
+
+use crate::supply::MintSource;
+use crate::*;
+
+const GAS_FOR_ON_FLASH_MINT: Gas = Gas(100_000_000_000_000);
+const GAS_FOR_HANDLE_FLASH_MINT: Gas = Gas(10_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FlashMint {
+    /// 0 disables flash mints.
+    max_amount: Balance,
+    /// The fee has 6 decimals, as the spread.
+    fee: Balance,
+    /// Fees collected at the contract account.
+    collected: Balance,
+    /// Minted USN, which hasn't been clawed back from failed flash mints.
+    debts: LookupMap<AccountId, Balance>,
+    /// Borrower contracts allowed to flash mint.
+    borrowers: UnorderedSet<AccountId>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FlashMintView {
+    pub max_amount: U128,
+    pub fee: U128,
+    pub collected: U128,
+}
+
+impl FlashMint {
+    pub fn new<S, B>(prefix: S, borrowers_prefix: B) -> Self
+    where
+        S: IntoStorageKey,
+        B: IntoStorageKey,
+    {
+        Self {
+            max_amount: 0,
+            fee: 0,
+            collected: 0,
+            debts: LookupMap::new(prefix),
+            borrowers: UnorderedSet::new(borrowers_prefix),
+        }
+    }
+
+    fn fee_of(&self, amount: Balance) -> Balance {
+        (U256::from(amount) * U256::from(self.fee) / U256::from(10u128.pow(SPREAD_DECIMAL as u32)))
+            .as_u128()
+    }
+}
+
+#[ext_contract(ext_flash_borrower)]
+pub(crate) trait FlashBorrower {
+    /// The borrower must hold `amount + fee` USN when the call ends.
+    fn on_flash_mint(&mut self, initiator_id: AccountId, amount: U128, fee: U128, msg: String);
+}
+
+#[ext_contract(ext_flash_self)]
+trait FlashMintHandler {
+    #[private]
+    fn handle_flash_mint(&mut self, borrower_id: AccountId, amount: U128, fee: U128) -> U128;
+}
+
+pub(crate) trait FlashMintHandler {
+    fn handle_flash_mint(&mut self, borrower_id: AccountId, amount: U128, fee: U128) -> U128;
+}
+
+#[near_bindgen]
+impl FlashMintHandler for Contract {
+    /// Takes the flash minted USN and the fee back from the borrower.
+    /// If the borrower can't repay both, only the minted amount is clawed back.
+    /// Returns the repaid amount including the fee.
+    #[private]
+    fn handle_flash_mint(&mut self, borrower_id: AccountId, amount: U128, fee: U128) -> U128 {
+        let (amount, fee) = (amount.0, fee.0);
+        let balance = self.token.internal_unwrap_balance_of(&borrower_id);
+
+        if balance >= amount + fee {
+            self.internal_burn_flash(&borrower_id, amount);
+            if fee > 0 {
+                self.token.internal_transfer(
+                    &borrower_id,
+                    &env::current_account_id(),
+                    fee,
+                    Some("Flash mint fee".to_string()),
+                );
+                self.flash.collected += fee;
+            }
+            event::emit::flash_mint(&borrower_id, amount, fee, 0);
+            return U128(amount + fee);
+        }
+
+        let clawed_back = std::cmp::min(balance, amount);
+        self.internal_burn_flash(&borrower_id, clawed_back);
+
+        let unpaid = amount - clawed_back;
+        if unpaid > 0 {
+            self.flash.debts.insert(&borrower_id, &unpaid);
+        }
+        event::emit::flash_mint(&borrower_id, amount, 0, unpaid);
+
+        U128(clawed_back)
+    }
+}
+
+impl Contract {
+    fn internal_burn_flash(&mut self, borrower_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            self.token.internal_withdraw(borrower_id, amount);
+            self.record_burn(MintSource::Flash, amount);
+            event::emit::ft_burn(borrower_id, amount, Some("Flash mint"));
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Mints USN to the borrower contract and calls its `on_flash_mint`.
+    /// Only the borrower itself can flash mint, and it must hold the amount plus the fee by the end of the call.
+    #[payable]
+    pub fn flash_mint(&mut self, borrower_id: AccountId, amount: U128, msg: String) -> Promise {
+        assert_one_yocto();
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        require!(
+            env::predecessor_account_id() == borrower_id,
+            "Only the borrower can flash mint"
+        );
+        require!(
+            self.flash.borrowers.contains(&borrower_id),
+            "The borrower isn't allowed to flash mint"
+        );
+
+        let amount = amount.0;
+
+        require!(amount > 0, "The amount should be positive");
+        require!(
+            amount <= self.flash.max_amount,
+            "The flash mint limit is exceeded"
+        );
+        require!(
+            borrower_id != env::current_account_id(),
+            "The contract can't borrow"
+        );
+        if self.blacklist_status(&borrower_id) != BlackListStatus::Allowable {
+            env::panic_str(&format!("Account '{}' is banned", borrower_id));
+        }
+        require!(
+            self.flash.debts.get(&borrower_id).is_none(),
+            "The borrower has an unpaid flash mint"
+        );
+
+        let fee = self.flash.fee_of(amount);
+
+        self.internal_mint(&borrower_id, amount, MintSource::Flash, Some("Flash mint"));
+
+        ext_flash_borrower::on_flash_mint(
+            env::predecessor_account_id(),
+            amount.into(),
+            fee.into(),
+            msg,
+            borrower_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_ON_FLASH_MINT,
+        )
+        .then(ext_flash_self::handle_flash_mint(
+            borrower_id,
+            amount.into(),
+            fee.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_FLASH_MINT,
+        ))
+    }
+
+    /// Burns the unpaid flash mint of the caller from its balance.
+    /// Returns the remaining debt.
+    #[payable]
+    pub fn repay_flash_mint(&mut self) -> U128 {
+        assert_one_yocto();
+
+        let borrower_id = env::predecessor_account_id();
+        let debt = self
+            .flash
+            .debts
+            .get(&borrower_id)
+            .unwrap_or_else(|| env::panic_str("No unpaid flash mint"));
+
+        let balance = self.token.internal_unwrap_balance_of(&borrower_id);
+        let repaid = std::cmp::min(balance, debt);
+        self.internal_burn_flash(&borrower_id, repaid);
+        let unpaid = debt - repaid;
+
+        if unpaid > 0 {
+            self.flash.debts.insert(&borrower_id, &unpaid);
+        } else {
+            self.flash.debts.remove(&borrower_id);
+        }
+
+        unpaid.into()
+    }
+
+    /// Sets the maximum flash mint amount (0 disables flash mints) and the fee.
+    pub fn set_flash_mint_policy(&mut self, max_amount: U128, fee: U128) {
+        self.assert_owner();
+
+        if fee.0 > MAX_SPREAD {
+            env::panic_str(&format!("Flash mint fee limit is {}", MAX_SPREAD));
+        }
+
+        self.flash.max_amount = max_amount.0;
+        self.flash.fee = fee.0;
+    }
+
+    /// Allows the borrower contract to flash mint.
+    pub fn add_flash_borrower(&mut self, borrower_id: AccountId) {
+        self.assert_owner();
+        self.flash.borrowers.insert(&borrower_id);
+    }
+
+    /// Disallows the borrower immediately. Can be called by owner or guardians.
+    pub fn remove_flash_borrower(&mut self, borrower_id: AccountId) {
+        self.assert_owner_or_guardian();
+        require!(
+            self.flash.borrowers.remove(&borrower_id),
+            "The account is not a flash borrower"
+        );
+    }
+
+    pub fn flash_borrowers(&self) -> Vec<AccountId> {
+        self.flash.borrowers.to_vec()
+    }
+
+    pub fn flash_mint_policy(&self) -> FlashMintView {
+        FlashMintView {
+            max_amount: self.flash.max_amount.into(),
+            fee: self.flash.fee.into(),
+            collected: self.flash.collected.into(),
+        }
+    }
+
+    pub fn flash_mint_debt(&self, account_id: AccountId) -> Option<U128> {
+        self.flash.debts.get(&account_id).map(U128)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use super::*;
+
+    const ONE_USN: Balance = 1_000_000_000_000_000_000;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn setup() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_flash_mint_policy((1000 * ONE_USN).into(), 1000.into()); // 0.1%
+        contract.add_flash_borrower(accounts(3));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        (context, contract)
+    }
+
+    #[test]
+    fn test_flash_mint() {
+        let (mut context, mut contract) = setup();
+
+        contract.flash_mint(accounts(3), (1000 * ONE_USN).into(), "".to_string());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 1000 * ONE_USN);
+
+        // The borrower has earned 2 USN.
+        contract.token.internal_deposit(&accounts(3), 2 * ONE_USN);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let repaid =
+            contract.handle_flash_mint(accounts(3), (1000 * ONE_USN).into(), ONE_USN.into());
+
+        assert_eq!(repaid.0, 1001 * ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, ONE_USN);
+        assert_eq!(contract.ft_total_supply().0, 2 * ONE_USN);
+        assert_eq!(contract.flash_mint_policy().collected.0, ONE_USN);
+        assert!(contract.flash_mint_debt(accounts(3)).is_none());
+    }
+
+    #[test]
+    fn test_flash_mint_unpaid_fee() {
+        let (mut context, mut contract) = setup();
+
+        // The borrower has its own USN.
+        contract.token.internal_deposit(&accounts(3), ONE_USN / 2);
+        contract.flash_mint(accounts(3), (1000 * ONE_USN).into(), "".to_string());
+
+        // The fee isn't taken from USN, which hasn't been minted by the flash mint.
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let repaid =
+            contract.handle_flash_mint(accounts(3), (1000 * ONE_USN).into(), ONE_USN.into());
+
+        assert_eq!(repaid.0, 1000 * ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, ONE_USN / 2);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 0);
+        assert_eq!(contract.flash_mint_policy().collected.0, 0);
+        assert!(contract.flash_mint_debt(accounts(3)).is_none());
+    }
+
+    #[test]
+    fn test_flash_mint_shortfall() {
+        let (mut context, mut contract) = setup();
+
+        contract.flash_mint(accounts(3), (1000 * ONE_USN).into(), "".to_string());
+
+        // The borrower has lost 100 USN.
+        contract
+            .token
+            .internal_withdraw(&accounts(3), 100 * ONE_USN);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let repaid =
+            contract.handle_flash_mint(accounts(3), (1000 * ONE_USN).into(), ONE_USN.into());
+
+        assert_eq!(repaid.0, 900 * ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 0);
+        assert_eq!(
+            contract.flash_mint_debt(accounts(3)).unwrap().0,
+            100 * ONE_USN
+        );
+
+        contract.token.internal_deposit(&accounts(3), 200 * ONE_USN);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        assert_eq!(contract.repay_flash_mint().0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 100 * ONE_USN);
+        assert!(contract.flash_mint_debt(accounts(3)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the borrower can flash mint")]
+    fn test_flash_mint_for_another_borrower() {
+        let (mut context, mut contract) = setup();
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.flash_mint(accounts(3), (1000 * ONE_USN).into(), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "The borrower isn't allowed to flash mint")]
+    fn test_flash_mint_removed_borrower() {
+        let (mut context, mut contract) = setup();
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_flash_borrower(accounts(3));
+        assert!(contract.flash_borrowers().is_empty());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.flash_mint(accounts(3), (1000 * ONE_USN).into(), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "The flash mint limit is exceeded")]
+    fn test_flash_mint_limit() {
+        let (_, mut contract) = setup();
+
+        contract.flash_mint(accounts(3), (1001 * ONE_USN).into(), "".to_string());
+    }
+}
@@ -0,0 +1,474 @@
+//! Journal of buy/sell operations identified by caller-provided ids.
+//!
+//! Each operation spans several receipts: the oracle call, the callback, the refund or payout.
+//! The journal keeps the status of recent operations, so the caller can query the outcome
+//! and safely retry with the same id. Only the last `MAX_OPERATIONS_PER_ACCOUNT` operations
+//! of an account are kept: starting a new one removes the oldest finished operation.
+//! Ids of removed operations are still remembered for `OPERATION_RETRY_WINDOW`, as short hashes,
+//! so a retry within the window can't start the operation again.
+
+use near_sdk::{require, Timestamp};
+
+use crate::*;
+
+const MAX_OPERATION_ID_LEN: usize = 64;
+const MAX_OPERATIONS_PER_ACCOUNT: usize = 16;
+const MAX_USED_IDS_PER_ACCOUNT: usize = 1024;
+/// 24 hours in nanoseconds.
+const OPERATION_RETRY_WINDOW: Timestamp = 24 * 60 * 60 * 1_000_000_000;
+
+/// Operation id is unique per caller.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OperationKey {
+    pub account_id: AccountId,
+    pub operation_id: String,
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OperationKind {
+    Buy,
+    Sell,
+}
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OperationStatus {
+    /// The operation is in flight.
+    Pending,
+    /// USN has been minted (buy) or NEAR has been paid out (sell).
+    Completed,
+    /// Nothing has been exchanged: NEAR is refunded (buy) or USN is kept (sell).
+    Failed,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub status: OperationStatus,
+    /// NEAR attached to the buy, or NEAR paid out for the sell.
+    pub near: Balance,
+    /// USN minted by the buy, or USN sold.
+    pub usn: Balance,
+    /// The exchange rate used: multiplier and decimals.
+    pub rate: Option<(u128, u8)>,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OperationView {
+    pub kind: OperationKind,
+    pub status: OperationStatus,
+    pub near: U128,
+    pub usn: U128,
+    pub rate: Option<ExpectedRate>,
+    pub timestamp: U64,
+}
+
+impl From<Operation> for OperationView {
+    fn from(operation: Operation) -> Self {
+        Self {
+            kind: operation.kind,
+            status: operation.status,
+            near: operation.near.into(),
+            usn: operation.usn.into(),
+            rate: operation.rate.map(|(multiplier, decimals)| ExpectedRate {
+                multiplier: multiplier.into(),
+                slippage: 0.into(),
+                decimals,
+            }),
+            timestamp: operation.timestamp.into(),
+        }
+    }
+}
+
+impl Contract {
+    /// Registers a new pending operation, removing the oldest finished operation of the account
+    /// if the journal of the account is full. Panics if the id is in the journal,
+    /// or has been used within the retry window.
+    pub(crate) fn journal_start(
+        &mut self,
+        key: &OperationKey,
+        kind: OperationKind,
+        near: Balance,
+        usn: Balance,
+    ) {
+        require!(
+            key.operation_id.len() <= MAX_OPERATION_ID_LEN,
+            &format!("Operation id is longer than {}", MAX_OPERATION_ID_LEN)
+        );
+
+        if self.operations.contains_key(key) {
+            env::panic_str(&format!("Operation '{}' already exists", key.operation_id));
+        }
+
+        let now = env::block_timestamp();
+        let mut used_ids = self
+            .used_operation_ids
+            .get(&key.account_id)
+            .unwrap_or_default();
+        used_ids.retain(|(_, timestamp)| now.saturating_sub(*timestamp) < OPERATION_RETRY_WINDOW);
+        if used_ids
+            .iter()
+            .any(|(hash, _)| *hash == operation_id_hash(&key.operation_id))
+        {
+            env::panic_str(&format!("Operation '{}' already exists", key.operation_id));
+        }
+
+        let mut ids = self.operation_ids.get(&key.account_id).unwrap_or_default();
+        if ids.len() >= MAX_OPERATIONS_PER_ACCOUNT {
+            let finished = ids
+                .iter()
+                .position(|id| {
+                    let operation = self.operations.get(&OperationKey {
+                        account_id: key.account_id.clone(),
+                        operation_id: id.clone(),
+                    });
+                    match operation {
+                        Some(operation) => operation.status != OperationStatus::Pending,
+                        None => true,
+                    }
+                })
+                .unwrap_or_else(|| env::panic_str("Too many pending operations"));
+
+            let operation_id = ids.remove(finished);
+            let removed = self.operations.remove(&OperationKey {
+                account_id: key.account_id.clone(),
+                operation_id: operation_id.clone(),
+            });
+            if let Some(removed) = removed {
+                require!(
+                    used_ids.len() < MAX_USED_IDS_PER_ACCOUNT,
+                    "Too many operations within the retry window"
+                );
+                used_ids.push((operation_id_hash(&operation_id), removed.timestamp));
+            }
+        }
+        ids.push(key.operation_id.clone());
+        self.operation_ids.insert(&key.account_id, &ids);
+        if used_ids.is_empty() {
+            self.used_operation_ids.remove(&key.account_id);
+        } else {
+            self.used_operation_ids.insert(&key.account_id, &used_ids);
+        }
+
+        let operation = Operation {
+            kind,
+            status: OperationStatus::Pending,
+            near,
+            usn,
+            rate: None,
+            timestamp: now,
+        };
+
+        self.operations.insert(key, &operation);
+    }
+
+    /// Updates amounts and the exchange rate of the operation, setting the status.
+    pub(crate) fn journal_record(
+        &mut self,
+        key: &Option<OperationKey>,
+        status: OperationStatus,
+        near: Balance,
+        usn: Balance,
+        rate: &ExchangeRate,
+    ) {
+        if let Some(key) = key {
+            if let Some(mut operation) = self.operations.get(key) {
+                operation.status = status;
+                operation.near = near;
+                operation.usn = usn;
+                operation.rate = Some((rate.multiplier(), rate.decimals()));
+                self.operations.insert(key, &operation);
+            }
+        }
+    }
+
+    /// Finishes the operation with the given status, if it's still pending.
+    pub(crate) fn journal_finish(&mut self, key: &Option<OperationKey>, status: OperationStatus) {
+        if let Some(key) = key {
+            if let Some(mut operation) = self.operations.get(key) {
+                if operation.status == OperationStatus::Pending {
+                    operation.status = status;
+                    self.operations.insert(key, &operation);
+                }
+            }
+        }
+    }
+}
+
+/// Short hash of the operation id, enough to tell recently used ids apart.
+fn operation_id_hash(operation_id: &str) -> u64 {
+    let hash = env::sha256(operation_id.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns the buy/sell operation registered by the account with the given id.
+    pub fn operation(&self, account_id: AccountId, operation_id: String) -> Option<OperationView> {
+        let key = OperationKey {
+            account_id,
+            operation_id,
+        };
+        self.operations.get(&key).map(|operation| operation.into())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR, ONE_YOCTO};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn key(operation_id: &str) -> Option<OperationKey> {
+        Some(OperationKey {
+            account_id: accounts(2),
+            operation_id: operation_id.to_string(),
+        })
+    }
+
+    fn set_promise_result(context: &mut VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![result],
+        );
+    }
+
+    #[test]
+    fn test_buy_operation() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_NEAR)
+            .build());
+        contract.buy(None, None, Some("op-1".to_string()));
+        contract.buy(None, None, Some("op-2".to_string()));
+
+        let operation = contract.operation(accounts(2), "op-1".to_string()).unwrap();
+        assert_eq!(operation.kind, OperationKind::Buy);
+        assert_eq!(operation.status, OperationStatus::Pending);
+        assert_eq!(operation.near.0, ONE_NEAR);
+        assert!(contract
+            .operation(accounts(3), "op-1".to_string())
+            .is_none());
+
+        set_promise_result(&mut context, PromiseResult::Successful(vec![]));
+        let amount = contract.buy_with_price_callback(
+            accounts(2),
+            accounts(2),
+            ONE_NEAR.into(),
+            None,
+            key("op-1"),
+            PriceData::test_price_data(111439, 28),
+        );
+
+        set_promise_result(&mut context, PromiseResult::Failed);
+        contract.handle_refund(accounts(2), ONE_NEAR.into(), key("op-2"));
+
+        let operation = contract.operation(accounts(2), "op-1".to_string()).unwrap();
+        assert_eq!(operation.status, OperationStatus::Completed);
+        assert_eq!(operation.usn, amount);
+        assert_eq!(operation.rate.unwrap().multiplier.0, 111439);
+
+        let operation = contract.operation(accounts(2), "op-2".to_string()).unwrap();
+        assert_eq!(operation.status, OperationStatus::Failed);
+    }
+
+    #[test]
+    fn test_sell_operation() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.token.internal_deposit(&accounts(2), 2 * ONE_NEAR);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.sell(ONE_NEAR.into(), None, Some("op-1".to_string()));
+        contract.sell(ONE_NEAR.into(), None, Some("op-2".to_string()));
+
+        // The 1st sell is aborted by the oracle callback.
+        set_promise_result(&mut context, PromiseResult::Failed);
+        contract.handle_operation_result(key("op-1").unwrap());
+
+        // The 2nd sell pays out.
+        let rate = ExchangeRate::test_fresh_rate();
+        let (deposit, fee) = contract.finish_sell(accounts(2), ONE_NEAR, None, rate.clone());
+        contract.journal_record(
+            &key("op-2"),
+            OperationStatus::Pending,
+            deposit,
+            ONE_NEAR,
+            &rate,
+        );
+        set_promise_result(&mut context, PromiseResult::Successful(vec![]));
+        contract.handle_sell_payout(
+            accounts(2),
+            ONE_NEAR.into(),
+            deposit.into(),
+            fee.into(),
+            key("op-2"),
+        );
+        contract.handle_operation_result(key("op-2").unwrap());
+
+        let operation = contract.operation(accounts(2), "op-1".to_string()).unwrap();
+        assert_eq!(operation.kind, OperationKind::Sell);
+        assert_eq!(operation.status, OperationStatus::Failed);
+
+        let operation = contract.operation(accounts(2), "op-2".to_string()).unwrap();
+        assert_eq!(operation.status, OperationStatus::Completed);
+        assert_eq!(operation.near.0, deposit);
+        assert_eq!(operation.usn.0, ONE_NEAR);
+    }
+
+    #[test]
+    fn test_operations_pruned() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        for i in 0..MAX_OPERATIONS_PER_ACCOUNT {
+            testing_env!(context
+                .predecessor_account_id(accounts(2))
+                .attached_deposit(ONE_NEAR)
+                .build());
+            contract.buy(None, None, Some(format!("op-{}", i)));
+        }
+
+        // The 2nd operation is the oldest finished one.
+        set_promise_result(&mut context, PromiseResult::Failed);
+        contract.handle_refund(accounts(2), ONE_NEAR.into(), key("op-1"));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_NEAR)
+            .build());
+        contract.buy(None, None, Some("op-last".to_string()));
+
+        assert!(contract
+            .operation(accounts(2), "op-0".to_string())
+            .is_some());
+        assert!(contract
+            .operation(accounts(2), "op-1".to_string())
+            .is_none());
+        assert!(contract
+            .operation(accounts(2), "op-last".to_string())
+            .is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many pending operations")]
+    fn test_too_many_pending_operations() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        for i in 0..=MAX_OPERATIONS_PER_ACCOUNT {
+            testing_env!(context
+                .predecessor_account_id(accounts(2))
+                .attached_deposit(ONE_NEAR)
+                .build());
+            contract.buy(None, None, Some(format!("op-{}", i)));
+        }
+    }
+
+    /// Fills the journal of the account, the 1st operation being finished and pruned.
+    fn prune_first_operation(context: &mut VMContextBuilder, contract: &mut Contract) {
+        for i in 0..=MAX_OPERATIONS_PER_ACCOUNT {
+            testing_env!(context
+                .predecessor_account_id(accounts(2))
+                .attached_deposit(ONE_NEAR)
+                .build());
+            contract.buy(None, None, Some(format!("op-{}", i)));
+
+            if i == 0 {
+                set_promise_result(context, PromiseResult::Failed);
+                contract.handle_refund(accounts(2), ONE_NEAR.into(), key("op-0"));
+            }
+        }
+        assert!(contract
+            .operation(accounts(2), "op-0".to_string())
+            .is_none());
+
+        for i in 1..=MAX_OPERATIONS_PER_ACCOUNT {
+            set_promise_result(context, PromiseResult::Failed);
+            contract.handle_refund(accounts(2), ONE_NEAR.into(), key(&format!("op-{}", i)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Operation 'op-0' already exists")]
+    fn test_pruned_operation_id_within_retry_window() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        prune_first_operation(&mut context, &mut contract);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_NEAR)
+            .block_timestamp(OPERATION_RETRY_WINDOW - 1)
+            .build());
+        contract.buy(None, None, Some("op-0".to_string()));
+    }
+
+    #[test]
+    fn test_pruned_operation_id_after_retry_window() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        prune_first_operation(&mut context, &mut contract);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_NEAR)
+            .block_timestamp(OPERATION_RETRY_WINDOW)
+            .build());
+        contract.buy(None, None, Some("op-0".to_string()));
+        assert!(contract
+            .operation(accounts(2), "op-0".to_string())
+            .is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Operation 'op-1' already exists")]
+    fn test_duplicate_operation() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_NEAR)
+            .build());
+        contract.buy(None, None, Some("op-1".to_string()));
+        contract.buy(None, None, Some("op-1".to_string()));
+    }
+}
@@ -1,9 +1,28 @@
+mod collateral;
 mod event;
+mod flash;
 mod ft;
+mod journal;
+mod lock;
+mod minters;
 mod oracle;
 mod owner;
+mod peg;
 mod pool;
+mod psm;
+mod redemption;
+mod report;
+mod reserves;
+mod savings;
+mod settlement;
+mod spread;
+mod staking;
 mod storage;
+mod supply;
+mod tiers;
+mod treasury;
+mod vaults;
+mod volatility;
 
 use near_contract_standards::fungible_token::core::FungibleTokenCore;
 use near_contract_standards::fungible_token::metadata::{
@@ -11,18 +30,36 @@ use near_contract_standards::fungible_token::metadata::{
 };
 use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LazyOption, LookupMap, UnorderedSet};
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedMap, UnorderedSet};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, env, ext_contract, is_promise_success, near_bindgen, sys, AccountId, Balance,
-    BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseOrValue,
+    BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseOrValue, Timestamp,
 };
 
 use std::fmt::Debug;
 
 use crate::ft::FungibleTokenFreeStorage;
+use collateral::CollateralPolicy;
+use flash::FlashMint;
+use journal::{Operation, OperationKey, OperationKind, OperationStatus};
 use oracle::{ExchangeRate, Oracle, PriceData};
+use peg::PegKeeper;
+use pool::{PoolSettings, StablePool};
+use psm::StableAsset;
+use redemption::RedemptionQueue;
+use report::ReservesSnapshot;
+use reserves::Reserves;
+use savings::Savings;
+use settlement::Settlement;
+use spread::{LegacySpread, PiecewiseSpread, SpreadSide, SpreadTier};
+use staking::Staking;
+use supply::{MintSource, SupplyCaps};
+use tiers::{AccountVolume, FeeTier, VolumeTier};
+use treasury::Treasury;
+use vaults::Vaults;
+use volatility::Volatility;
 
 uint::construct_uint!(
     pub struct U256(4);
@@ -31,13 +68,16 @@ uint::construct_uint!(
 const NO_DEPOSIT: Balance = 0;
 const TOKEN_DECIMAL: u8 = 18;
 const GAS_FOR_REFUND_PROMISE: Gas = Gas(5_000_000_000_000);
-const GAS_FOR_BUY_PROMISE: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_BUY_PROMISE: Gas = Gas(30_000_000_000_000);
 const GAS_FOR_SELL_PROMISE: Gas = Gas(15_000_000_000_000);
 const GAS_FOR_RETURN_VALUE_PROMISE: Gas = Gas(5_000_000_000_000);
 
+const MAX_REDEMPTIONS_PER_BUY: u64 = 2;
+
 const MAX_SPREAD: Balance = 50_000; // 0.05 = 5%
 const SPREAD_DECIMAL: u8 = 6;
-const SPREAD_MAX_SCALER: f64 = 0.4;
+const SPREAD_SCALER_DECIMAL: u8 = 18;
+const SPREAD_MAX_SCALER: u128 = 400_000_000_000_000_000; // 0.4
 
 #[derive(BorshStorageKey, BorshSerialize)]
 enum StorageKey {
@@ -45,6 +85,23 @@ enum StorageKey {
     Token,
     TokenMetadata,
     Blacklist,
+    StableAssets,
+    FailedPayouts,
+    Locked,
+    Operations,
+    Redemptions,
+    FeeTiers,
+    Volumes,
+    SettlementPayouts,
+    Minters,
+    FlashDebts,
+    Vaults,
+    SavingsShares,
+    StablePools,
+    LiquidityFunds,
+    OperationIds,
+    UsedOperationIds,
+    FlashBorrowers,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
@@ -61,6 +118,8 @@ pub enum BlackListStatus {
 pub enum ContractStatus {
     Working,
     Paused,
+    /// Emergency global settlement: USN can only be redeemed for reserves.
+    Settled,
 }
 
 impl std::fmt::Display for ContractStatus {
@@ -68,6 +127,7 @@ impl std::fmt::Display for ContractStatus {
         match self {
             ContractStatus::Working => write!(f, "working"),
             ContractStatus::Paused => write!(f, "paused"),
+            ContractStatus::Settled => write!(f, "settled"),
         }
     }
 }
@@ -80,20 +140,22 @@ pub struct ExpectedRate {
     pub decimals: u8,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+/// Parameters of the exponential spread in fixed-point numbers:
+/// `min` and `max` have `SPREAD_DECIMAL` decimals, `scaler` has `SPREAD_SCALER_DECIMAL` decimals.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct ExponentialSpreadParams {
-    pub min: f64,
-    pub max: f64,
-    pub scaler: f64,
+    pub min: U128,
+    pub max: U128,
+    pub scaler: U128,
 }
 
 impl Default for ExponentialSpreadParams {
     fn default() -> Self {
         Self {
-            min: 0.001,
-            max: 0.005,
-            scaler: 0.0000075,
+            min: U128(1000),                 // 0.001
+            max: U128(5000),                 // 0.005
+            scaler: U128(7_500_000_000_000), // 0.0000075
         }
     }
 }
@@ -102,6 +164,8 @@ impl Default for ExponentialSpreadParams {
 pub enum Spread {
     Fixed(Balance),
     Exponential(ExponentialSpreadParams),
+    /// Separate schedules for buys and sells.
+    Piecewise(PiecewiseSpread),
 }
 
 #[near_bindgen]
@@ -115,6 +179,33 @@ pub struct Contract {
     status: ContractStatus,
     oracle: Oracle,
     spread: Spread,
+    psm: UnorderedMap<AccountId, StableAsset>,
+    failed_payouts: LookupMap<AccountId, Balance>,
+    locked: LookupMap<AccountId, Balance>,
+    total_locked: Balance,
+    operations: LookupMap<OperationKey, Operation>,
+    redemptions: RedemptionQueue,
+    volatility: Volatility,
+    fee_tiers: LookupMap<AccountId, FeeTier>,
+    volumes: LookupMap<AccountId, AccountVolume>,
+    volume_tiers: Vec<VolumeTier>,
+    treasury: Treasury,
+    collateral_policy: CollateralPolicy,
+    staking: Staking,
+    reserves: Reserves,
+    reserves_snapshot: Option<ReservesSnapshot>,
+    settlement: Settlement,
+    supply: SupplyCaps,
+    minters: UnorderedMap<AccountId, Balance>,
+    flash: FlashMint,
+    vaults: Vaults,
+    savings: Savings,
+    peg: PegKeeper,
+    pool_settings: PoolSettings,
+    stable_pools: UnorderedMap<u64, StablePool>,
+    liquidity_funds: LookupMap<AccountId, Balance>,
+    operation_ids: LookupMap<AccountId, Vec<String>>,
+    used_operation_ids: LookupMap<AccountId, Vec<(u64, Timestamp)>>,
 }
 
 const DATA_IMAGE_SVG_NEAR_ICON: &str =
@@ -125,9 +216,11 @@ trait ContractCallback {
     #[private]
     fn buy_with_price_callback(
         &mut self,
+        payer: AccountId,
         account: AccountId,
         near: U128,
         expected: Option<ExpectedRate>,
+        operation: Option<OperationKey>,
         #[callback] price: PriceData,
     ) -> U128;
 
@@ -137,14 +230,33 @@ trait ContractCallback {
         account: AccountId,
         tokens: U128,
         expected: Option<ExpectedRate>,
+        operation: Option<OperationKey>,
         #[callback] price: PriceData,
-    ) -> Promise;
+    ) -> PromiseOrValue<U128>;
 
     #[private]
-    fn handle_refund(&mut self, account: AccountId, attached_deposit: U128);
+    fn handle_refund(
+        &mut self,
+        account: AccountId,
+        attached_deposit: U128,
+        operation: Option<OperationKey>,
+    );
 
     #[private]
-    fn return_value(&mut self, value: U128) -> U128;
+    fn handle_sell_payout(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        deposit: U128,
+        fee: U128,
+        operation: Option<OperationKey>,
+    ) -> U128;
+
+    #[private]
+    fn handle_operation_result(&mut self, operation: OperationKey);
+
+    #[private]
+    fn return_value(&mut self, account: AccountId, value: U128) -> U128;
 
     #[private]
     fn handle_unregister(&mut self, account: AccountId);
@@ -153,9 +265,11 @@ trait ContractCallback {
 trait ContractCallback {
     fn buy_with_price_callback(
         &mut self,
+        payer: AccountId,
         account: AccountId,
         near: U128,
         expected: Option<ExpectedRate>,
+        operation: Option<OperationKey>,
         price: PriceData,
     ) -> U128;
 
@@ -164,12 +278,29 @@ trait ContractCallback {
         account: AccountId,
         tokens: U128,
         expected: Option<ExpectedRate>,
+        operation: Option<OperationKey>,
         price: PriceData,
-    ) -> Promise;
+    ) -> PromiseOrValue<U128>;
 
-    fn handle_refund(&mut self, account: AccountId, attached_deposit: U128);
+    fn handle_refund(
+        &mut self,
+        account: AccountId,
+        attached_deposit: U128,
+        operation: Option<OperationKey>,
+    );
 
-    fn return_value(&mut self, value: U128) -> U128;
+    fn handle_sell_payout(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        deposit: U128,
+        fee: U128,
+        operation: Option<OperationKey>,
+    ) -> U128;
+
+    fn handle_operation_result(&mut self, operation: OperationKey);
+
+    fn return_value(&mut self, account: AccountId, value: U128) -> U128;
 }
 
 #[near_bindgen]
@@ -177,14 +308,29 @@ impl ContractCallback for Contract {
     #[private]
     fn buy_with_price_callback(
         &mut self,
+        payer: AccountId,
         account: AccountId,
         near: U128,
         expected: Option<ExpectedRate>,
+        operation: Option<OperationKey>,
         #[callback] price: PriceData,
     ) -> U128 {
         let rate: ExchangeRate = price.into();
 
-        self.finish_buy(account, near.0, expected, rate).into()
+        let amount = self.finish_buy(payer, account, near.0, expected, rate.clone());
+
+        self.journal_record(
+            &operation,
+            OperationStatus::Completed,
+            near.0,
+            amount,
+            &rate,
+        );
+
+        // The bought NEAR is available now to pay out queued redemptions.
+        self.internal_fill_redemptions(MAX_REDEMPTIONS_PER_BUY);
+
+        amount.into()
     }
 
     #[private]
@@ -193,36 +339,107 @@ impl ContractCallback for Contract {
         account: AccountId,
         tokens: U128,
         expected: Option<ExpectedRate>,
+        operation: Option<OperationKey>,
         #[callback] price: PriceData,
-    ) -> Promise {
+    ) -> PromiseOrValue<U128> {
         let rate: ExchangeRate = price.into();
 
-        let deposit = self.finish_sell(account.clone(), tokens.0, expected, rate);
+        let (deposit, fee) = self.finish_sell(account.clone(), tokens.0, expected, rate.clone());
+
+        self.journal_record(
+            &operation,
+            OperationStatus::Pending,
+            deposit,
+            tokens.0,
+            &rate,
+        );
+
+        // Not enough NEAR: USN stays locked until the redemption is filled.
+        if self.should_queue_redemption(deposit) {
+            self.internal_queue_redemption(account, tokens.0, deposit, fee, operation);
+            return PromiseOrValue::Value(0.into());
+        }
 
-        Promise::new(account)
+        Promise::new(account.clone())
             .transfer(deposit)
-            .then(ext_self::return_value(
+            .then(ext_self::handle_sell_payout(
+                account,
+                tokens,
                 deposit.into(),
+                fee.into(),
+                operation,
                 env::current_account_id(),
                 0,
                 GAS_FOR_RETURN_VALUE_PROMISE,
             ))
+            .into()
     }
 
     #[private]
-    fn handle_refund(&mut self, account: AccountId, attached_deposit: U128) {
+    fn handle_refund(
+        &mut self,
+        account: AccountId,
+        attached_deposit: U128,
+        operation: Option<OperationKey>,
+    ) {
         if !is_promise_success() {
+            self.journal_finish(&operation, OperationStatus::Failed);
+
             Promise::new(account)
                 .transfer(attached_deposit.0)
                 .as_return();
         }
     }
 
+    /// Burns locked USN if the NEAR has been transferred, otherwise returns USN to the seller.
+    /// `fee` is the USN spread of the sell. Returns the transferred NEAR amount.
     #[private]
-    fn return_value(&mut self, value: U128) -> U128 {
-        assert!(is_promise_success(), "Transfer has failed");
-        // TODO: Remember lost value? Unlikely to happen, and only by user error.
-        value
+    fn handle_sell_payout(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        deposit: U128,
+        fee: U128,
+        operation: Option<OperationKey>,
+    ) -> U128 {
+        if is_promise_success() {
+            self.internal_burn_locked(&account, amount.0);
+            self.record_burn(MintSource::Buy, amount.0);
+            let fee_near = self.treasury.record_sell(amount.0, deposit.0, fee.0);
+            self.internal_share_revenue(fee.0, fee_near);
+            self.record_volume(&account, amount.0);
+            self.journal_finish(&operation, OperationStatus::Completed);
+            deposit
+        } else {
+            self.internal_unlock(&account, amount.0);
+            self.journal_finish(&operation, OperationStatus::Failed);
+            0.into()
+        }
+    }
+
+    /// Marks the operation as failed if it has been aborted before exchanging anything,
+    /// e.g. because of a slippage error.
+    #[private]
+    fn handle_operation_result(&mut self, operation: OperationKey) {
+        if !is_promise_success() {
+            self.journal_finish(&Some(operation), OperationStatus::Failed);
+        }
+    }
+
+    /// Returns the transferred NEAR amount, or remembers the failed payout
+    /// making it claimable with `claim_failed_payout` later.
+    #[private]
+    fn return_value(&mut self, account: AccountId, value: U128) -> U128 {
+        if is_promise_success() {
+            return value;
+        }
+
+        let payout = self.failed_payouts.get(&account).unwrap_or(0) + value.0;
+        self.failed_payouts.insert(&account, &payout);
+
+        event::emit::payout_failed(&account, value.0);
+
+        0.into()
     }
 }
 
@@ -250,9 +467,40 @@ impl Contract {
             status: ContractStatus::Working,
             oracle: Oracle::default(),
             spread: Spread::Exponential(ExponentialSpreadParams::default()),
+            psm: UnorderedMap::new(StorageKey::StableAssets),
+            failed_payouts: LookupMap::new(StorageKey::FailedPayouts),
+            locked: LookupMap::new(StorageKey::Locked),
+            total_locked: 0,
+            operations: LookupMap::new(StorageKey::Operations),
+            redemptions: RedemptionQueue::new(StorageKey::Redemptions),
+            volatility: Volatility::default(),
+            fee_tiers: LookupMap::new(StorageKey::FeeTiers),
+            volumes: LookupMap::new(StorageKey::Volumes),
+            volume_tiers: Vec::new(),
+            treasury: Treasury::new(0),
+            collateral_policy: CollateralPolicy::default(),
+            staking: Staking::default(),
+            reserves: Reserves::default(),
+            reserves_snapshot: None,
+            settlement: Settlement::new(StorageKey::SettlementPayouts),
+            supply: SupplyCaps::default(),
+            minters: UnorderedMap::new(StorageKey::Minters),
+            flash: FlashMint::new(StorageKey::FlashDebts, StorageKey::FlashBorrowers),
+            vaults: Vaults::new(StorageKey::Vaults),
+            savings: Savings::new(StorageKey::SavingsShares),
+            peg: PegKeeper::default(),
+            pool_settings: PoolSettings::default(),
+            stable_pools: UnorderedMap::new(StorageKey::StablePools),
+            liquidity_funds: LookupMap::new(StorageKey::LiquidityFunds),
+            operation_ids: LookupMap::new(StorageKey::OperationIds),
+            used_operation_ids: LookupMap::new(StorageKey::UsedOperationIds),
         };
 
         this.token.internal_deposit(&owner_id, NO_DEPOSIT);
+        this.stable_pools.insert(
+            &this.pool_settings.stable_pool_id,
+            &StablePool::usdt(this.pool_settings.usdt_address.clone()),
+        );
         this
     }
 
@@ -314,36 +562,57 @@ impl Contract {
         assert_one_yocto();
         // TODO: Should guardians be able to pause?
         self.assert_owner_or_guardian();
+        self.abort_if_settled();
         self.status = ContractStatus::Paused;
     }
 
     /// Resumes the contract. Only can be called by owner.
     pub fn resume(&mut self) {
         self.assert_owner();
+        self.abort_if_settled();
         self.status = ContractStatus::Working;
     }
 
     /// Buys USN tokens for NEAR tokens.
     /// Can make cross-contract call to an oracle.
     /// Returns amount of purchased USN tokens.
+    /// The optional `operation_id` makes the operation trackable with the `operation` view,
+    /// and rejects repeated calls with the same id.
     /// NOTE: The method returns a promise, but SDK doesn't support clone on promise and we
     ///     want to return a promise in the middle.
     #[payable]
-    pub fn buy(&mut self, expected: Option<ExpectedRate>, to: Option<AccountId>) {
+    pub fn buy(
+        &mut self,
+        expected: Option<ExpectedRate>,
+        to: Option<AccountId>,
+        operation_id: Option<String>,
+    ) {
         self.abort_if_pause();
         self.abort_if_blacklisted();
+        self.assert_buy_allowed();
 
         let near = env::attached_deposit();
 
         // Select target account.
         let account = to.unwrap_or_else(env::predecessor_account_id);
 
+        let operation = operation_id.map(|operation_id| OperationKey {
+            account_id: env::predecessor_account_id(),
+            operation_id,
+        });
+
+        if let Some(operation) = &operation {
+            self.journal_start(operation, OperationKind::Buy, near, 0);
+        }
+
         self.oracle
             .get_exchange_rate_promise()
             .then(ext_self::buy_with_price_callback(
+                env::predecessor_account_id(),
                 account.clone(),
                 near.into(),
                 expected,
+                operation.clone(),
                 env::current_account_id(),
                 NO_DEPOSIT,
                 GAS_FOR_BUY_PROMISE,
@@ -354,6 +623,7 @@ impl Contract {
             .then(ext_self::handle_refund(
                 account,
                 near.into(),
+                operation,
                 env::current_account_id(),
                 NO_DEPOSIT,
                 GAS_FOR_REFUND_PROMISE,
@@ -363,17 +633,24 @@ impl Contract {
     /// Completes the purchase (NEAR -> USN). It is called in 2 cases:
     /// 1. Direct call from the `buy` method if the exchange rate cache is valid.
     /// 2. Indirect callback from the cross-contract call after getting a fresh exchange rate.
+    /// The spread and the volume are of the `payer`, USN is minted to the `account`.
     fn finish_buy(
         &mut self,
+        payer: AccountId,
         account: AccountId,
         near: Balance,
         expected: Option<ExpectedRate>,
         rate: ExchangeRate,
     ) -> Balance {
+        self.abort_if_settled();
+
         if let Some(expected) = expected {
             Self::assert_exchange_rate(&rate, &expected);
         }
 
+        self.volatility.record(&rate);
+        self.oracle.last_report = Some(rate.clone());
+
         let near = U256::from(near);
         let multiplier = U256::from(rate.multiplier());
 
@@ -386,7 +663,9 @@ impl Contract {
 
         // Commission.
         let spread_denominator = 10u128.pow(SPREAD_DECIMAL as u32);
-        let spread_multiplier = spread_denominator - self.spread_u128(amount); // 1 - 0.005
+        let spread_multiplier =
+            spread_denominator - self.account_spread(&payer, amount, SpreadSide::Buy); // 1 - 0.005
+        let gross = amount;
         let amount = U256::from(amount) * U256::from(spread_multiplier) / spread_denominator; // amount * 0.995
 
         // The final amount is going to be less than u128 after removing commission.
@@ -396,17 +675,30 @@ impl Contract {
             env::panic_str("Not enough NEAR: attached deposit exchanges to 0 tokens");
         }
 
-        self.token.internal_deposit(&account, amount);
+        let fee_near = self
+            .treasury
+            .record_buy(near.as_u128(), gross, gross - amount);
 
-        event::emit::ft_mint(&account, amount, None);
+        self.internal_mint(&account, amount, MintSource::Buy, None);
+        self.internal_share_revenue(gross - amount, fee_near);
+        self.record_volume(&payer, gross);
 
         amount
     }
 
     /// Sells USN tokens getting NEAR tokens.
     /// Return amount of purchased NEAR tokens.
+    /// The optional `operation_id` makes the operation trackable with the `operation` view,
+    /// and rejects repeated calls with the same id.
+    /// NOTE: The method returns a promise, but SDK doesn't support clone on promise and we
+    ///     want to return a promise in the middle.
     #[payable]
-    pub fn sell(&mut self, amount: U128, expected: Option<ExpectedRate>) -> Promise {
+    pub fn sell(
+        &mut self,
+        amount: U128,
+        expected: Option<ExpectedRate>,
+        operation_id: Option<String>,
+    ) {
         assert_one_yocto();
         self.abort_if_pause();
         self.abort_if_blacklisted();
@@ -419,35 +711,64 @@ impl Contract {
 
         let account = env::predecessor_account_id();
 
-        self.oracle
+        let operation = operation_id.map(|operation_id| OperationKey {
+            account_id: account.clone(),
+            operation_id,
+        });
+
+        if let Some(operation) = &operation {
+            self.journal_start(operation, OperationKind::Sell, 0, amount);
+        }
+
+        let promise = self
+            .oracle
             .get_exchange_rate_promise()
             .then(ext_self::sell_with_price_callback(
                 account,
                 amount.into(),
                 expected,
+                operation.clone(),
                 env::current_account_id(),
                 NO_DEPOSIT,
                 GAS_FOR_SELL_PROMISE,
             ))
+            // Returning callback promise, so the transaction will return the value or a failure.
+            .as_return();
+
+        if let Some(operation) = operation {
+            promise.then(ext_self::handle_operation_result(
+                operation,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RETURN_VALUE_PROMISE,
+            ));
+        }
     }
 
     /// Finishes the sell (USN -> NEAR). It is called in 2 cases:
     /// 1. Direct call from the `sell` method if the exchange rate cache is valid.
     /// 2. Indirect callback from the cross-contract call after getting a fresh exchange rate.
+    /// Returns the NEAR to pay out and the USN spread.
     fn finish_sell(
         &mut self,
         account: AccountId,
         amount: Balance,
         expected: Option<ExpectedRate>,
         rate: ExchangeRate,
-    ) -> Balance {
+    ) -> (Balance, Balance) {
+        self.abort_if_settled();
+
         if let Some(expected) = expected {
             Self::assert_exchange_rate(&rate, &expected);
         }
 
+        self.volatility.record(&rate);
+        self.oracle.last_report = Some(rate.clone());
+
         // Commission.
         let spread_denominator = 10u128.pow(SPREAD_DECIMAL as u32);
-        let spread_multiplier = spread_denominator - self.spread_u128(amount);
+        let spread_multiplier =
+            spread_denominator - self.account_spread(&account, amount, SpreadSide::Sell);
         let sell = U256::from(amount) * U256::from(spread_multiplier) / spread_denominator;
 
         // Make exchange: USN -> NEAR.
@@ -457,11 +778,42 @@ impl Contract {
         // Here we don't expect too big deposit. Otherwise, panic.
         let deposit = deposit.as_u128();
 
-        self.token.internal_withdraw(&account, amount);
+        // USN is burned after the NEAR transfer succeeds.
+        self.internal_lock(&account, amount);
 
-        event::emit::ft_burn(&account, amount, None);
+        (deposit, amount - sell.as_u128())
+    }
 
-        deposit
+    /// Returns NEAR amount of payouts (e.g. vault withdrawals) which have failed to be transferred to the account.
+    pub fn failed_payout(&self, account_id: AccountId) -> U128 {
+        self.failed_payouts.get(&account_id).unwrap_or(0).into()
+    }
+
+    /// Transfers NEAR of failed payouts to the given account.
+    /// If the transfer fails again, the payout stays claimable.
+    #[payable]
+    pub fn claim_failed_payout(&mut self, to: AccountId) -> Promise {
+        assert_one_yocto();
+        self.abort_if_maintenance();
+        self.abort_if_blacklisted();
+
+        let account = env::predecessor_account_id();
+        let payout = self
+            .failed_payouts
+            .remove(&account)
+            .unwrap_or_else(|| env::panic_str("No failed payouts to claim"));
+
+        event::emit::payout_claimed(&account, &to, payout);
+
+        Promise::new(to)
+            .transfer(payout)
+            .then(ext_self::return_value(
+                account,
+                payout.into(),
+                env::current_account_id(),
+                0,
+                GAS_FOR_RETURN_VALUE_PROMISE,
+            ))
     }
 
     fn assert_exchange_rate(actual: &ExchangeRate, expected: &ExpectedRate) {
@@ -507,15 +859,18 @@ impl Contract {
         metadata.expect("Unable to get decimals").decimals
     }
 
-    /// Returns either a fixed spread, or a adaptive spread
-    pub fn spread(&self, amount: Option<U128>) -> U128 {
+    /// Returns either a fixed spread, or a adaptive spread, or a spread of the buy/sell schedule.
+    /// The side is `Buy` by default.
+    pub fn spread(&self, amount: Option<U128>, side: Option<SpreadSide>) -> U128 {
         let amount = amount.unwrap_or(U128::from(0));
-        self.spread_u128(u128::from(amount)).into()
+        let side = side.unwrap_or(SpreadSide::Buy);
+        self.spread_u128(u128::from(amount), side).into()
     }
 
-    fn spread_u128(&self, amount: u128) -> u128 {
-        match &self.spread {
+    fn spread_u128(&self, amount: u128, side: SpreadSide) -> u128 {
+        let spread = match &self.spread {
             Spread::Fixed(spread) => *spread,
+            Spread::Piecewise(schedule) => schedule.spread(amount, side),
             Spread::Exponential(params) => {
                 // C1(v) = CHV + (CLV - CHV) * e ^ {-s1 * amount}
                 //     CHV = 0.1%
@@ -527,11 +882,12 @@ impl Contract {
                 let decimals = 10u128.pow(self.decimals() as u32);
                 let n = amount / decimals; // [0, ...], dropping decimals
                 let amount: u128 = if n > 10_000_000 { 10_000_000 } else { n }; // [0, 10_000_000]
-                let exp = (-params.scaler * amount as f64).exp();
-                let spread = params.min + (params.max - params.min) * exp;
-                (spread * (10u32.pow(SPREAD_DECIMAL as u32) as f64)).round() as u128
+                spread::exponential_spread(params, amount)
             }
-        }
+        };
+
+        // Widen the spread during turbulent markets.
+        std::cmp::min(spread + self.volatility.spread(), MAX_SPREAD)
     }
 
     pub fn set_fixed_spread(&mut self, spread: U128) {
@@ -552,49 +908,115 @@ impl Contract {
         self.spread = match params {
             None => Spread::Exponential(ExponentialSpreadParams::default()),
             Some(params) => {
-                let max_spread = MAX_SPREAD as f64 / 10u64.pow(SPREAD_DECIMAL as u32) as f64;
-                if params.max < params.min {
+                if params.max.0 < params.min.0 {
                     env::panic_str("params.min cannot be greater than params.max");
                 }
-                if params.min > max_spread {
-                    env::panic_str(&format!("params.min is greater than {}", max_spread));
+                if params.min.0 > MAX_SPREAD {
+                    env::panic_str(&format!("params.min is greater than {}", MAX_SPREAD));
                 }
-                if params.max > max_spread {
-                    env::panic_str(&format!("params.max is greater than {}", max_spread));
+                if params.max.0 > MAX_SPREAD {
+                    env::panic_str(&format!("params.max is greater than {}", MAX_SPREAD));
                 }
-                if params.scaler > SPREAD_MAX_SCALER {
+                if params.scaler.0 > SPREAD_MAX_SCALER {
                     #[rustfmt::skip]
                     env::panic_str(&format!("params.scaler is greater than {}", SPREAD_MAX_SCALER));
                 }
-                if params.min.is_sign_negative()
-                    || params.max.is_sign_negative()
-                    || params.scaler.is_sign_negative()
-                {
-                    env::panic_str("params.min, params.max, params.scaler cannot be negative");
-                }
                 Spread::Exponential(params)
             }
         }
     }
 
+    /// Sets separate buy and sell schedules: USN amount thresholds with a spread per tier.
+    pub fn set_piecewise_spread(&mut self, buy: Vec<SpreadTier>, sell: Vec<SpreadTier>) {
+        self.assert_owner();
+        self.spread = Spread::Piecewise(PiecewiseSpread::new(buy, sell));
+    }
+
     pub fn version(&self) -> String {
         format!("{}:{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
     }
 
-    /// This is NOOP implementation. KEEP IT if you haven't changed contract state.
+    /// Migrates the contract state of the previous version (0.1.7).
     /// Should only be called by this contract on migration.
     /// This method is called from `upgrade()` method.
     /// For next version upgrades, change this function.
     #[init(ignore_state)]
     #[private]
     pub fn migrate() -> Self {
-        let contract: Contract = env::state_read().expect("Contract is not initialized");
-        contract
+        #[derive(BorshDeserialize)]
+        struct PrevContract {
+            owner_id: AccountId,
+            guardians: UnorderedSet<AccountId>,
+            token: FungibleTokenFreeStorage,
+            metadata: LazyOption<FungibleTokenMetadata>,
+            black_list: LookupMap<AccountId, BlackListStatus>,
+            status: ContractStatus,
+            oracle: Oracle,
+            spread: LegacySpread,
+        }
+
+        let contract: PrevContract = env::state_read().expect("Contract is not initialized");
+
+        let mut migrated = Self {
+            owner_id: contract.owner_id,
+            guardians: contract.guardians,
+            token: contract.token,
+            metadata: contract.metadata,
+            black_list: contract.black_list,
+            status: contract.status,
+            oracle: contract.oracle,
+            spread: contract.spread.into(),
+            psm: UnorderedMap::new(StorageKey::StableAssets),
+            failed_payouts: LookupMap::new(StorageKey::FailedPayouts),
+            locked: LookupMap::new(StorageKey::Locked),
+            total_locked: 0,
+            operations: LookupMap::new(StorageKey::Operations),
+            redemptions: RedemptionQueue::new(StorageKey::Redemptions),
+            volatility: Volatility::default(),
+            fee_tiers: LookupMap::new(StorageKey::FeeTiers),
+            volumes: LookupMap::new(StorageKey::Volumes),
+            volume_tiers: Vec::new(),
+            treasury: Treasury::new(0),
+            collateral_policy: CollateralPolicy::default(),
+            staking: Staking::default(),
+            reserves: Reserves::default(),
+            reserves_snapshot: None,
+            settlement: Settlement::new(StorageKey::SettlementPayouts),
+            supply: SupplyCaps::default(),
+            minters: UnorderedMap::new(StorageKey::Minters),
+            flash: FlashMint::new(StorageKey::FlashDebts, StorageKey::FlashBorrowers),
+            vaults: Vaults::new(StorageKey::Vaults),
+            savings: Savings::new(StorageKey::SavingsShares),
+            peg: PegKeeper::default(),
+            pool_settings: PoolSettings::default(),
+            stable_pools: UnorderedMap::new(StorageKey::StablePools),
+            liquidity_funds: LookupMap::new(StorageKey::LiquidityFunds),
+            operation_ids: LookupMap::new(StorageKey::OperationIds),
+            used_operation_ids: LookupMap::new(StorageKey::UsedOperationIds),
+        };
+
+        // All the NEAR collected before the treasury accounting backs USN.
+        migrated.treasury = Treasury::new(migrated.internal_available_near());
+        migrated.stable_pools.insert(
+            &migrated.pool_settings.stable_pool_id,
+            &StablePool::usdt(migrated.pool_settings.usdt_address.clone()),
+        );
+        migrated.internal_seed_liquidity_funds();
+        migrated
     }
 
     fn abort_if_pause(&self) {
+        match self.status {
+            ContractStatus::Working => {}
+            ContractStatus::Paused => env::panic_str("The contract is under maintenance"),
+            ContractStatus::Settled => env::panic_str("The contract is settled"),
+        }
+    }
+
+    /// Unlike `abort_if_pause`, lets the call through after the settlement.
+    fn abort_if_maintenance(&self) {
         if self.status == ContractStatus::Paused {
-            env::panic_str("The contract is under maintenance")
+            env::panic_str("The contract is under maintenance");
         }
     }
 
@@ -710,7 +1132,10 @@ impl FungibleTokenMetadataProvider for Contract {
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
     use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, Balance, ONE_NEAR, ONE_YOCTO};
+    use near_sdk::{
+        testing_env, Balance, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR, ONE_YOCTO,
+    };
+    use std::collections::HashMap;
 
     use super::*;
 
@@ -916,19 +1341,19 @@ mod tests {
 
         let old_rate = ExchangeRate::test_old_rate();
 
-        contract.buy(None, None);
+        contract.buy(None, None, None);
 
         testing_env!(context.attached_deposit(ONE_YOCTO).build());
 
-        contract.sell(U128::from(11032461000000000000), None);
-        contract.buy(Some(old_rate.clone().into()), None);
+        contract.sell(U128::from(11032461000000000000), None, None);
+        contract.buy(Some(old_rate.clone().into()), None, None);
 
         testing_env!(context.attached_deposit(ONE_YOCTO).build());
 
         let mut expected_rate: ExpectedRate = old_rate.clone().into();
         expected_rate.multiplier = (old_rate.multiplier() * 96 / 100).into();
 
-        contract.sell(U128::from(9900000000000000000), Some(expected_rate));
+        contract.sell(U128::from(9900000000000000000), Some(expected_rate), None);
     }
 
     #[test]
@@ -940,11 +1365,11 @@ mod tests {
 
         testing_env!(context.predecessor_account_id(accounts(2)).build());
 
-        contract.buy(None, None);
+        contract.buy(None, None, None);
 
         testing_env!(context.attached_deposit(ONE_YOCTO).build());
 
-        contract.sell(U128::from(11032461000000000000), None);
+        contract.sell(U128::from(11032461000000000000), None, None);
     }
 
     #[test]
@@ -963,7 +1388,7 @@ mod tests {
             .predecessor_account_id(accounts(2))
             .attached_deposit(ONE_NEAR)
             .build());
-        contract.buy(None, None);
+        contract.buy(None, None, None);
     }
 
     #[test]
@@ -982,7 +1407,11 @@ mod tests {
             .predecessor_account_id(accounts(2))
             .attached_deposit(ONE_YOCTO)
             .build());
-        contract.sell(U128::from(1), Some(ExchangeRate::test_old_rate().into()));
+        contract.sell(
+            U128::from(1),
+            Some(ExchangeRate::test_old_rate().into()),
+            None,
+        );
     }
 
     #[test]
@@ -992,7 +1421,7 @@ mod tests {
         let mut contract = Contract::new(accounts(1));
 
         contract.set_fixed_spread(MAX_SPREAD.into());
-        assert_eq!(contract.spread(None).0, MAX_SPREAD);
+        assert_eq!(contract.spread(None, None).0, MAX_SPREAD);
         let res =
             std::panic::catch_unwind(move || contract.set_fixed_spread((MAX_SPREAD + 1).into()));
         assert!(res.is_err());
@@ -1009,18 +1438,71 @@ mod tests {
         let ten_mln = one_token * 10_000_000;
 
         contract.set_adaptive_spread(None);
-        assert_eq!(contract.spread(Some(one_token.into())).0, 5000); // $1: 0.0050000 = 0.5%
-        assert_eq!(contract.spread(Some(hundred_thousands.into())).0, 2889); // $1000: 0.002889 = 0.289%
-        assert_eq!(contract.spread(Some(ten_mln.into())).0, 1000); // $10mln: 0.001000 = 0.1%
+        assert_eq!(contract.spread(Some(one_token.into()), None).0, 5000); // $1: 0.0050000 = 0.5%
+        assert_eq!(
+            contract.spread(Some(hundred_thousands.into()), None).0,
+            2889
+        ); // $1000: 0.002889 = 0.289%
+        assert_eq!(contract.spread(Some(ten_mln.into()), None).0, 1000); // $10mln: 0.001000 = 0.1%
 
         contract.set_adaptive_spread(Some(ExponentialSpreadParams {
-            min: 0.002,
-            max: 0.006,
-            scaler: 0.00001,
+            min: 2000.into(),
+            max: 6000.into(),
+            scaler: 10_000_000_000_000.into(),
         }));
-        assert_eq!(contract.spread(Some(one_token.into())).0, 6000); // $1: 0.0060000 = 0.6%
-        assert_eq!(contract.spread(Some(hundred_thousands.into())).0, 3472); // $1000: 0.003472 = 0.347%
-        assert_eq!(contract.spread(Some(ten_mln.into())).0, 2000); // $10mln: 0.002000 = 021%
+        assert_eq!(contract.spread(Some(one_token.into()), None).0, 6000); // $1: 0.0060000 = 0.6%
+        assert_eq!(
+            contract.spread(Some(hundred_thousands.into()), None).0,
+            3472
+        ); // $1000: 0.003472 = 0.347%
+        assert_eq!(contract.spread(Some(ten_mln.into()), None).0, 2000); // $10mln: 0.002000 = 021%
+    }
+
+    #[test]
+    fn test_piecewise_spread() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        let one_token = 10u128.pow(contract.decimals() as u32);
+        let tier = |amount: u128, spread: u128| SpreadTier {
+            amount: (amount * one_token).into(),
+            spread: spread.into(),
+        };
+
+        contract.set_piecewise_spread(
+            vec![tier(0, 10000)],
+            vec![tier(1000, 20000), tier(3000, 10000)],
+        );
+
+        assert_eq!(contract.spread(None, None).0, 10000);
+        assert_eq!(contract.spread(None, Some(SpreadSide::Sell)).0, 20000);
+        assert_eq!(
+            contract
+                .spread(Some((2000 * one_token).into()), Some(SpreadSide::Sell))
+                .0,
+            15000
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let fresh_rate = ExchangeRate::test_fresh_rate();
+
+        // 1 NEAR = $11.1439: minus 1% of the buy spread.
+        assert_eq!(
+            contract.finish_buy(accounts(2), accounts(2), ONE_NEAR, None, fresh_rate.clone()),
+            11032461000000000000
+        );
+
+        // $11.1439 minus 2% of the sell spread.
+        contract
+            .token
+            .internal_deposit(&accounts(2), 11143900000000000000);
+        assert_eq!(
+            contract
+                .finish_sell(accounts(2), 11143900000000000000, None, fresh_rate)
+                .0,
+            980000000000000000000000
+        );
     }
 
     #[test]
@@ -1031,9 +1513,9 @@ mod tests {
         let mut contract = Contract::new(accounts(1));
 
         contract.set_adaptive_spread(Some(ExponentialSpreadParams {
-            min: 0.06,
-            max: 0.001,
-            scaler: 0.00001,
+            min: 60000.into(),
+            max: 1000.into(),
+            scaler: 10_000_000_000_000.into(),
         }));
     }
 
@@ -1045,9 +1527,9 @@ mod tests {
         let mut contract = Contract::new(accounts(1));
 
         contract.set_adaptive_spread(Some(ExponentialSpreadParams {
-            min: 0.001,
-            max: 0.06,
-            scaler: 0.00001,
+            min: 1000.into(),
+            max: 60000.into(),
+            scaler: 10_000_000_000_000.into(),
         }));
     }
 
@@ -1059,9 +1541,9 @@ mod tests {
         let mut contract = Contract::new(accounts(1));
 
         contract.set_adaptive_spread(Some(ExponentialSpreadParams {
-            min: 0.002,
-            max: 0.001,
-            scaler: 0.00001,
+            min: 2000.into(),
+            max: 1000.into(),
+            scaler: 10_000_000_000_000.into(),
         }));
     }
 
@@ -1073,24 +1555,18 @@ mod tests {
         let mut contract = Contract::new(accounts(1));
 
         contract.set_adaptive_spread(Some(ExponentialSpreadParams {
-            min: 0.001,
-            max: 0.002,
-            scaler: 0.5,
+            min: 1000.into(),
+            max: 2000.into(),
+            scaler: 500_000_000_000_000_000.into(),
         }));
     }
 
     #[test]
-    #[should_panic]
     fn test_adaptive_spread_negative_param() {
-        let context = get_context(accounts(1));
-        testing_env!(context.build());
-        let mut contract = Contract::new(accounts(1));
-
-        contract.set_adaptive_spread(Some(ExponentialSpreadParams {
-            min: -0.001,
-            max: -0.002,
-            scaler: 1.0,
-        }));
+        let params = near_sdk::serde_json::from_str::<ExponentialSpreadParams>(
+            r#"{ "min": "-1000", "max": "-2000", "scaler": "1000000000000000000" }"#,
+        );
+        assert!(params.is_err());
     }
 
     #[test]
@@ -1107,6 +1583,7 @@ mod tests {
 
         assert_eq!(
             contract.finish_buy(
+                accounts(2),
                 accounts(2),
                 1_000_000_000_000 * ONE_NEAR,
                 Some(expected_rate.clone()),
@@ -1126,7 +1603,10 @@ mod tests {
                 Some(expected_rate),
                 fresh_rate,
             ),
-            994_005_000000000000000000000000000000
+            (
+                994_005_000000000000000000000000000000,
+                11088180500_000000000000000000
+            )
         );
     }
 
@@ -1150,4 +1630,120 @@ mod tests {
             fresh_rate,
         );
     }
+
+    #[test]
+    fn test_failed_payout() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed],
+        );
+
+        assert_eq!(contract.return_value(accounts(2), ONE_NEAR.into()).0, 0);
+        assert_eq!(contract.return_value(accounts(2), ONE_NEAR.into()).0, 0);
+        assert_eq!(contract.failed_payout(accounts(2)).0, 2 * ONE_NEAR);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+
+        contract.claim_failed_payout(accounts(3));
+        assert_eq!(contract.failed_payout(accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_successful_payout() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+
+        assert_eq!(
+            contract.return_value(accounts(2), ONE_NEAR.into()).0,
+            ONE_NEAR
+        );
+        assert_eq!(contract.failed_payout(accounts(2)).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "No failed payouts to claim")]
+    fn test_cannot_claim_without_failed_payout() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+
+        contract.claim_failed_payout(accounts(2));
+    }
+
+    #[test]
+    fn test_sell_locks_tokens() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.token.internal_deposit(&accounts(2), 3 * ONE_NEAR);
+
+        let fresh_rate = ExchangeRate::test_fresh_rate();
+
+        contract.finish_sell(accounts(2), ONE_NEAR, None, fresh_rate.clone());
+        contract.finish_sell(accounts(2), ONE_NEAR, None, fresh_rate);
+
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, ONE_NEAR);
+        assert_eq!(contract.locked_balance(accounts(2)).0, 2 * ONE_NEAR);
+        assert_eq!(contract.total_locked().0, 2 * ONE_NEAR);
+        assert_eq!(contract.ft_total_supply().0, 3 * ONE_NEAR);
+
+        // The 1st NEAR transfer succeeds: burn locked USN.
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert_eq!(
+            contract
+                .handle_sell_payout(accounts(2), ONE_NEAR.into(), 1000.into(), 0.into(), None)
+                .0,
+            1000
+        );
+        assert_eq!(contract.ft_total_supply().0, 2 * ONE_NEAR);
+
+        // The 2nd NEAR transfer fails: return USN to the seller.
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert_eq!(
+            contract
+                .handle_sell_payout(accounts(2), ONE_NEAR.into(), 1000.into(), 0.into(), None)
+                .0,
+            0
+        );
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 2 * ONE_NEAR);
+        assert_eq!(contract.locked_balance(accounts(2)).0, 0);
+        assert_eq!(contract.total_locked().0, 0);
+        assert_eq!(contract.ft_total_supply().0, 2 * ONE_NEAR);
+    }
 }
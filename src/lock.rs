@@ -0,0 +1,71 @@
+//! USN locked in the middle of multi-step operations.
+//!
+//! Locked tokens are taken from the account balance, but still counted in the total supply
+//! until they are burned or returned to the account.
+
+use crate::*;
+
+impl Contract {
+    /// Moves tokens from the account balance to the lock.
+    pub(crate) fn internal_lock(&mut self, account_id: &AccountId, amount: Balance) {
+        let balance = self.token.internal_unwrap_balance_of(account_id);
+        let balance = balance
+            .checked_sub(amount)
+            .unwrap_or_else(|| env::panic_str("The account doesn't have enough balance"));
+        self.token.internal_save_balance(account_id, balance);
+
+        let locked = self.locked.get(account_id).unwrap_or(0) + amount;
+        self.locked.insert(account_id, &locked);
+        self.total_locked += amount;
+    }
+
+    /// Returns locked tokens back to the account balance.
+    pub(crate) fn internal_unlock(&mut self, account_id: &AccountId, amount: Balance) {
+        self.internal_release(account_id, amount);
+
+        let balance = self.token.internal_unwrap_balance_of(account_id);
+        self.token
+            .internal_save_balance(account_id, balance + amount);
+    }
+
+    /// Burns locked tokens decreasing the total supply.
+    pub(crate) fn internal_burn_locked(&mut self, account_id: &AccountId, amount: Balance) {
+        self.internal_release(account_id, amount);
+
+        self.token.total_supply = self
+            .token
+            .total_supply
+            .checked_sub(amount)
+            .unwrap_or_else(|| env::panic_str("Total supply overflow"));
+
+        event::emit::ft_burn(account_id, amount, None);
+    }
+
+    fn internal_release(&mut self, account_id: &AccountId, amount: Balance) {
+        let locked = self.locked.get(account_id).unwrap_or(0);
+        let locked = locked
+            .checked_sub(amount)
+            .unwrap_or_else(|| env::panic_str("The account doesn't have enough locked balance"));
+
+        if locked > 0 {
+            self.locked.insert(account_id, &locked);
+        } else {
+            self.locked.remove(account_id);
+        }
+        self.total_locked -= amount;
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Returns USN of the account locked by pending operations, e.g. a sell waiting for the NEAR transfer.
+    pub fn locked_balance(&self, account_id: AccountId) -> U128 {
+        self.locked.get(&account_id).unwrap_or(0).into()
+    }
+
+    /// Returns USN locked by all pending operations.
+    /// It's a part of `ft_total_supply`.
+    pub fn total_locked(&self) -> U128 {
+        self.total_locked.into()
+    }
+}
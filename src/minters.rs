@@ -0,0 +1,178 @@
+//! Configurable minters.
+//!
+//! Bridges and partner protocols can mint and burn USN without the oracle, within an allowance set by the owner.
+//! Minting decreases the allowance, and burning restores it.
+
+use near_sdk::require;
+
+use crate::supply::MintSource;
+use crate::*;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MinterView {
+    pub minter_id: AccountId,
+    pub allowance: U128,
+}
+
+impl Contract {
+    fn internal_minter_allowance(&self, minter_id: &AccountId) -> Balance {
+        self.minters
+            .get(minter_id)
+            .unwrap_or_else(|| env::panic_str("The account is not a minter"))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Adds the minter or replaces its allowance.
+    pub fn configure_minter(&mut self, minter_id: AccountId, allowance: U128) {
+        self.assert_owner();
+        self.minters.insert(&minter_id, &allowance.0);
+    }
+
+    /// Revokes the minter immediately. Can be called by owner or guardians.
+    pub fn remove_minter(&mut self, minter_id: AccountId) {
+        self.assert_owner_or_guardian();
+        require!(
+            self.minters.remove(&minter_id).is_some(),
+            "The account is not a minter"
+        );
+    }
+
+    /// Mints USN within the allowance of the calling minter.
+    #[payable]
+    pub fn mint(&mut self, to: AccountId, amount: U128) {
+        assert_one_yocto();
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        if self.blacklist_status(&to) != BlackListStatus::Allowable {
+            env::panic_str(&format!("Account '{}' is banned", to));
+        }
+
+        let minter_id = env::predecessor_account_id();
+        let allowance = self.internal_minter_allowance(&minter_id);
+        let amount = amount.0;
+
+        require!(amount > 0, "The amount should be positive");
+        require!(amount <= allowance, "The minter allowance is exceeded");
+
+        self.minters.insert(&minter_id, &(allowance - amount));
+        self.internal_mint(
+            &to,
+            amount,
+            MintSource::Minter,
+            Some(&format!("Minted by {}", minter_id)),
+        );
+    }
+
+    /// Burns USN of the calling minter restoring its allowance.
+    #[payable]
+    pub fn burn(&mut self, amount: U128) {
+        assert_one_yocto();
+        self.abort_if_pause();
+
+        let minter_id = env::predecessor_account_id();
+        let allowance = self.internal_minter_allowance(&minter_id);
+        let amount = amount.0;
+
+        require!(amount > 0, "The amount should be positive");
+
+        self.token.internal_withdraw(&minter_id, amount);
+        self.minters.insert(&minter_id, &(allowance + amount));
+        self.record_burn(MintSource::Minter, amount);
+        event::emit::ft_burn(
+            &minter_id,
+            amount,
+            Some(&format!("Burned by {}", minter_id)),
+        );
+    }
+
+    pub fn minter_allowance(&self, minter_id: AccountId) -> Option<U128> {
+        self.minters.get(&minter_id).map(U128)
+    }
+
+    pub fn minters(&self) -> Vec<MinterView> {
+        self.minters
+            .iter()
+            .map(|(minter_id, allowance)| MinterView {
+                minter_id,
+                allowance: allowance.into(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_YOCTO};
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn setup(allowance: Balance) -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.configure_minter(accounts(2), allowance.into());
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        (context, contract)
+    }
+
+    #[test]
+    fn test_mint_burn() {
+        let (_, mut contract) = setup(1000);
+
+        contract.mint(accounts(3), 600.into());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 600);
+        assert_eq!(contract.minter_allowance(accounts(2)).unwrap().0, 400);
+
+        contract.mint(accounts(2), 300.into());
+        contract.burn(200.into());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 100);
+        assert_eq!(contract.minter_allowance(accounts(2)).unwrap().0, 300);
+        assert_eq!(contract.ft_total_supply().0, 700);
+
+        let minters = contract.minters();
+        assert_eq!(minters.len(), 1);
+        assert_eq!(minters[0].minter_id, accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "The minter allowance is exceeded")]
+    fn test_mint_over_allowance() {
+        let (_, mut contract) = setup(1000);
+
+        contract.mint(accounts(3), 600.into());
+        contract.mint(accounts(3), 600.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "The account is not a minter")]
+    fn test_removed_minter() {
+        let (mut context, mut contract) = setup(1000);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_minter(accounts(2));
+        assert!(contract.minter_allowance(accounts(2)).is_none());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.mint(accounts(3), 1.into());
+    }
+}
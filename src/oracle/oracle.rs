@@ -46,6 +46,10 @@ impl ExchangeRate {
     pub fn decimals(&self) -> u8 {
         self.decimals
     }
+
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -100,6 +104,15 @@ impl ExchangeRate {
         }
     }
 
+    pub fn test_rate(multiplier: u128, decimals: u8) -> Self {
+        Self {
+            multiplier,
+            decimals,
+            timestamp: env::block_timestamp(),
+            recency_duration: env::block_timestamp() + 1000000000,
+        }
+    }
+
     pub fn test_old_rate() -> Self {
         Self {
             multiplier: 111439,
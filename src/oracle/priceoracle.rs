@@ -53,6 +53,23 @@ impl PriceData {
     }
 }
 
+#[cfg(test)]
+impl PriceData {
+    pub fn test_price_data(multiplier: u128, decimals: u8) -> Self {
+        Self {
+            timestamp: env::block_timestamp().into(),
+            recency_duration_sec: 90,
+            prices: vec![AssetOptionalPrice {
+                asset_id: "wrap.test.near".to_string(),
+                price: Some(Price {
+                    multiplier: multiplier.into(),
+                    decimals,
+                }),
+            }],
+        }
+    }
+}
+
 #[ext_contract(ext_priceoracle)]
 pub trait PriceOracle {
     fn get_price_data(&self, asset_ids: Vec<AssetId>) -> PriceData;
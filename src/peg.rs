@@ -0,0 +1,624 @@
+//! Peg keeper of the USN/USDT stable pool.
+//!
+//! Anyone can trigger the keeper, which reads balances of the pool. If USN is scarce in the pool (above peg),
+//! the keeper mints USN and adds it as single-sided liquidity. If USN is in excess (below peg),
+//! it removes USN, which it has added before, and burns it. Actions are limited by the max amount and the cooldown.
+//! Shares are added and burned no worse than at par, so every action is profitable for the protocol.
+//! The caller is rewarded with a share of the amount.
+
+use near_sdk::{require, PromiseResult, Timestamp, ONE_YOCTO};
+
+use crate::pool::{ext_ref_finance, gas_for_ref_withdraw, ref_withdrawn_amounts, StablePoolInfo};
+use crate::supply::MintSource;
+use crate::*;
+
+const GAS_FOR_GET_STABLE_POOL: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_HANDLE_PEG_UPDATE: Gas = Gas(180_000_000_000_000);
+const GAS_FOR_PEG_TRANSFER_CALL: Gas = Gas(35_000_000_000_000);
+const GAS_FOR_PEG_LIQUIDITY: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_PEG_WITHDRAW: Gas = gas_for_ref_withdraw(1);
+const GAS_FOR_HANDLE_PEG_BURN: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_HANDLE_PEG_ADDED: Gas =
+    Gas(10_000_000_000_000 + GAS_FOR_PEG_WITHDRAW.0 + GAS_FOR_HANDLE_PEG_BURN.0);
+const GAS_FOR_HANDLE_PEG_DEPOSIT: Gas =
+    Gas(10_000_000_000_000 + GAS_FOR_PEG_LIQUIDITY.0 + GAS_FOR_HANDLE_PEG_ADDED.0);
+const GAS_FOR_HANDLE_PEG_REMOVED: Gas =
+    Gas(10_000_000_000_000 + GAS_FOR_PEG_WITHDRAW.0 + GAS_FOR_HANDLE_PEG_BURN.0);
+
+/// The deviation and the reward have 6 decimals: 1_000_000 is 100%.
+const PEG_DECIMAL: u8 = 6;
+
+/// Parameters of the peg keeper: `max_amount` of USN per action, `cooldown` in seconds between actions,
+/// `min_deviation` of the pool imbalance to act, and `reward` of the caller as a share of the amount.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PegKeeperParams {
+    pub max_amount: U128,
+    pub cooldown: U64,
+    pub min_deviation: U128,
+    pub reward: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct PegKeeper {
+    /// `None` disables the keeper.
+    params: Option<PegKeeperParams>,
+    updated_at: Timestamp,
+    /// USN added to the pool by the keeper, which can be removed.
+    debt: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PegKeeperView {
+    pub params: Option<PegKeeperParams>,
+    pub updated_at: U64,
+    pub debt: U128,
+}
+
+/// Indexes of USN and USDT in the pool.
+fn token_indexes(info: &StablePoolInfo, usdt_addr: &AccountId) -> (usize, usize) {
+    let usn_addr = env::current_account_id();
+    let index_of = |token: &AccountId| {
+        info.token_account_ids
+            .iter()
+            .position(|id| id == token)
+            .unwrap_or_else(|| env::panic_str(&format!("{} isn't in the pool", token)))
+    };
+    (index_of(&usn_addr), index_of(usdt_addr))
+}
+
+/// Calculates `value * numerator / denominator`.
+fn share(value: Balance, numerator: Balance, denominator: Balance) -> Balance {
+    (U256::from(value) * U256::from(numerator) / U256::from(denominator)).as_u128()
+}
+
+#[ext_contract(ext_peg_self)]
+trait PegKeeperHandler {
+    #[private]
+    fn handle_peg_update(
+        &mut self,
+        keeper: AccountId,
+        #[callback] info: StablePoolInfo,
+    ) -> PromiseOrValue<U128>;
+
+    #[private]
+    fn handle_peg_deposit(
+        &mut self,
+        keeper: AccountId,
+        amounts: Vec<U128>,
+        usn_index: usize,
+        min_shares: U128,
+    ) -> PromiseOrValue<U128>;
+
+    #[private]
+    fn handle_peg_added(&mut self, keeper: AccountId, amount: U128) -> PromiseOrValue<U128>;
+
+    #[private]
+    fn handle_peg_removed(&mut self, keeper: AccountId, amount: U128) -> Promise;
+
+    #[private]
+    fn handle_peg_burn(&mut self, keeper: Option<AccountId>, amount: U128) -> U128;
+}
+
+pub(crate) trait PegKeeperHandler {
+    fn handle_peg_update(
+        &mut self,
+        keeper: AccountId,
+        info: StablePoolInfo,
+    ) -> PromiseOrValue<U128>;
+
+    fn handle_peg_deposit(
+        &mut self,
+        keeper: AccountId,
+        amounts: Vec<U128>,
+        usn_index: usize,
+        min_shares: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn handle_peg_added(&mut self, keeper: AccountId, amount: U128) -> PromiseOrValue<U128>;
+
+    fn handle_peg_removed(&mut self, keeper: AccountId, amount: U128) -> Promise;
+
+    fn handle_peg_burn(&mut self, keeper: Option<AccountId>, amount: U128) -> U128;
+}
+
+#[near_bindgen]
+impl PegKeeperHandler for Contract {
+    /// Mints and adds, or removes USN depending on balances of the pool.
+    /// Returns 0, if the pool is balanced enough.
+    #[private]
+    fn handle_peg_update(
+        &mut self,
+        keeper: AccountId,
+        #[callback] info: StablePoolInfo,
+    ) -> PromiseOrValue<U128> {
+        let params = self.peg_params();
+        let (usn_index, usdt_index) = token_indexes(&info, &self.pool_settings.usdt_address);
+
+        let usn_balance = info.c_amounts[usn_index].0;
+        let usdt_balance = info.c_amounts[usdt_index].0;
+        let total = info
+            .c_amounts
+            .iter()
+            .map(|amount| amount.0)
+            .sum::<Balance>();
+        let shares_total = info.shares_total_supply.0;
+
+        let imbalance = usn_balance.abs_diff(usdt_balance);
+        if total == 0
+            || share(imbalance, 10u128.pow(PEG_DECIMAL as u32), total) < params.min_deviation.0
+        {
+            return PromiseOrValue::Value(0.into());
+        }
+
+        let usn_addr = env::current_account_id();
+        let ref_addr = self.pool_settings.ref_address.clone();
+        let mut amounts = vec![U128(0); info.token_account_ids.len()];
+
+        if usn_balance < usdt_balance {
+            // USN is above peg: mint and add it.
+            let amount = std::cmp::min(imbalance / 2, params.max_amount.0);
+            self.assert_collateralized();
+            // Liquidity is accounted in advance, and it's unwound on failure.
+            self.internal_add_pool_usn(self.pool_settings.stable_pool_id, amount);
+            self.internal_mint(&usn_addr, amount, MintSource::Pool, Some("Peg keeper"));
+
+            amounts[usn_index] = amount.into();
+            // Shares are worth at least the USN amount at par.
+            let min_shares = share(amount, shares_total, total);
+
+            event::emit::peg_update(&keeper, true, amount);
+
+            PromiseOrValue::Promise(
+                self.token
+                    .internal_transfer_call(
+                        &usn_addr,
+                        &ref_addr,
+                        amount,
+                        GAS_FOR_PEG_TRANSFER_CALL,
+                        None,
+                        "".to_string(), // Empty message == deposit action on the ref-finance.
+                    )
+                    .then(ext_peg_self::handle_peg_deposit(
+                        keeper,
+                        amounts,
+                        usn_index,
+                        min_shares.into(),
+                        usn_addr,
+                        NO_DEPOSIT,
+                        GAS_FOR_HANDLE_PEG_DEPOSIT,
+                    )),
+            )
+        } else {
+            // USN is below peg: remove and burn it.
+            let amount = std::cmp::min(
+                std::cmp::min(imbalance / 2, params.max_amount.0),
+                self.peg.debt,
+            );
+            if amount == 0 {
+                return PromiseOrValue::Value(0.into());
+            }
+
+            amounts[usn_index] = amount.into();
+            // Shares are burned at most at par.
+            let max_burn_shares = share(amount, shares_total, total) + 1;
+
+            event::emit::peg_update(&keeper, false, amount);
+
+            PromiseOrValue::Promise(
+                ext_ref_finance::remove_liquidity_by_tokens(
+                    self.pool_settings.stable_pool_id,
+                    amounts,
+                    max_burn_shares.into(),
+                    ref_addr,
+                    ONE_YOCTO,
+                    GAS_FOR_PEG_LIQUIDITY,
+                )
+                .then(ext_peg_self::handle_peg_removed(
+                    keeper,
+                    amount.into(),
+                    usn_addr,
+                    NO_DEPOSIT,
+                    GAS_FOR_HANDLE_PEG_REMOVED,
+                )),
+            )
+        }
+    }
+
+    /// Adds the USN deposited to ref-finance as liquidity. USN, which hasn't reached ref-finance,
+    /// is burned right away from the contract balance.
+    #[private]
+    fn handle_peg_deposit(
+        &mut self,
+        keeper: AccountId,
+        mut amounts: Vec<U128>,
+        usn_index: usize,
+        min_shares: U128,
+    ) -> PromiseOrValue<U128> {
+        let amount = amounts[usn_index].0;
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<U128>(&value).map_or(0, |used| used.0)
+            }
+            _ => 0,
+        };
+        let used = std::cmp::min(amount, used);
+
+        let usn_addr = env::current_account_id();
+        if used < amount {
+            let unused = amount - used;
+            self.token.internal_withdraw(&usn_addr, unused);
+            self.record_burn(MintSource::Pool, unused);
+            event::emit::ft_burn(&usn_addr, unused, Some("Peg keeper"));
+            self.internal_remove_pool_usn(self.pool_settings.stable_pool_id, unused);
+        }
+        if used == 0 {
+            return PromiseOrValue::Value(0.into());
+        }
+
+        amounts[usn_index] = used.into();
+        PromiseOrValue::Promise(
+            ext_ref_finance::add_stable_liquidity(
+                self.pool_settings.stable_pool_id,
+                amounts,
+                share(min_shares.0, used, amount).into(),
+                self.pool_settings.ref_address.clone(),
+                ONE_YOCTO,
+                GAS_FOR_PEG_LIQUIDITY,
+            )
+            .then(ext_peg_self::handle_peg_added(
+                keeper,
+                used.into(),
+                usn_addr,
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_PEG_ADDED,
+            )),
+        )
+    }
+
+    /// Rewards the keeper, if the liquidity has been added.
+    /// Otherwise, withdraws the minted USN back to burn it.
+    #[private]
+    fn handle_peg_added(&mut self, keeper: AccountId, amount: U128) -> PromiseOrValue<U128> {
+        if is_promise_success() {
+            self.peg.debt += amount.0;
+            self.internal_reward_peg_keeper(&keeper, amount.0);
+            return PromiseOrValue::Value(amount);
+        }
+
+        let usn_addr = env::current_account_id();
+        PromiseOrValue::Promise(
+            self.internal_ref_withdraw(vec![(usn_addr.clone(), amount)])
+                .then(ext_peg_self::handle_peg_burn(
+                    None,
+                    amount,
+                    usn_addr,
+                    NO_DEPOSIT,
+                    GAS_FOR_HANDLE_PEG_BURN,
+                )),
+        )
+    }
+
+    /// Withdraws the removed USN to burn it. Without the removal, USN deposited to ref-finance
+    /// for other purposes would be withdrawn instead.
+    #[private]
+    fn handle_peg_removed(&mut self, keeper: AccountId, amount: U128) -> Promise {
+        require!(is_promise_success(), "Liquidity hasn't been removed");
+
+        let usn_addr = env::current_account_id();
+        self.internal_ref_withdraw(vec![(usn_addr.clone(), amount)])
+            .then(ext_peg_self::handle_peg_burn(
+                Some(keeper),
+                amount,
+                usn_addr,
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_PEG_BURN,
+            ))
+    }
+
+    /// Burns USN withdrawn from ref-finance. The keeper is rewarded, if USN has been removed from the pool.
+    /// USN, which hasn't left the ref-finance deposit, stays accounted as liquidity of the pool.
+    #[private]
+    fn handle_peg_burn(&mut self, keeper: Option<AccountId>, amount: U128) -> U128 {
+        let burned = std::cmp::min(ref_withdrawn_amounts(1)[0], amount.0);
+        if burned == 0 {
+            return 0.into();
+        }
+
+        let usn_addr = env::current_account_id();
+        self.token.internal_withdraw(&usn_addr, burned);
+        self.record_burn(MintSource::Pool, burned);
+        event::emit::ft_burn(&usn_addr, burned, Some("Peg keeper"));
+        self.internal_remove_pool_usn(self.pool_settings.stable_pool_id, burned);
+
+        if let Some(keeper) = keeper {
+            self.peg.debt = self.peg.debt.saturating_sub(burned);
+            self.internal_reward_peg_keeper(&keeper, burned);
+        }
+
+        burned.into()
+    }
+}
+
+impl PegKeeper {
+    /// USN added to the pool by the keeper, which hasn't been removed yet.
+    pub fn debt(&self) -> Balance {
+        self.debt
+    }
+}
+
+impl Contract {
+    fn peg_params(&self) -> PegKeeperParams {
+        self.peg
+            .params
+            .clone()
+            .unwrap_or_else(|| env::panic_str("The peg keeper is disabled"))
+    }
+
+    /// Mints the reward within the supply caps.
+    fn internal_reward_peg_keeper(&mut self, keeper: &AccountId, amount: Balance) {
+        let reward = match &self.peg.params {
+            Some(params) => share(amount, params.reward.0, 10u128.pow(PEG_DECIMAL as u32)),
+            None => 0,
+        };
+        let reward = match self.mint_headroom(MintSource::Pool) {
+            Some(headroom) => std::cmp::min(reward, headroom),
+            None => reward,
+        };
+
+        if reward > 0 {
+            self.internal_mint(keeper, reward, MintSource::Pool, Some("Peg keeper reward"));
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Checks the stable pool and restores the peg, if it's needed. Can be called by anyone
+    /// once per cooldown.
+    pub fn update_peg(&mut self) -> Promise {
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        let params = self.peg_params();
+        require!(
+            env::block_timestamp() >= self.peg.updated_at + params.cooldown.0 * 1_000_000_000,
+            "The peg keeper is cooling down"
+        );
+        self.peg.updated_at = env::block_timestamp();
+
+        ext_ref_finance::get_stable_pool(
+            self.pool_settings.stable_pool_id,
+            self.pool_settings.ref_address.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_STABLE_POOL,
+        )
+        .then(ext_peg_self::handle_peg_update(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_PEG_UPDATE,
+        ))
+    }
+
+    /// Sets parameters of the peg keeper. `None` disables it.
+    pub fn set_peg_keeper(&mut self, params: Option<PegKeeperParams>) {
+        self.assert_owner();
+
+        if let Some(params) = &params {
+            require!(
+                params.reward.0 <= MAX_SPREAD,
+                &format!("Peg keeper reward limit is {}", MAX_SPREAD)
+            );
+        }
+
+        self.peg.params = params;
+    }
+
+    pub fn peg_keeper(&self) -> PegKeeperView {
+        PegKeeperView {
+            params: self.peg.params.clone(),
+            updated_at: self.peg.updated_at.into(),
+            debt: self.peg.debt.into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const ONE_USN: Balance = 1_000_000_000_000_000_000;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn setup() -> (VMContextBuilder, Contract) {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_peg_keeper(Some(PegKeeperParams {
+            max_amount: U128(1000 * ONE_USN),
+            cooldown: U64(60),
+            min_deviation: U128(10_000), // 1%
+            reward: U128(1000),          // 0.1%
+        }));
+        (context, contract)
+    }
+
+    fn pool(usn: Balance, usdt: Balance) -> StablePoolInfo {
+        StablePoolInfo {
+            token_account_ids: vec!["usdt.test.near".parse().unwrap(), accounts(0)],
+            decimals: vec![6, 18],
+            amounts: vec![U128(usdt / 10u128.pow(12)), U128(usn)],
+            c_amounts: vec![U128(usdt), U128(usn)],
+            total_fee: 5,
+            shares_total_supply: U128(usn + usdt),
+            amp: 240,
+        }
+    }
+
+    fn with_result(context: &mut VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![result],
+        );
+    }
+
+    #[test]
+    fn test_peg_above() {
+        let (mut context, mut contract) = setup();
+
+        // 1% of the pool is in balance.
+        let result = contract.handle_peg_update(accounts(2), pool(9950 * ONE_USN, 10050 * ONE_USN));
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+
+        // 1000 USN max of the 1500 USN half-imbalance.
+        contract.handle_peg_update(accounts(2), pool(8000 * ONE_USN, 11000 * ONE_USN));
+        assert_eq!(contract.ft_total_supply().0, 1000 * ONE_USN);
+        assert_eq!(
+            contract.ft_balance_of("ref.test.near".parse().unwrap()).0,
+            1000 * ONE_USN
+        );
+
+        let pool_id = contract.stable_pool_id();
+        assert_eq!(contract.stable_pool(pool_id).unwrap().usn.0, 1000 * ONE_USN);
+
+        with_result(
+            &mut context,
+            PromiseResult::Successful(b"\"1000\"".to_vec()),
+        );
+        contract.handle_peg_added(accounts(2), (1000 * ONE_USN).into());
+        assert_eq!(contract.peg_keeper().debt.0, 1000 * ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, ONE_USN);
+        assert_eq!(contract.stable_pool(pool_id).unwrap().usn.0, 1000 * ONE_USN);
+    }
+
+    #[test]
+    fn test_peg_deposit_failed() {
+        let (mut context, mut contract) = setup();
+
+        contract.handle_peg_update(accounts(2), pool(8000 * ONE_USN, 11000 * ONE_USN));
+
+        // ref-finance has refused the deposit, and USN is back at the contract.
+        contract.token.internal_transfer(
+            &"ref.test.near".parse().unwrap(),
+            &accounts(0),
+            1000 * ONE_USN,
+            None,
+        );
+        with_result(&mut context, PromiseResult::Successful(b"\"0\"".to_vec()));
+        let result = contract.handle_peg_deposit(
+            accounts(2),
+            vec![U128(0), U128(1000 * ONE_USN)],
+            1,
+            U128(1000 * ONE_USN),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+
+        assert_eq!(contract.ft_total_supply().0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.peg_keeper().debt.0, 0);
+        let pool_id = contract.stable_pool_id();
+        assert_eq!(contract.stable_pool(pool_id).unwrap().usn.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool cap is exceeded")]
+    fn test_peg_pool_cap() {
+        let (_, mut contract) = setup();
+
+        let pool_id = contract.stable_pool_id();
+        contract.set_stable_pool_cap(pool_id, Some(U128(500 * ONE_USN)));
+        contract.handle_peg_update(accounts(2), pool(8000 * ONE_USN, 11000 * ONE_USN));
+    }
+
+    #[test]
+    fn test_peg_below() {
+        let (mut context, mut contract) = setup();
+
+        // Nothing to remove without the added USN.
+        let result = contract.handle_peg_update(accounts(2), pool(11000 * ONE_USN, 9000 * ONE_USN));
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+
+        contract.peg.debt = 400 * ONE_USN;
+        let pool_id = contract.stable_pool_id();
+        contract.internal_add_pool_usn(pool_id, 400 * ONE_USN);
+        contract.handle_peg_update(accounts(2), pool(11000 * ONE_USN, 9000 * ONE_USN));
+
+        // USN has been withdrawn from ref-finance.
+        contract.token.internal_deposit(&accounts(0), 400 * ONE_USN);
+        with_result(
+            &mut context,
+            PromiseResult::Successful(format!("[\"{}\"]", 400 * ONE_USN).into_bytes()),
+        );
+        assert_eq!(
+            contract
+                .handle_peg_burn(Some(accounts(2)), (400 * ONE_USN).into())
+                .0,
+            400 * ONE_USN
+        );
+        assert_eq!(contract.peg_keeper().debt.0, 0);
+        assert_eq!(contract.stable_pool(pool_id).unwrap().usn.0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 400 * ONE_USN / 1000);
+    }
+
+    #[test]
+    fn test_peg_burn_not_withdrawn() {
+        let (mut context, mut contract) = setup();
+        contract.peg.debt = 400 * ONE_USN;
+        let pool_id = contract.stable_pool_id();
+        contract.internal_add_pool_usn(pool_id, 400 * ONE_USN);
+
+        // The transfer from ref-finance has failed, USN of the savings vault isn't burned.
+        contract.token.internal_deposit(&accounts(0), 400 * ONE_USN);
+        with_result(&mut context, PromiseResult::Successful(b"[\"0\"]".to_vec()));
+        assert_eq!(
+            contract
+                .handle_peg_burn(Some(accounts(2)), (400 * ONE_USN).into())
+                .0,
+            0
+        );
+        assert_eq!(contract.peg_keeper().debt.0, 400 * ONE_USN);
+        assert_eq!(contract.stable_pool(pool_id).unwrap().usn.0, 400 * ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 400 * ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidity hasn't been removed")]
+    fn test_peg_removal_failed() {
+        let (mut context, mut contract) = setup();
+
+        with_result(&mut context, PromiseResult::Failed);
+        contract.handle_peg_removed(accounts(2), (400 * ONE_USN).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "The peg keeper is cooling down")]
+    fn test_peg_cooldown() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(1_000_000_000_000)
+            .build());
+
+        contract.update_peg();
+        contract.update_peg();
+    }
+}
@@ -1,44 +1,179 @@
-use near_sdk::{require, ONE_YOCTO};
+use near_sdk::{require, PromiseResult, ONE_YOCTO};
 
 use crate::*;
 
 use std::collections::HashMap;
 
-const MINIMUM_DEPOSIT: u128 = 1000_000; // $1000000
+const MINIMUM_DEPOSIT: u128 = 1000_000_000_000; // $1000000
 
 const GAS_FOR_GET_PROMISE: Gas = Gas(10_000_000_000_000);
 const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(35_000_000_000_000);
-const GAS_FOR_HANDLE_ADD_LIQUIDITY_PROMISE: Gas = Gas(20_000_000_000_000);
 const GAS_FOR_ADD_LIQUIDITY_PROMISE: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_REMOVE_LIQUIDITY_PROMISE: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_WITHDRAW_PROMISE: Gas = Gas(35_000_000_000_000);
+const GAS_FOR_HANDLE_PROMISE: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_HANDLE_WITHDRAW_PROMISE: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_HANDLE_POOL_SETTINGS: Gas = Gas(10_000_000_000_000);
 
-const USDT_DECIMALS: u8 = 6;
+pub(crate) const USDT_DECIMALS: u8 = 6;
 
-struct PoolConfig {
+/// The `ft_transfer_call` message, which funds stable pool liquidity with partner tokens.
+pub(crate) const LIQUIDITY_MSG: &str = "liquidity";
+
+pub(crate) struct PoolConfig {
     pub ref_address: &'static str,
     pub usdt_address: &'static str,
+    pub wnear_address: &'static str,
     pub stable_pool_id: u64,
 }
 
-const CONFIG: PoolConfig = if cfg!(feature = "mainnet") {
+pub(crate) const CONFIG: PoolConfig = if cfg!(feature = "mainnet") {
     PoolConfig {
         ref_address: "v2.ref-finance.near",
         usdt_address: "dac17f958d2ee523a2206206994597c13d831ec7.factory.bridge.near",
+        wnear_address: "wrap.near",
         stable_pool_id: 3020,
     }
 } else if cfg!(feature = "testnet") {
     PoolConfig {
         ref_address: "ref-finance-101.testnet",
         usdt_address: "usdt.fakes.testnet",
+        wnear_address: "wrap.testnet",
         stable_pool_id: 356,
     }
 } else {
     PoolConfig {
         ref_address: "ref.test.near",
         usdt_address: "usdt.test.near",
+        wnear_address: "wrap.test.near",
         stable_pool_id: 0,
     }
 };
 
+/// Settings of the USDT/USN stable pool, which the owner can change.
+/// Defaults are chosen by cargo features.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolSettings {
+    pub ref_address: AccountId,
+    pub usdt_address: AccountId,
+    pub stable_pool_id: u64,
+    /// The minimum value to add with `transfer_stable_liquidity`, in USDT with decimals.
+    pub min_deposit: U128,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            ref_address: CONFIG.ref_address.parse().unwrap(),
+            usdt_address: CONFIG.usdt_address.parse().unwrap(),
+            stable_pool_id: CONFIG.stable_pool_id,
+            min_deposit: MINIMUM_DEPOSIT.into(),
+        }
+    }
+}
+
+/// A token paired with USN in a stable pool.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PartnerToken {
+    pub token_id: AccountId,
+    pub decimals: u8,
+}
+
+/// A stable pool, which the contract provides with USN liquidity.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StablePool {
+    /// Tokens of the pool except USN.
+    tokens: Vec<PartnerToken>,
+    /// The maximum of USN added to the pool, `None` is unlimited.
+    cap: Option<Balance>,
+    /// USN added to the pool and not removed yet.
+    usn: Balance,
+    /// Partner tokens added to the pool and not removed yet, in the sequence of `tokens`.
+    amounts: Vec<Balance>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StablePoolView {
+    pub pool_id: u64,
+    pub tokens: Vec<PartnerToken>,
+    pub cap: Option<U128>,
+    pub usn: U128,
+    pub amounts: Vec<U128>,
+}
+
+impl StablePool {
+    pub fn new(tokens: Vec<PartnerToken>) -> Self {
+        Self {
+            amounts: vec![0; tokens.len()],
+            tokens,
+            cap: None,
+            usn: 0,
+        }
+    }
+
+    /// The USDT/USN pool of pool settings.
+    pub fn usdt(usdt_address: AccountId) -> Self {
+        Self::new(vec![PartnerToken {
+            token_id: usdt_address,
+            decimals: USDT_DECIMALS,
+        }])
+    }
+
+    /// USN amount of the same value as partner token amounts at par.
+    fn usn_value(&self, amounts: &[U128]) -> Balance {
+        self.tokens
+            .iter()
+            .zip(amounts)
+            .map(|(token, amount)| extend_decimals(amount.0, TOKEN_DECIMAL - token.decimals))
+            .sum()
+    }
+
+    /// Token amounts in the sequence of the pool tokens, which can be in any order.
+    fn pool_amounts(&self, info: &StablePoolInfo, usn_amount: U128, amounts: &[U128]) -> Vec<U128> {
+        let usn_addr = env::current_account_id();
+
+        info.token_account_ids
+            .iter()
+            .map(|token_id| {
+                if *token_id == usn_addr {
+                    return usn_amount;
+                }
+                match self
+                    .tokens
+                    .iter()
+                    .position(|token| token.token_id == *token_id)
+                {
+                    Some(index) => amounts[index],
+                    None => env::panic_str(&format!("Unexpected token in the pool: {}", token_id)),
+                }
+            })
+            .collect::<Vec<U128>>()
+    }
+
+    fn has_liquidity(&self) -> bool {
+        self.usn > 0 || self.amounts.iter().any(|amount| *amount > 0)
+    }
+
+    fn assert_cap(&self, usn_amount: Balance) {
+        if let Some(cap) = self.cap {
+            require!(self.usn + usn_amount <= cap, "The pool cap is exceeded");
+        }
+    }
+
+    fn view(&self, pool_id: u64) -> StablePoolView {
+        StablePoolView {
+            pool_id,
+            tokens: self.tokens.clone(),
+            cap: self.cap.map(U128),
+            usn: self.usn.into(),
+            amounts: self.amounts.iter().map(|amount| U128(*amount)).collect(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct StablePoolInfo {
@@ -56,12 +191,25 @@ pub struct StablePoolInfo {
     pub amp: u64,
 }
 
+/// A single swap on ref-finance.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
 #[ext_contract(ext_ref_finance)]
-trait RefFinance {
+pub(crate) trait RefFinance {
     fn get_stable_pool(&self, pool_id: u64) -> StablePoolInfo;
 
     fn get_deposits(&self, account_id: AccountId) -> HashMap<AccountId, U128>;
 
+    fn get_pool_shares(&self, pool_id: u64, account_id: AccountId) -> U128;
+
     #[payable]
     fn add_stable_liquidity(&mut self, pool_id: u64, amounts: Vec<U128>, min_shares: U128) -> U128;
 
@@ -72,10 +220,18 @@ trait RefFinance {
         amounts: Vec<U128>,
         max_burn_shares: U128,
     ) -> U128;
+
+    #[payable]
+    fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128;
+
+    #[payable]
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>) -> Promise;
 }
 
 #[ext_contract(ext_ft)]
-trait Usdt {
+pub(crate) trait Usdt {
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+
     #[payable]
     fn ft_transfer_call(
         &mut self,
@@ -89,35 +245,145 @@ trait Usdt {
 #[ext_contract(ext_pool_self)]
 trait RefFinanceHandler {
     #[private]
-    fn handle_transfer_then_mint(
-        &mut self,
-        whole_amount: U128,
-        #[callback] transferred_usdt_amount: U128,
-    ) -> PromiseOrValue<()>;
+    fn handle_transfer_then_mint(&mut self, pool_id: u64, amounts: Vec<U128>)
+        -> PromiseOrValue<()>;
 
     #[private]
     #[payable]
     fn handle_deposit_then_add_liquidity(
         &mut self,
-        whole_amount: U128,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        min_shares: U128,
         #[callback] deposits: HashMap<AccountId, U128>,
         #[callback] info: StablePoolInfo,
     ) -> Promise;
-}
 
-trait RefFinanceHandler {
-    fn handle_transfer_then_mint(
+    #[private]
+    fn handle_add_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> PromiseOrValue<U128>;
+
+    #[private]
+    fn handle_pool_then_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+        max_burn_shares: U128,
+        #[callback] info: StablePoolInfo,
+    ) -> Promise;
+
+    #[private]
+    fn handle_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> Promise;
+
+    #[private]
+    fn handle_withdraw_then_burn(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> U128;
+
+    #[private]
+    fn handle_deposits_then_withdraw(
+        &mut self,
+        withdrawals: Vec<(AccountId, U128)>,
+        #[callback] deposits: HashMap<AccountId, U128>,
+    ) -> Promise;
+
+    #[private]
+    fn handle_ref_withdraw(
+        &mut self,
+        withdrawals: Vec<(AccountId, U128)>,
+        deposits: Vec<U128>,
+        #[callback] remaining: HashMap<AccountId, U128>,
+    ) -> Vec<U128>;
+
+    #[private]
+    fn handle_pool_settings(&mut self, settings: PoolSettings, #[callback] info: StablePoolInfo);
+
+    #[private]
+    fn handle_seed_liquidity_funds(&mut self, #[callback] balance: U128);
+
+    #[private]
+    fn handle_add_stable_pool(
         &mut self,
-        whole_amount: U128,
-        transferred_usdt_amount: U128,
-    ) -> PromiseOrValue<()>;
+        pool_id: u64,
+        cap: Option<U128>,
+        #[callback] info: StablePoolInfo,
+    );
+}
+
+pub(crate) trait RefFinanceHandler {
+    fn handle_transfer_then_mint(&mut self, pool_id: u64, amounts: Vec<U128>)
+        -> PromiseOrValue<()>;
 
     fn handle_deposit_then_add_liquidity(
         &mut self,
-        whole_amount: U128,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        min_shares: U128,
         deposits: HashMap<AccountId, U128>,
         info: StablePoolInfo,
     ) -> Promise;
+
+    fn handle_add_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> PromiseOrValue<U128>;
+
+    fn handle_pool_then_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+        max_burn_shares: U128,
+        info: StablePoolInfo,
+    ) -> Promise;
+
+    fn handle_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> Promise;
+
+    fn handle_withdraw_then_burn(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> U128;
+
+    fn handle_deposits_then_withdraw(
+        &mut self,
+        withdrawals: Vec<(AccountId, U128)>,
+        deposits: HashMap<AccountId, U128>,
+    ) -> Promise;
+
+    fn handle_ref_withdraw(
+        &mut self,
+        withdrawals: Vec<(AccountId, U128)>,
+        deposits: Vec<U128>,
+        remaining: HashMap<AccountId, U128>,
+    ) -> Vec<U128>;
+
+    fn handle_pool_settings(&mut self, settings: PoolSettings, info: StablePoolInfo);
+
+    fn handle_seed_liquidity_funds(&mut self, balance: U128);
+
+    fn handle_add_stable_pool(&mut self, pool_id: u64, cap: Option<U128>, info: StablePoolInfo);
 }
 
 #[near_bindgen]
@@ -125,31 +391,60 @@ impl RefFinanceHandler for Contract {
     #[private]
     fn handle_transfer_then_mint(
         &mut self,
-        whole_amount: U128,
-        #[callback] transferred_usdt_amount: U128,
+        pool_id: u64,
+        amounts: Vec<U128>,
     ) -> PromiseOrValue<()> {
-        let transferred_usdt_amount: u128 = transferred_usdt_amount.into();
+        self.abort_if_settled();
 
-        if !is_promise_success() || transferred_usdt_amount == 0 {
-            // USDT transfer failed, skip minting.
-            return PromiseOrValue::Value(());
-        }
+        let pool = self.internal_stable_pool(pool_id);
 
-        // A theoretical corner case when USDT has transferred partially.
+        // Results follow the sequence of transfers: only non-zero amounts are transferred.
+        // A theoretical corner case when a token has transferred partially.
         // Let's equalize liquidity and finish depositing.
-        let usdt_whole_amount = remove_decimals(transferred_usdt_amount, USDT_DECIMALS);
-        let whole_amount = std::cmp::min(whole_amount.into(), usdt_whole_amount);
+        let mut result_index = 0;
+        let transferred = amounts
+            .iter()
+            .map(|amount| {
+                if amount.0 == 0 {
+                    return U128(0);
+                }
+                let used = match env::promise_result(result_index) {
+                    PromiseResult::Successful(value) => {
+                        near_sdk::serde_json::from_slice::<U128>(&value).map_or(0, |used| used.0)
+                    }
+                    _ => 0,
+                };
+                result_index += 1;
+                U128(std::cmp::min(amount.0, used))
+            })
+            .collect::<Vec<U128>>();
+
+        // Tokens, which haven't reached ref-finance, are back in liquidity funds.
+        for ((token, amount), used) in pool.tokens.iter().zip(&amounts).zip(&transferred) {
+            self.internal_fund_liquidity(&token.token_id, amount.0 - used.0);
+        }
+
+        let usn_amount = pool.usn_value(&transferred);
+        if usn_amount == 0 {
+            // Transfers failed, skip minting.
+            return PromiseOrValue::Value(());
+        }
 
         let usn_addr = env::current_account_id();
-        let ref_addr = CONFIG.ref_address.parse().unwrap();
-        let usn_balance = self.token.internal_unwrap_balance_of(&usn_addr);
-        let usn_amount = extend_decimals(whole_amount, self.decimals());
+        let ref_addr = self.pool_settings.ref_address.clone();
+        // USN of the savings vault isn't liquidity.
+        let usn_balance = self
+            .token
+            .internal_unwrap_balance_of(&usn_addr)
+            .saturating_sub(self.savings.assets());
 
         // Mint necessary USN amount.
         if usn_balance < usn_amount {
+            // This USN isn't backed by the contract reserves.
+            self.assert_collateralized();
+
             let yet_to_mint = usn_amount - usn_balance;
-            self.token.internal_deposit(&usn_addr, yet_to_mint);
-            event::emit::ft_mint(&usn_addr, yet_to_mint, None);
+            self.internal_mint(&usn_addr, yet_to_mint, MintSource::Pool, None);
         }
 
         PromiseOrValue::Promise(self.token.internal_transfer_call(
@@ -166,140 +461,1116 @@ impl RefFinanceHandler for Contract {
     #[payable]
     fn handle_deposit_then_add_liquidity(
         &mut self,
-        whole_amount: U128,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        min_shares: U128,
         #[callback] deposits: HashMap<AccountId, U128>,
         #[callback] info: StablePoolInfo,
     ) -> Promise {
+        let mut pool = self.internal_stable_pool(pool_id);
         let usn_addr = env::current_account_id();
-        let usdt_addr = CONFIG.usdt_address.parse().unwrap();
-        let ref_addr = CONFIG.ref_address.parse().unwrap();
-        let usn_amount: u128 = extend_decimals(whole_amount.0, self.decimals());
-        let usdt_amount: u128 = extend_decimals(whole_amount.0, USDT_DECIMALS);
-        let usdt_deposit: u128 = deposits.get(&usdt_addr).unwrap_or(&0u128.into()).0;
-        let usn_deposit: u128 = deposits.get(&usn_addr).unwrap_or(&0u128.into()).0;
+        let usn_amount = pool.usn_value(&amounts);
 
-        require!(
-            usdt_deposit >= usdt_amount,
-            &format!("Not enough USDT: {} < {},", usdt_deposit, usdt_amount)
-        );
+        for (token, amount) in pool.tokens.iter().zip(&amounts) {
+            let deposit = deposits.get(&token.token_id).map_or(0, |deposit| deposit.0);
+            require!(
+                deposit >= amount.0,
+                &format!("Not enough {}: {} < {},", token.token_id, deposit, amount.0)
+            );
+        }
+        let usn_deposit: u128 = deposits.get(&usn_addr).map_or(0, |deposit| deposit.0);
         require!(
             usn_deposit >= usn_amount,
             &format!("Not enough USN: {} < {},", usn_deposit, usn_amount)
         );
 
-        // Preserve the sequence of token amounts.
-        let token_amounts = info
-            .token_account_ids
-            .iter()
-            .map(|token| {
-                if *token == usdt_addr {
-                    U128::from(usdt_amount)
-                } else if *token == usn_addr {
-                    U128::from(usn_amount)
-                } else {
-                    env::panic_str(&format!("Unexpected token in the pool: {}", token));
-                }
-            })
-            .collect::<Vec<U128>>();
+        pool.assert_cap(usn_amount);
+        let token_amounts = pool.pool_amounts(&info, usn_amount.into(), &amounts);
 
-        let min_shares = NO_DEPOSIT.into();
+        // Liquidity is accounted in advance, and it's unwound on failure.
+        pool.usn += usn_amount;
+        for (added, amount) in pool.amounts.iter_mut().zip(&amounts) {
+            *added += amount.0;
+        }
+        self.stable_pools.insert(&pool_id, &pool);
 
+        // ref-finance rejects adding liquidity for fewer shares, then deposits are unwound.
         ext_ref_finance::add_stable_liquidity(
-            CONFIG.stable_pool_id,
+            pool_id,
             token_amounts,
             min_shares,
-            ref_addr,
+            self.pool_settings.ref_address.clone(),
             env::attached_deposit(),
             GAS_FOR_ADD_LIQUIDITY_PROMISE,
         )
+        .then(ext_pool_self::handle_add_liquidity(
+            pool_id,
+            usn_amount.into(),
+            amounts,
+            usn_addr,
+            NO_DEPOSIT,
+            gas_for_handle_liquidity(pool.tokens.len()),
+        ))
+    }
+
+    /// Returns minted pool shares. If liquidity hasn't been added,
+    /// withdraws deposits from ref-finance and burns USN.
+    #[private]
+    fn handle_add_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> PromiseOrValue<U128> {
+        match env::promise_result(0) {
+            PromiseResult::Successful(value) => PromiseOrValue::Value(
+                near_sdk::serde_json::from_slice::<U128>(&value).unwrap_or(U128(0)),
+            ),
+            _ => PromiseOrValue::Promise(
+                self.internal_withdraw_then_burn(pool_id, usn_amount, amounts),
+            ),
+        }
+    }
+
+    #[private]
+    fn handle_pool_then_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+        max_burn_shares: U128,
+        #[callback] info: StablePoolInfo,
+    ) -> Promise {
+        let pool = self.internal_stable_pool(pool_id);
+        let token_amounts = pool.pool_amounts(&info, usn_amount, &amounts);
+
+        ext_ref_finance::remove_liquidity_by_tokens(
+            pool_id,
+            token_amounts,
+            max_burn_shares,
+            self.pool_settings.ref_address.clone(),
+            ONE_YOCTO,
+            GAS_FOR_REMOVE_LIQUIDITY_PROMISE,
+        )
+        .then(ext_pool_self::handle_remove_liquidity(
+            pool_id,
+            usn_amount,
+            amounts,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            gas_for_handle_liquidity(pool.tokens.len()),
+        ))
+    }
+
+    /// Withdraws the removed tokens, if liquidity has been removed. Otherwise, tokens deposited
+    /// to ref-finance for other purposes would be withdrawn instead.
+    #[private]
+    fn handle_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> Promise {
+        require!(is_promise_success(), "Liquidity hasn't been removed");
+        self.internal_withdraw_then_burn(pool_id, usn_amount, amounts)
+    }
+
+    /// Burns USN withdrawn from ref-finance. Partner tokens stay at "usn" accounts.
+    /// Returns the burned amount.
+    #[private]
+    fn handle_withdraw_then_burn(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> U128 {
+        let usn_addr = env::current_account_id();
+
+        // Withdrawn amounts: USN first, then partner tokens.
+        let mut withdrawn = ref_withdrawn_amounts(amounts.len() + 1)
+            .into_iter()
+            .zip(std::iter::once(usn_amount).chain(amounts))
+            .map(|(withdrawn, amount)| std::cmp::min(withdrawn, amount.0))
+            .collect::<Vec<Balance>>();
+        let burned = withdrawn.remove(0);
+
+        if burned > 0 {
+            self.token.internal_withdraw(&usn_addr, burned);
+            self.record_burn(MintSource::Pool, burned);
+            event::emit::ft_burn(&usn_addr, burned, Some("Stable liquidity"));
+        }
+
+        // The pool could be removed from the registry meanwhile.
+        if let Some(mut pool) = self.stable_pools.get(&pool_id) {
+            pool.usn = pool.usn.saturating_sub(burned);
+            for (added, amount) in pool.amounts.iter_mut().zip(&withdrawn) {
+                *added = added.saturating_sub(*amount);
+            }
+            self.stable_pools.insert(&pool_id, &pool);
+
+            // Withdrawn partner tokens can be added again.
+            for (token, amount) in pool.tokens.iter().zip(&withdrawn) {
+                self.internal_fund_liquidity(&token.token_id, *amount);
+            }
+
+            let tokens = pool
+                .tokens
+                .into_iter()
+                .map(|token| token.token_id)
+                .zip(withdrawn)
+                .collect::<Vec<_>>();
+            event::emit::stable_liquidity_withdrawn(pool_id, burned, &tokens);
+        }
+
+        burned.into()
+    }
+
+    /// Withdraws the tokens from ref-finance, remembering deposits before the withdrawal.
+    #[private]
+    fn handle_deposits_then_withdraw(
+        &mut self,
+        withdrawals: Vec<(AccountId, U128)>,
+        #[callback] deposits: HashMap<AccountId, U128>,
+    ) -> Promise {
+        let usn_addr = env::current_account_id();
+        let ref_addr = self.pool_settings.ref_address.clone();
+        let deposits = withdrawals
+            .iter()
+            .map(|(token_id, _)| deposits.get(token_id).copied().unwrap_or(U128(0)))
+            .collect::<Vec<U128>>();
+
+        // Withdraw only non-zero amounts: ref-finance rejects empty withdrawals.
+        withdrawals
+            .iter()
+            .filter(|(_, amount)| amount.0 > 0)
+            .map(|(token_id, amount)| {
+                ext_ref_finance::withdraw(
+                    token_id.clone(),
+                    *amount,
+                    None,
+                    ref_addr.clone(),
+                    ONE_YOCTO,
+                    GAS_FOR_WITHDRAW_PROMISE,
+                )
+            })
+            .reduce(|all, withdrawal| all.and(withdrawal))
+            .unwrap_or_else(|| env::panic_str("The amount should be positive"))
+            .then(ext_ref_finance::get_deposits(
+                usn_addr.clone(),
+                ref_addr,
+                NO_DEPOSIT,
+                GAS_FOR_GET_PROMISE,
+            ))
+            .then(ext_pool_self::handle_ref_withdraw(
+                withdrawals,
+                deposits,
+                usn_addr,
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_WITHDRAW_PROMISE,
+            ))
+    }
+
+    /// Returns amounts, which have left the ref-finance deposit. ref-finance reports a successful
+    /// withdrawal even if the transfer has failed and the deposit is restored.
+    #[private]
+    fn handle_ref_withdraw(
+        &mut self,
+        withdrawals: Vec<(AccountId, U128)>,
+        deposits: Vec<U128>,
+        #[callback] remaining: HashMap<AccountId, U128>,
+    ) -> Vec<U128> {
+        withdrawals
+            .iter()
+            .zip(deposits)
+            .map(|((token_id, amount), deposit)| {
+                let left = remaining.get(token_id).map_or(0, |left| left.0);
+                U128(std::cmp::min(amount.0, deposit.0.saturating_sub(left)))
+            })
+            .collect()
+    }
+
+    /// Applies the settings, if the pool consists of USN and USDT.
+    #[private]
+    fn handle_pool_settings(&mut self, settings: PoolSettings, #[callback] info: StablePoolInfo) {
+        let usn_addr = env::current_account_id();
+        let usdt_index = info
+            .token_account_ids
+            .iter()
+            .position(|token| *token == settings.usdt_address);
+
+        require!(
+            info.token_account_ids.len() == 2 && info.token_account_ids.contains(&usn_addr),
+            "The pool must consist of USN and USDT"
+        );
+        match usdt_index {
+            Some(index) => require!(
+                info.decimals[index] == USDT_DECIMALS,
+                &format!("USDT must have {} decimals", USDT_DECIMALS)
+            ),
+            None => env::panic_str("The pool must consist of USN and USDT"),
+        }
+
+        // Positions could have been added meanwhile.
+        self.assert_pool_settings_change(&settings);
+        self.internal_sync_stable_pools(&settings);
+        self.pool_settings = settings;
+    }
+
+    /// Credits USDT of the contract, which isn't PSM or USDT reserves, to liquidity funds.
+    #[private]
+    fn handle_seed_liquidity_funds(&mut self, #[callback] balance: U128) {
+        let usdt_addr = self.pool_settings.usdt_address.clone();
+        let psm_reserve = self.psm.get(&usdt_addr).map_or(0, |asset| asset.reserve);
+        let funds = self.liquidity_funds.get(&usdt_addr).unwrap_or(0);
+        let tracked = self.reserves.usdt + psm_reserve + funds;
+
+        self.internal_fund_liquidity(&usdt_addr, balance.0.saturating_sub(tracked));
+    }
+
+    /// Registers the pool, if it has USN. Partner tokens and decimals come from the pool.
+    #[private]
+    fn handle_add_stable_pool(
+        &mut self,
+        pool_id: u64,
+        cap: Option<U128>,
+        #[callback] info: StablePoolInfo,
+    ) {
+        let usn_addr = env::current_account_id();
+
+        require!(
+            info.token_account_ids.contains(&usn_addr),
+            "The pool must contain USN"
+        );
+        require!(
+            self.stable_pools.get(&pool_id).is_none(),
+            "The pool is already registered"
+        );
+
+        let tokens = info
+            .token_account_ids
+            .into_iter()
+            .zip(info.decimals)
+            .filter(|(token_id, _)| *token_id != usn_addr)
+            .map(|(token_id, decimals)| {
+                require!(
+                    decimals <= TOKEN_DECIMAL,
+                    &format!("{} has more than {} decimals", token_id, TOKEN_DECIMAL)
+                );
+                PartnerToken { token_id, decimals }
+            })
+            .collect::<Vec<PartnerToken>>();
+        require!(!tokens.is_empty(), "The pool must have partner tokens");
+
+        let mut pool = StablePool::new(tokens);
+        pool.cap = cap.map(|cap| cap.0);
+        self.stable_pools.insert(&pool_id, &pool);
+    }
+}
+
+/// Gas to withdraw the tokens from ref-finance and to check deposits after the withdrawal.
+const fn gas_for_deposits_then_withdraw(tokens: usize) -> Gas {
+    Gas(GAS_FOR_WITHDRAW_PROMISE.0 * tokens as u64
+        + GAS_FOR_GET_PROMISE.0
+        + GAS_FOR_HANDLE_WITHDRAW_PROMISE.0 * 2)
+}
+
+/// Gas to withdraw the tokens from ref-finance, checking deposits before and after.
+pub(crate) const fn gas_for_ref_withdraw(tokens: usize) -> Gas {
+    Gas(GAS_FOR_GET_PROMISE.0 + gas_for_deposits_then_withdraw(tokens).0)
+}
+
+/// Gas to withdraw the tokens from ref-finance and to burn USN.
+fn gas_for_withdraw_then_burn(tokens: usize) -> Gas {
+    Gas(gas_for_ref_withdraw(tokens).0 + GAS_FOR_HANDLE_WITHDRAW_PROMISE.0)
+}
+
+/// Amounts withdrawn by `internal_ref_withdraw`, the result of the previous promise.
+/// Nothing has been withdrawn, if the promise has failed.
+pub(crate) fn ref_withdrawn_amounts(count: usize) -> Vec<Balance> {
+    match env::promise_result(0) {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<Vec<U128>>(&value)
+            .map_or(vec![0; count], |amounts| {
+                amounts.into_iter().map(|amount| amount.0).collect()
+            }),
+        _ => vec![0; count],
+    }
+}
+
+/// Gas to handle added liquidity, which could be unwound, or removed liquidity, which is withdrawn,
+/// including USN.
+fn gas_for_handle_liquidity(partner_tokens: usize) -> Gas {
+    Gas(GAS_FOR_HANDLE_WITHDRAW_PROMISE.0 + gas_for_withdraw_then_burn(partner_tokens + 1).0)
+}
+
+impl Contract {
+    /// Credits partner tokens, which can be added to stable pools.
+    pub(crate) fn internal_fund_liquidity(&mut self, token_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            let funds = self.liquidity_funds.get(token_id).unwrap_or(0);
+            self.liquidity_funds.insert(token_id, &(funds + amount));
+        }
+    }
+
+    /// Takes partner tokens from liquidity funds. Other token balances of "usn",
+    /// e.g. PSM and USDT reserves, are never spent for liquidity.
+    fn internal_spend_liquidity(&mut self, token_id: &AccountId, amount: Balance) {
+        let funds = self.liquidity_funds.get(token_id).unwrap_or(0);
+        require!(
+            amount <= funds,
+            &format!("Not enough {} in liquidity funds", token_id)
+        );
+        self.liquidity_funds.insert(token_id, &(funds - amount));
+    }
+
+    /// Accounts USN, which the peg keeper adds to the registered pool, within the pool cap.
+    pub(crate) fn internal_add_pool_usn(&mut self, pool_id: u64, amount: Balance) {
+        let mut pool = self.internal_stable_pool(pool_id);
+        pool.assert_cap(amount);
+        pool.usn += amount;
+        self.stable_pools.insert(&pool_id, &pool);
+    }
+
+    /// Accounts USN, which the peg keeper removes from the pool, or unwinds a failed addition.
+    pub(crate) fn internal_remove_pool_usn(&mut self, pool_id: u64, amount: Balance) {
+        // The pool could be removed from the registry meanwhile.
+        if let Some(mut pool) = self.stable_pools.get(&pool_id) {
+            pool.usn = pool.usn.saturating_sub(amount);
+            self.stable_pools.insert(&pool_id, &pool);
+        }
+    }
+
+    /// Positions at the old ref-finance contract or the old stable pool must be withdrawn
+    /// before they're changed: the contract would lose track of them.
+    fn assert_pool_settings_change(&self, settings: &PoolSettings) {
+        let old = &self.pool_settings;
+
+        require!(
+            settings.usdt_address == old.usdt_address || !self.reserves.has_usdt(),
+            "USDT reserves must be swapped before changing USDT"
+        );
+
+        if settings.ref_address != old.ref_address {
+            require!(
+                self.stable_pools
+                    .iter()
+                    .all(|(_, pool)| !pool.has_liquidity()),
+                "Stable pools have liquidity at ref-finance"
+            );
+            require!(
+                !self.reserves.has_ref_deposits(),
+                "Reserves must be withdrawn from ref-finance"
+            );
+        }
+
+        if settings.ref_address != old.ref_address || settings.stable_pool_id != old.stable_pool_id
+        {
+            require!(
+                self.peg.debt() == 0,
+                "The peg keeper has liquidity in the stable pool"
+            );
+            if let Some(pool) = self.stable_pools.get(&old.stable_pool_id) {
+                require!(!pool.has_liquidity(), "The stable pool has liquidity");
+            }
+        }
+    }
+
+    /// Keeps the registry in line with new pool settings. Pools of the old ref-finance contract,
+    /// and the old USDT/USN pool are unregistered: they have no liquidity by now.
+    /// The new USDT/USN pool is registered keeping the cap of the old one.
+    fn internal_sync_stable_pools(&mut self, settings: &PoolSettings) {
+        let old = self.pool_settings.clone();
+        let old_pool = self.stable_pools.get(&old.stable_pool_id);
+
+        if settings.ref_address != old.ref_address {
+            self.stable_pools.clear();
+        } else if settings.stable_pool_id != old.stable_pool_id
+            || settings.usdt_address != old.usdt_address
+        {
+            self.stable_pools.remove(&old.stable_pool_id);
+        }
+
+        if self.stable_pools.get(&settings.stable_pool_id).is_none() {
+            let mut pool = StablePool::usdt(settings.usdt_address.clone());
+            pool.cap = old_pool.and_then(|pool| pool.cap);
+            self.stable_pools.insert(&settings.stable_pool_id, &pool);
+        }
+    }
+
+    /// Queries USDT of the contract to seed liquidity funds after the upgrade: before liquidity funds,
+    /// all the USDT of the contract has been spent on liquidity.
+    pub(crate) fn internal_seed_liquidity_funds(&self) -> Promise {
+        let usn_addr = env::current_account_id();
+        ext_ft::ft_balance_of(
+            usn_addr.clone(),
+            self.pool_settings.usdt_address.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_PROMISE,
+        )
+        .then(ext_pool_self::handle_seed_liquidity_funds(
+            usn_addr,
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_POOL_SETTINGS,
+        ))
+    }
+
+    fn internal_stable_pool(&self, pool_id: u64) -> StablePool {
+        self.stable_pools
+            .get(&pool_id)
+            .unwrap_or_else(|| env::panic_str("The pool is not registered"))
+    }
+
+    /// Withdraws tokens from ref-finance back to "usn" accounts, then burns the USN.
+    fn internal_withdraw_then_burn(
+        &self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+    ) -> Promise {
+        let usn_addr = env::current_account_id();
+        let pool = self.internal_stable_pool(pool_id);
+
+        let withdrawals = std::iter::once(usn_addr.clone())
+            .chain(pool.tokens.into_iter().map(|token| token.token_id))
+            .zip(std::iter::once(usn_amount).chain(amounts.clone()))
+            .collect();
+
+        self.internal_ref_withdraw(withdrawals)
+            .then(ext_pool_self::handle_withdraw_then_burn(
+                pool_id,
+                usn_amount,
+                amounts,
+                usn_addr,
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_WITHDRAW_PROMISE,
+            ))
+    }
+
+    /// Withdraws tokens from ref-finance. The promise results in amounts, which have actually
+    /// reached the contract, measured by ref-finance deposits before and after the withdrawal.
+    pub(crate) fn internal_ref_withdraw(&self, withdrawals: Vec<(AccountId, U128)>) -> Promise {
+        require!(
+            withdrawals.iter().any(|(_, amount)| amount.0 > 0),
+            "The amount should be positive"
+        );
+        let usn_addr = env::current_account_id();
+        let tokens = withdrawals.len();
+
+        ext_ref_finance::get_deposits(
+            usn_addr.clone(),
+            self.pool_settings.ref_address.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_PROMISE,
+        )
+        .then(ext_pool_self::handle_deposits_then_withdraw(
+            withdrawals,
+            usn_addr,
+            NO_DEPOSIT,
+            gas_for_deposits_then_withdraw(tokens),
+        ))
     }
 }
 
 #[near_bindgen]
 impl Contract {
     pub fn stable_pool_id(&self) -> u64 {
-        CONFIG.stable_pool_id
+        self.pool_settings.stable_pool_id
+    }
+
+    pub fn stable_pool_settings(&self) -> PoolSettings {
+        self.pool_settings.clone()
+    }
+
+    pub fn stable_pool(&self, pool_id: u64) -> Option<StablePoolView> {
+        self.stable_pools
+            .get(&pool_id)
+            .map(|pool| pool.view(pool_id))
+    }
+
+    /// Partner tokens, which can be added to stable pools.
+    pub fn liquidity_funds(&self, token_id: AccountId) -> U128 {
+        self.liquidity_funds.get(&token_id).unwrap_or(0).into()
+    }
+
+    pub fn stable_pools(&self) -> Vec<StablePoolView> {
+        self.stable_pools
+            .iter()
+            .map(|(pool_id, pool)| pool.view(pool_id))
+            .collect()
+    }
+
+    /// Changes settings of the stable pool, once the pool is checked at ref-finance.
+    /// USDT reserves must be swapped back to NEAR before changing USDT.
+    pub fn set_stable_pool_settings(&mut self, settings: PoolSettings) -> Promise {
+        self.assert_owner();
+        self.assert_pool_settings_change(&settings);
+
+        ext_ref_finance::get_stable_pool(
+            settings.stable_pool_id,
+            settings.ref_address.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_PROMISE,
+        )
+        .then(ext_pool_self::handle_pool_settings(
+            settings,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_POOL_SETTINGS,
+        ))
     }
 
-    /// Transfers liquidity from USDT and USN accounts to ref.finance on behalf of "usn".
+    /// Registers a stable pool of USN and partner tokens, once the pool is checked at ref-finance.
     ///
-    ///  * `whole_amount` - token amount WITHOUT decimals, e.g. "1000" means $1000.
+    ///  * `cap` - the maximum of USN added to the pool, `None` is unlimited.
+    pub fn add_stable_pool(&mut self, pool_id: u64, cap: Option<U128>) -> Promise {
+        self.assert_owner();
+
+        ext_ref_finance::get_stable_pool(
+            pool_id,
+            self.pool_settings.ref_address.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_PROMISE,
+        )
+        .then(ext_pool_self::handle_add_stable_pool(
+            pool_id,
+            cap,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_POOL_SETTINGS,
+        ))
+    }
+
+    /// Removes the pool from the registry. Its USN liquidity must be withdrawn before.
+    pub fn remove_stable_pool(&mut self, pool_id: u64) {
+        self.assert_owner();
+
+        let pool = self.internal_stable_pool(pool_id);
+        require!(!pool.has_liquidity(), "The pool has liquidity");
+        self.stable_pools.remove(&pool_id);
+    }
+
+    /// Sets the maximum of USN added to the pool, `None` is unlimited.
+    pub fn set_stable_pool_cap(&mut self, pool_id: u64, cap: Option<U128>) {
+        self.assert_owner();
+
+        let mut pool = self.internal_stable_pool(pool_id);
+        pool.cap = cap.map(|cap| cap.0);
+        self.stable_pools.insert(&pool_id, &pool);
+    }
+
+    /// Transfers liquidity from partner token and USN accounts to ref.finance on behalf of "usn".
+    /// Partner tokens are taken from liquidity funds, see `LIQUIDITY_MSG`.
+    ///
+    ///  * `pool_id` - a registered stable pool.
+    ///  * `amounts` - partner token amounts WITH decimals in the sequence of the pool `tokens`,
+    ///    e.g. "1000000000" means $1000 of USDT. USN is added of the same value.
+    ///  * `min_shares` - the minimum of pool shares to receive, otherwise deposits are withdrawn back
+    ///    and the minted USN is burned.
     ///
     /// # Algorithm
     ///
-    /// Step 1. `USDT -> REF`: ft_transfer_call from "usn" USDT account to ref.finance contract.
-    /// Step 2. `USN -> REF`: If USDT transfer successful, then
-    ///          * mint as much USN as successfully transferred USDT,
+    /// Step 1. `TOKEN -> REF`: ft_transfer_call from "usn" token accounts to ref.finance contract.
+    /// Step 2. `USN -> REF`: If token transfers successful, then
+    ///          * mint as much USN as successfully transferred tokens are worth,
     ///          * ft_transfer_call of minted USN to ref.finance contract.
     /// Step 3. Check balances, ignoring step 1 & 2. It allows to repeat adding liquidity
     ///         next time with full ref.finance deposits (transfers would fail in this case).
-    /// Step 4. `REF -> POOL`: add_stable_liquidity to the USDT/USN stable pool filling it
+    /// Step 4. `REF -> POOL`: add_stable_liquidity to the stable pool filling it
     ///         from usn deposit.
+    /// Step 5. If fewer than `min_shares` would be minted, withdraw deposits and burn USN.
     #[payable]
-    pub fn transfer_stable_liquidity(&mut self, whole_amount: U128) -> Promise {
+    pub fn transfer_stable_liquidity(
+        &mut self,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        min_shares: U128,
+    ) -> Promise {
         self.assert_owner();
 
-        // 1st yoctoNEAR is for USDT ft_transfer_call.
+        let pool = self.internal_stable_pool(pool_id);
+        require!(
+            amounts.len() == pool.tokens.len(),
+            "Amounts must follow tokens of the pool"
+        );
+
+        // 1 yoctoNEAR is for every token ft_transfer_call.
         // More NEARs could be required for add_stable_liquidity.
+        let transfers = amounts.iter().filter(|amount| amount.0 > 0).count() as u128;
         require!(
-            env::attached_deposit() > 1,
-            "Requires attached deposit more than 1 yoctoNEAR"
+            env::attached_deposit() > transfers,
+            &format!(
+                "Requires attached deposit more than {} yoctoNEAR",
+                transfers
+            )
         );
 
+        let usn_amount = pool.usn_value(&amounts);
+        let min_deposit = self.pool_settings.min_deposit.0;
         require!(
-            whole_amount.0 >= MINIMUM_DEPOSIT,
-            &format!("The minimum deposit: ${}", MINIMUM_DEPOSIT)
+            usn_amount >= extend_decimals(min_deposit, TOKEN_DECIMAL - USDT_DECIMALS),
+            &format!("The minimum deposit: {} USDT", min_deposit)
         );
+        pool.assert_cap(usn_amount);
+
+        for (token, amount) in pool.tokens.iter().zip(&amounts) {
+            self.internal_spend_liquidity(&token.token_id, amount.0);
+        }
 
         let usn_addr = env::current_account_id();
-        let usdt_addr: AccountId = CONFIG.usdt_address.parse().unwrap();
-        let ref_addr: AccountId = CONFIG.ref_address.parse().unwrap();
-        let usdt_amount: u128 = extend_decimals(whole_amount.0, USDT_DECIMALS);
+        let ref_addr = self.pool_settings.ref_address.clone();
 
-        // Do 2 transfers: "usn":USDT -> ref-finance, then "usn":USN -> ref-finance.
-        // Mint USN once after successful USDT transfer.
+        // Do token transfers: "usn":TOKEN -> ref-finance, then "usn":USN -> ref-finance.
+        // Mint USN once after successful token transfers.
         // Ignoring transfer results overall, relying on the deposit state.
-        ext_ft::ft_transfer_call(
-            ref_addr.clone(),
-            usdt_amount.into(),
-            None,
-            "".to_string(), // Empty message == deposit action on the ref-finance.
-            usdt_addr,
-            ONE_YOCTO,
-            GAS_FOR_FT_TRANSFER_CALL,
-        )
-        .then(ext_pool_self::handle_transfer_then_mint(
-            whole_amount,
-            usn_addr.clone(),
-            NO_DEPOSIT,
-            GAS_FOR_FT_TRANSFER_CALL,
-        ))
-        // Double-check deposits and pool configuration.
-        .then(ext_ref_finance::get_deposits(
-            usn_addr.clone(),
-            ref_addr.clone(),
+        let deposits = pool
+            .tokens
+            .iter()
+            .zip(&amounts)
+            .filter(|(_, amount)| amount.0 > 0)
+            .map(|(token, amount)| {
+                ext_ft::ft_transfer_call(
+                    ref_addr.clone(),
+                    *amount,
+                    None,
+                    "".to_string(), // Empty message == deposit action on the ref-finance.
+                    token.token_id.clone(),
+                    ONE_YOCTO,
+                    GAS_FOR_FT_TRANSFER_CALL,
+                )
+            })
+            .reduce(|all, deposit| all.and(deposit))
+            .unwrap();
+
+        deposits
+            .then(ext_pool_self::handle_transfer_then_mint(
+                pool_id,
+                amounts.clone(),
+                usn_addr.clone(),
+                NO_DEPOSIT,
+                GAS_FOR_FT_TRANSFER_CALL,
+            ))
+            // Double-check deposits and pool configuration.
+            .then(ext_ref_finance::get_deposits(
+                usn_addr.clone(),
+                ref_addr.clone(),
+                NO_DEPOSIT,
+                GAS_FOR_GET_PROMISE,
+            ))
+            .and(ext_ref_finance::get_stable_pool(
+                pool_id,
+                ref_addr,
+                0,
+                GAS_FOR_GET_PROMISE,
+            ))
+            .then(ext_pool_self::handle_deposit_then_add_liquidity(
+                pool_id,
+                amounts,
+                min_shares,
+                usn_addr,
+                env::attached_deposit() - transfers,
+                Gas(GAS_FOR_HANDLE_PROMISE.0
+                    + GAS_FOR_ADD_LIQUIDITY_PROMISE.0
+                    + gas_for_handle_liquidity(pool.tokens.len()).0),
+            ))
+    }
+
+    /// Removes liquidity from the stable pool and burns the returned USN.
+    ///
+    ///  * `pool_id` - a registered stable pool.
+    ///  * `usn_amount`, `amounts` - USN and partner token amounts WITH decimals to remove from the pool.
+    ///    Partner token amounts follow the sequence of the pool `tokens`.
+    ///  * `max_burn_shares` - the maximum of pool shares to burn for the amounts.
+    ///
+    /// All the tokens are withdrawn from ref.finance back to "usn" accounts.
+    #[payable]
+    pub fn withdraw_stable_liquidity(
+        &mut self,
+        pool_id: u64,
+        usn_amount: U128,
+        amounts: Vec<U128>,
+        max_burn_shares: U128,
+    ) -> Promise {
+        self.assert_owner();
+        assert_one_yocto();
+
+        let pool = self.internal_stable_pool(pool_id);
+        require!(
+            amounts.len() == pool.tokens.len(),
+            "Amounts must follow tokens of the pool"
+        );
+        require!(
+            usn_amount.0 > 0 || amounts.iter().any(|amount| amount.0 > 0),
+            "The amount should be positive"
+        );
+
+        let usn_addr = env::current_account_id();
+
+        ext_ref_finance::get_stable_pool(
+            pool_id,
+            self.pool_settings.ref_address.clone(),
             NO_DEPOSIT,
             GAS_FOR_GET_PROMISE,
-        ))
-        .and(ext_ref_finance::get_stable_pool(
-            CONFIG.stable_pool_id,
-            ref_addr.clone(),
-            0,
-            GAS_FOR_GET_PROMISE,
-        ))
-        .then(ext_pool_self::handle_deposit_then_add_liquidity(
-            whole_amount,
+        )
+        .then(ext_pool_self::handle_pool_then_remove_liquidity(
+            pool_id,
+            usn_amount,
+            amounts,
+            max_burn_shares,
             usn_addr,
-            env::attached_deposit() - 1,
-            GAS_FOR_HANDLE_ADD_LIQUIDITY_PROMISE,
+            NO_DEPOSIT,
+            Gas(GAS_FOR_HANDLE_PROMISE.0
+                + GAS_FOR_REMOVE_LIQUIDITY_PROMISE.0
+                + gas_for_handle_liquidity(pool.tokens.len()).0),
         ))
     }
 }
 
-fn extend_decimals(whole: u128, decimals: u8) -> u128 {
+pub(crate) fn extend_decimals(whole: u128, decimals: u8) -> u128 {
     whole * 10u128.pow(decimals as u32)
 }
 
-fn remove_decimals(amount: u128, decimals: u8) -> u128 {
-    amount / 10u128.pow(decimals as u32)
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+    use super::*;
+
+    const ONE_USN: Balance = 1_000_000_000_000_000_000;
+
+    fn setup() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(accounts(1));
+        (context, contract)
+    }
+
+    fn with_results(context: &mut VMContextBuilder, results: Vec<PromiseResult>) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            results,
+        );
+    }
+
+    fn pool(token_account_ids: Vec<AccountId>, decimals: Vec<u8>) -> StablePoolInfo {
+        StablePoolInfo {
+            amounts: vec![U128(0); token_account_ids.len()],
+            c_amounts: vec![U128(0); token_account_ids.len()],
+            token_account_ids,
+            decimals,
+            total_fee: 5,
+            shares_total_supply: U128(0),
+            amp: 240,
+        }
+    }
+
+    fn usdc() -> AccountId {
+        "usdc.test.near".parse().unwrap()
+    }
+
+    fn dai() -> AccountId {
+        "dai.test.near".parse().unwrap()
+    }
+
+    /// Registers the USDC/USN/DAI tri-pool.
+    fn add_tri_pool(contract: &mut Contract) {
+        contract.handle_add_stable_pool(
+            7,
+            Some(U128(3000 * ONE_USN)),
+            pool(vec![usdc(), accounts(0), dai()], vec![6, 18, 18]),
+        );
+    }
+
+    #[test]
+    fn test_add_stable_pool() {
+        let (_, mut contract) = setup();
+        add_tri_pool(&mut contract);
+
+        let pools = contract.stable_pools();
+        assert_eq!(pools.len(), 2);
+        assert_eq!(pools[0].pool_id, 0);
+        assert_eq!(
+            pools[0].tokens[0].token_id,
+            "usdt.test.near".parse().unwrap()
+        );
+
+        let tri_pool = contract.stable_pool(7).unwrap();
+        assert_eq!(
+            tri_pool.tokens,
+            vec![
+                PartnerToken {
+                    token_id: usdc(),
+                    decimals: 6
+                },
+                PartnerToken {
+                    token_id: dai(),
+                    decimals: 18
+                }
+            ]
+        );
+        assert_eq!(tri_pool.cap, Some(U128(3000 * ONE_USN)));
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool must contain USN")]
+    fn test_add_stable_pool_without_usn() {
+        let (_, mut contract) = setup();
+        contract.handle_add_stable_pool(7, None, pool(vec![usdc(), dai()], vec![6, 18]));
+    }
+
+    #[test]
+    fn test_pool_amounts() {
+        let (_, mut contract) = setup();
+        add_tri_pool(&mut contract);
+
+        // USN is in the middle of the tri-pool.
+        let tri_pool = contract.internal_stable_pool(7);
+        let amounts = vec![U128(1000_000_000), U128(2000 * ONE_USN)];
+        let usn_amount = tri_pool.usn_value(&amounts);
+        assert_eq!(usn_amount, 3000 * ONE_USN);
+        assert_eq!(
+            tri_pool.pool_amounts(
+                &pool(vec![dai(), usdc(), accounts(0)], vec![18, 6, 18]),
+                usn_amount.into(),
+                &amounts
+            ),
+            vec![
+                U128(2000 * ONE_USN),
+                U128(1000_000_000),
+                U128(3000 * ONE_USN)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deposit_then_add_liquidity() {
+        let (mut context, mut contract) = setup();
+        add_tri_pool(&mut contract);
+
+        let amounts = vec![U128(1000_000_000), U128(1000 * ONE_USN)];
+        let deposits = vec![
+            (usdc(), U128(1000_000_000)),
+            (dai(), U128(1000 * ONE_USN)),
+            (accounts(0), U128(2000 * ONE_USN)),
+        ];
+
+        with_results(&mut context, vec![]);
+        contract.handle_deposit_then_add_liquidity(
+            7,
+            amounts,
+            U128(0),
+            deposits.into_iter().collect(),
+            pool(vec![usdc(), accounts(0), dai()], vec![6, 18, 18]),
+        );
+
+        let tri_pool = contract.stable_pool(7).unwrap();
+        assert_eq!(tri_pool.usn.0, 2000 * ONE_USN);
+        assert_eq!(
+            tri_pool.amounts,
+            vec![U128(1000_000_000), U128(1000 * ONE_USN)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool cap is exceeded")]
+    fn test_pool_cap() {
+        let (mut context, mut contract) = setup();
+        add_tri_pool(&mut contract);
+        contract.pool_settings.min_deposit = U128(1000_000_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(3)
+            .build());
+        contract.transfer_stable_liquidity(
+            7,
+            vec![U128(2000_000_000), U128(2000 * ONE_USN)],
+            U128(0),
+        );
+    }
+
+    #[test]
+    fn test_pool_settings() {
+        let (_, mut contract) = setup();
+        let settings = PoolSettings {
+            ref_address: "ref.test.near".parse().unwrap(),
+            usdt_address: usdc(),
+            stable_pool_id: 7,
+            min_deposit: U128(1000_000_000),
+        };
+
+        contract.handle_pool_settings(
+            settings.clone(),
+            pool(vec![accounts(0), usdc()], vec![18, 6]),
+        );
+        assert_eq!(contract.stable_pool_settings(), settings);
+        assert_eq!(contract.stable_pool_id(), 7);
+
+        // The registry follows the settings.
+        let pools = contract.stable_pools();
+        assert_eq!(pools.len(), 1);
+        assert_eq!(pools[0].pool_id, 7);
+        assert_eq!(pools[0].tokens[0].token_id, usdc());
+    }
+
+    #[test]
+    #[should_panic(expected = "The stable pool has liquidity")]
+    fn test_pool_settings_with_liquidity() {
+        let (_, mut contract) = setup();
+        let pool_id = contract.stable_pool_id();
+        contract.internal_add_pool_usn(pool_id, 1000 * ONE_USN);
+
+        let mut settings = contract.stable_pool_settings();
+        settings.stable_pool_id = 7;
+        contract.set_stable_pool_settings(settings);
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool must consist of USN and USDT")]
+    fn test_pool_settings_wrong_pool() {
+        let (_, mut contract) = setup();
+        let settings = PoolSettings {
+            stable_pool_id: 7,
+            ..PoolSettings::default()
+        };
+
+        contract.handle_pool_settings(settings, pool(vec![accounts(0), usdc()], vec![18, 6]));
+    }
+
+    #[test]
+    fn test_add_liquidity() {
+        let (mut context, mut contract) = setup();
+
+        with_results(
+            &mut context,
+            vec![PromiseResult::Successful(b"\"2000\"".to_vec())],
+        );
+        let result = contract.handle_add_liquidity(0, 1000.into(), vec![1000.into()]);
+        assert!(matches!(result, PromiseOrValue::Value(U128(2000))));
+
+        // Too few shares: deposits are withdrawn back.
+        with_results(&mut context, vec![PromiseResult::Failed]);
+        let result = contract.handle_add_liquidity(0, 1000.into(), vec![1000.into()]);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+    }
+
+    #[test]
+    fn test_withdraw_then_burn() {
+        let (mut context, mut contract) = setup();
+        add_tri_pool(&mut contract);
+
+        // USN and USDC have been withdrawn from ref-finance, DAI hasn't.
+        contract.internal_mint(&accounts(0), 1000 * ONE_USN, MintSource::Pool, None);
+        let mut tri_pool = contract.internal_stable_pool(7);
+        tri_pool.usn = 1000 * ONE_USN;
+        tri_pool.amounts = vec![500_000_000, 500 * ONE_USN];
+        contract.stable_pools.insert(&7, &tri_pool);
+
+        let withdrawn = vec![U128(400 * ONE_USN), U128(200_000_000), U128(0)];
+        with_results(
+            &mut context,
+            vec![PromiseResult::Successful(
+                near_sdk::serde_json::to_vec(&withdrawn).unwrap(),
+            )],
+        );
+        let burned = contract.handle_withdraw_then_burn(
+            7,
+            (400 * ONE_USN).into(),
+            vec![200_000_000.into(), (200 * ONE_USN).into()],
+        );
+
+        assert_eq!(burned.0, 400 * ONE_USN);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 600 * ONE_USN);
+        assert_eq!(contract.ft_total_supply().0, 600 * ONE_USN);
+
+        let tri_pool = contract.stable_pool(7).unwrap();
+        assert_eq!(tri_pool.usn.0, 600 * ONE_USN);
+        assert_eq!(
+            tri_pool.amounts,
+            vec![U128(300_000_000), U128(500 * ONE_USN)]
+        );
+        assert_eq!(contract.liquidity_funds(usdc()).0, 200_000_000);
+        assert_eq!(contract.liquidity_funds(dai()).0, 0);
+    }
+
+    #[test]
+    fn test_ref_withdraw() {
+        let (mut context, mut contract) = setup();
+
+        // USN has left the deposit, the USDC transfer has failed and the deposit is restored.
+        with_results(&mut context, vec![]);
+        let withdrawn = contract.handle_ref_withdraw(
+            vec![
+                (accounts(0), U128(400 * ONE_USN)),
+                (usdc(), U128(400_000_000)),
+                (dai(), U128(0)),
+            ],
+            vec![U128(500 * ONE_USN), U128(400_000_000), U128(0)],
+            vec![
+                (accounts(0), U128(100 * ONE_USN)),
+                (usdc(), U128(400_000_000)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(withdrawn, vec![U128(400 * ONE_USN), U128(0), U128(0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidity hasn't been removed")]
+    fn test_remove_liquidity_failed() {
+        let (mut context, mut contract) = setup();
+
+        with_results(&mut context, vec![PromiseResult::Failed]);
+        contract.handle_remove_liquidity(0, (400 * ONE_USN).into(), vec![400_000_000.into()]);
+    }
+
+    #[test]
+    fn test_seed_liquidity_funds() {
+        let (_, mut contract) = setup();
+        let usdt = contract.stable_pool_settings().usdt_address;
+        contract.reserves.usdt = 100_000_000;
+        contract.internal_fund_liquidity(&usdt, 50_000_000);
+
+        contract.handle_seed_liquidity_funds(U128(1000_000_000));
+        assert_eq!(contract.liquidity_funds(usdt).0, 900_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough usdc.test.near in liquidity funds")]
+    fn test_liquidity_funds() {
+        let (mut context, mut contract) = setup();
+        add_tri_pool(&mut contract);
+        contract.pool_settings.min_deposit = U128(1000_000_000);
+        contract.internal_fund_liquidity(&usdc(), 500_000_000);
+        contract.internal_fund_liquidity(&dai(), 1000 * ONE_USN);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(3)
+            .build());
+        contract.transfer_stable_liquidity(
+            7,
+            vec![U128(1000_000_000), U128(500 * ONE_USN)],
+            U128(0),
+        );
+    }
+
+    #[test]
+    fn test_withdraw_failed() {
+        let (mut context, mut contract) = setup();
+
+        contract.internal_mint(&accounts(0), 1000 * ONE_USN, MintSource::Pool, None);
+        with_results(&mut context, vec![PromiseResult::Failed]);
+        let burned =
+            contract.handle_withdraw_then_burn(0, (400 * ONE_USN).into(), vec![400_000_000.into()]);
+
+        assert_eq!(burned.0, 0);
+        assert_eq!(contract.ft_total_supply().0, 1000 * ONE_USN);
+    }
 }
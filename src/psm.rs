@@ -0,0 +1,439 @@
+//! Peg stability module (PSM): 1:1 mint and redeem of USN for whitelisted stablecoins.
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::{require, ONE_YOCTO};
+
+use crate::pool::{extend_decimals, LIQUIDITY_MSG};
+use crate::*;
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_HANDLE_REDEEM: Gas = Gas(5_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StableAsset {
+    /// Decimals of the stablecoin, e.g. 6 for USDT.
+    pub decimals: u8,
+    /// Fee taken on mint and redeem, in `SPREAD_DECIMAL` precision.
+    pub fee: Balance,
+    /// Maximum amount of USN which can be minted against the stablecoin.
+    pub debt_ceiling: Balance,
+    /// USN minted against the stablecoin and not redeemed yet.
+    pub debt: Balance,
+    /// Stablecoin amount held by the module.
+    pub reserve: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StableAssetView {
+    pub asset_id: AccountId,
+    pub decimals: u8,
+    pub fee: U128,
+    pub debt_ceiling: U128,
+    pub debt: U128,
+    pub reserve: U128,
+}
+
+impl StableAsset {
+    fn view(&self, asset_id: AccountId) -> StableAssetView {
+        StableAssetView {
+            asset_id,
+            decimals: self.decimals,
+            fee: self.fee.into(),
+            debt_ceiling: self.debt_ceiling.into(),
+            debt: self.debt.into(),
+            reserve: self.reserve.into(),
+        }
+    }
+
+    fn fee_of(&self, amount: Balance) -> Balance {
+        (U256::from(amount) * U256::from(self.fee) / U256::from(10u128.pow(SPREAD_DECIMAL as u32)))
+            .as_u128()
+    }
+}
+
+#[ext_contract(ext_stable_ft)]
+pub(crate) trait StableToken {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[ext_contract(ext_psm_self)]
+trait PegStabilityHandler {
+    #[private]
+    fn handle_psm_redeem(
+        &mut self,
+        account: AccountId,
+        asset_id: AccountId,
+        amount: U128,
+        asset_amount: U128,
+        debt_repaid: U128,
+    ) -> U128;
+}
+
+trait PegStabilityHandler {
+    fn handle_psm_redeem(
+        &mut self,
+        account: AccountId,
+        asset_id: AccountId,
+        amount: U128,
+        asset_amount: U128,
+        debt_repaid: U128,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl PegStabilityHandler for Contract {
+    /// Rolls back the redemption if the stablecoin transfer has failed.
+    #[private]
+    fn handle_psm_redeem(
+        &mut self,
+        account: AccountId,
+        asset_id: AccountId,
+        amount: U128,
+        asset_amount: U128,
+        debt_repaid: U128,
+    ) -> U128 {
+        if is_promise_success() {
+            return asset_amount;
+        }
+
+        // The asset could be removed meanwhile, but USN is refunded anyway.
+        if let Some(mut asset) = self.psm.get(&asset_id) {
+            asset.reserve += asset_amount.0;
+            asset.debt += debt_repaid.0;
+            self.psm.insert(&asset_id, &asset);
+        }
+
+        self.internal_remint(
+            &account,
+            amount.0,
+            MintSource::Psm,
+            Some("PSM redeem refund"),
+        );
+
+        0.into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Mints USN 1:1 for a whitelisted stablecoin, minus the asset fee.
+    /// The message must be empty, or `LIQUIDITY_MSG` to fund stable pool liquidity without minting.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.abort_if_pause();
+
+        if self.blacklist_status(&sender_id) != BlackListStatus::Allowable {
+            env::panic_str(&format!("Account '{}' is banned", sender_id));
+        }
+
+        let asset_id = env::predecessor_account_id();
+
+        // Partner tokens for stable pools are kept apart from PSM reserves.
+        if msg == LIQUIDITY_MSG {
+            self.internal_fund_liquidity(&asset_id, amount.0);
+            return PromiseOrValue::Value(0.into());
+        }
+
+        require!(msg.is_empty(), "Unsupported transfer message");
+        let mut asset = self
+            .psm
+            .get(&asset_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Token {} is not supported", asset_id)));
+
+        let usn_amount = extend_decimals(amount.0, TOKEN_DECIMAL - asset.decimals);
+        let fee = asset.fee_of(usn_amount);
+        let minted = usn_amount - fee;
+
+        require!(minted > 0, "Not enough tokens to mint USN");
+
+        asset.debt += minted;
+        asset.reserve += amount.0;
+
+        require!(
+            asset.debt <= asset.debt_ceiling,
+            &format!("Debt ceiling of {} is reached", asset_id)
+        );
+
+        self.psm.insert(&asset_id, &asset);
+
+        self.internal_mint(&sender_id, minted, MintSource::Psm, Some("PSM mint"));
+        event::emit::psm_mint(&sender_id, &asset_id, amount.0, fee);
+
+        PromiseOrValue::Value(0.into())
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Whitelists a stablecoin for the peg stability module.
+    pub fn psm_add_asset(
+        &mut self,
+        asset_id: AccountId,
+        decimals: u8,
+        fee: U128,
+        debt_ceiling: U128,
+    ) {
+        self.assert_owner();
+        require!(
+            self.psm.get(&asset_id).is_none(),
+            "The asset already exists"
+        );
+        require!(decimals <= TOKEN_DECIMAL, "Too many decimals");
+        Self::assert_psm_fee(fee.0);
+
+        let asset = StableAsset {
+            decimals,
+            fee: fee.0,
+            debt_ceiling: debt_ceiling.0,
+            debt: 0,
+            reserve: 0,
+        };
+        self.psm.insert(&asset_id, &asset);
+    }
+
+    /// Removes a stablecoin from the whitelist. The asset reserve must be empty.
+    pub fn psm_remove_asset(&mut self, asset_id: AccountId) {
+        self.assert_owner();
+        let asset = self.psm.get(&asset_id).expect("Unknown stable asset");
+        require!(asset.reserve == 0, "The asset reserve is not empty");
+        self.psm.remove(&asset_id);
+    }
+
+    pub fn psm_set_fee(&mut self, asset_id: AccountId, fee: U128) {
+        self.assert_owner();
+        Self::assert_psm_fee(fee.0);
+        let mut asset = self.psm.get(&asset_id).expect("Unknown stable asset");
+        asset.fee = fee.0;
+        self.psm.insert(&asset_id, &asset);
+    }
+
+    pub fn psm_set_debt_ceiling(&mut self, asset_id: AccountId, debt_ceiling: U128) {
+        self.assert_owner();
+        let mut asset = self.psm.get(&asset_id).expect("Unknown stable asset");
+        asset.debt_ceiling = debt_ceiling.0;
+        self.psm.insert(&asset_id, &asset);
+    }
+
+    pub fn psm_asset(&self, asset_id: AccountId) -> Option<StableAssetView> {
+        self.psm.get(&asset_id).map(|asset| asset.view(asset_id))
+    }
+
+    /// Returns all whitelisted stablecoins with their reserves.
+    pub fn psm_assets(&self) -> Vec<StableAssetView> {
+        self.psm
+            .iter()
+            .map(|(asset_id, asset)| asset.view(asset_id))
+            .collect()
+    }
+
+    /// Redeems USN for the stablecoin held by the peg stability module.
+    /// Returns the stablecoin amount transferred to the caller.
+    #[payable]
+    pub fn psm_redeem(&mut self, asset_id: AccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        let account = env::predecessor_account_id();
+        let amount = Balance::from(amount);
+        let mut asset = self.psm.get(&asset_id).expect("Unknown stable asset");
+
+        let fee = asset.fee_of(amount);
+        let precision = 10u128.pow((TOKEN_DECIMAL - asset.decimals) as u32);
+        let asset_amount = (amount - fee) / precision;
+
+        require!(asset_amount > 0, "Not enough USN to redeem");
+        require!(
+            asset_amount <= asset.reserve,
+            &format!("Not enough {} in the reserve", asset_id)
+        );
+
+        let debt_repaid = std::cmp::min(asset.debt, amount);
+        asset.debt -= debt_repaid;
+        asset.reserve -= asset_amount;
+        self.psm.insert(&asset_id, &asset);
+
+        self.token.internal_withdraw(&account, amount);
+        self.record_burn(MintSource::Psm, amount);
+        event::emit::ft_burn(&account, amount, Some("PSM redeem"));
+        event::emit::psm_redeem(&account, &asset_id, amount, fee);
+
+        ext_stable_ft::ft_transfer(
+            account.clone(),
+            asset_amount.into(),
+            Some("USN redemption".to_string()),
+            asset_id.clone(),
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_psm_self::handle_psm_redeem(
+            account,
+            asset_id,
+            amount.into(),
+            asset_amount.into(),
+            debt_repaid.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_REDEEM,
+        ))
+    }
+
+    fn assert_psm_fee(fee: Balance) {
+        if fee > MAX_SPREAD {
+            env::panic_str(&format!("PSM fee limit is {}", MAX_SPREAD));
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const USDT_DECIMALS: u8 = 6;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn usdt() -> AccountId {
+        "usdt.test.near".parse().unwrap()
+    }
+
+    fn setup(fee: Balance, debt_ceiling: Balance) -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.psm_add_asset(usdt(), USDT_DECIMALS, fee.into(), debt_ceiling.into());
+        testing_env!(context.predecessor_account_id(usdt()).build());
+        (context, contract)
+    }
+
+    #[test]
+    fn test_psm_mint() {
+        let one_usn = 10u128.pow(TOKEN_DECIMAL as u32);
+        let (_, mut contract) = setup(1000, 1_000_000 * one_usn); // 0.1%
+
+        contract.ft_on_transfer(accounts(2), 1_000_000_000.into(), "".to_string()); // $1000
+
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 999 * one_usn);
+        let asset = contract.psm_asset(usdt()).unwrap();
+        assert_eq!(asset.reserve.0, 1_000_000_000);
+        assert_eq!(asset.debt.0, 999 * one_usn);
+    }
+
+    #[test]
+    fn test_liquidity_funding() {
+        let one_usn = 10u128.pow(TOKEN_DECIMAL as u32);
+        let (_, mut contract) = setup(1000, 1_000_000 * one_usn);
+
+        contract.ft_on_transfer(accounts(2), 1_000_000_000.into(), LIQUIDITY_MSG.to_string());
+
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.psm_asset(usdt()).unwrap().reserve.0, 0);
+        assert_eq!(contract.liquidity_funds(usdt()).0, 1_000_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Debt ceiling of usdt.test.near is reached")]
+    fn test_psm_debt_ceiling() {
+        let one_usn = 10u128.pow(TOKEN_DECIMAL as u32);
+        let (_, mut contract) = setup(0, 100 * one_usn);
+
+        contract.ft_on_transfer(accounts(2), 101_000_000.into(), "".to_string());
+        // $101
+    }
+
+    #[test]
+    #[should_panic(expected = "Token charlie is not supported")]
+    fn test_psm_unknown_token() {
+        let (mut context, mut contract) = setup(0, 0);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+
+        contract.ft_on_transfer(accounts(2), 1_000_000.into(), "".to_string());
+    }
+
+    #[test]
+    fn test_psm_redeem() {
+        let one_usn = 10u128.pow(TOKEN_DECIMAL as u32);
+        let (mut context, mut contract) = setup(1000, 1_000_000 * one_usn);
+
+        contract.ft_on_transfer(accounts(2), 1_000_000_000.into(), "".to_string());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.psm_redeem(usdt(), (500 * one_usn).into());
+
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 499 * one_usn);
+        let asset = contract.psm_asset(usdt()).unwrap();
+        assert_eq!(asset.reserve.0, 500_500_000); // $1000 - $499.5
+        assert_eq!(asset.debt.0, 499 * one_usn);
+    }
+
+    #[test]
+    fn test_psm_redeem_refund_removed_asset() {
+        let one_usn = 10u128.pow(TOKEN_DECIMAL as u32);
+        let (mut context, mut contract) = setup(0, 1_000_000 * one_usn);
+
+        contract.ft_on_transfer(accounts(2), 1_000_000.into(), "".to_string()); // $1
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.psm_redeem(usdt(), one_usn.into());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+
+        // The empty asset is removed while the transfer is in flight.
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.psm_remove_asset(usdt());
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.handle_psm_redeem(
+            accounts(2),
+            usdt(),
+            one_usn.into(),
+            1_000_000.into(),
+            one_usn.into(),
+        );
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, one_usn);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough usdt.test.near in the reserve")]
+    fn test_psm_redeem_over_reserve() {
+        let one_usn = 10u128.pow(TOKEN_DECIMAL as u32);
+        let (mut context, mut contract) = setup(0, 1_000_000 * one_usn);
+
+        contract.ft_on_transfer(accounts(2), 1_000_000.into(), "".to_string()); // $1
+        contract.token.internal_deposit(&accounts(2), one_usn);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.psm_redeem(usdt(), (2 * one_usn).into());
+    }
+}
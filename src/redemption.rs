@@ -0,0 +1,405 @@
+//! FIFO queue of sells waiting for NEAR reserves.
+//!
+//! If the contract can't pay out a sell keeping the storage staking floor,
+//! the sold USN stays locked and the redemption is queued with the NEAR amount
+//! fixed by the exchange rate of the sell. Queued redemptions are filled in order
+//! as NEAR arrives with new buys, reserve top-ups, or `process_redemptions` calls.
+//! The payout is repriced at the last oracle price, and never exceeds the fixed NEAR,
+//! so waiting in the queue isn't a free option on NEAR.
+
+use near_sdk::{IntoStorageKey, Timestamp};
+
+use crate::*;
+
+/// Extra NEAR kept on top of the current storage staking for storage growth.
+const STORAGE_RESERVE_BUFFER: Balance = 1_000_000_000_000_000_000_000_000; // 1 NEAR
+
+/// Gas required to fill one redemption: NEAR transfer and the payout callback.
+const GAS_FOR_REDEMPTION_FILL: Gas = Gas(10_000_000_000_000);
+
+const MAX_REDEMPTIONS_PER_CALL: u64 = 10;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Redemption {
+    pub account: AccountId,
+    /// USN locked until the redemption is paid out.
+    pub usn: Balance,
+    /// USN spread of the sell.
+    pub fee: Balance,
+    /// NEAR to pay out at most, fixed by the exchange rate of the sell.
+    pub near: Balance,
+    /// Total NEAR of all redemptions queued before this one.
+    pub queued_before: Balance,
+    pub operation: Option<OperationKey>,
+    pub timestamp: Timestamp,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RedemptionQueue {
+    items: LookupMap<u64, Redemption>,
+    /// Id of the first redemption in the queue.
+    head: u64,
+    /// Id of the next redemption to be queued.
+    tail: u64,
+    /// NEAR of all redemptions ever queued.
+    total_queued: Balance,
+    /// NEAR of all redemptions ever filled.
+    total_filled: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedemptionView {
+    pub id: U64,
+    pub account_id: AccountId,
+    pub usn: U128,
+    pub near: U128,
+    /// Number of redemptions ahead in the queue.
+    pub position: U64,
+    /// NEAR which has to arrive to the contract before the redemption gets filled.
+    pub near_to_fill: U128,
+    pub timestamp: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedemptionQueueView {
+    pub length: U64,
+    pub queued_near: U128,
+    pub available_near: U128,
+}
+
+impl RedemptionQueue {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            items: LookupMap::new(prefix),
+            head: 0,
+            tail: 0,
+            total_queued: 0,
+            total_filled: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    /// NEAR required to fill all queued redemptions.
+    pub fn queued_near(&self) -> Balance {
+        self.total_queued - self.total_filled
+    }
+
+    fn push(
+        &mut self,
+        account: AccountId,
+        usn: Balance,
+        fee: Balance,
+        near: Balance,
+        operation: Option<OperationKey>,
+    ) -> u64 {
+        let id = self.tail;
+        let redemption = Redemption {
+            account,
+            usn,
+            fee,
+            near,
+            queued_before: self.total_queued,
+            operation,
+            timestamp: env::block_timestamp(),
+        };
+        self.items.insert(&id, &redemption);
+        self.tail += 1;
+        self.total_queued += near;
+        id
+    }
+
+    fn front(&self) -> Option<Redemption> {
+        if self.is_empty() {
+            None
+        } else {
+            self.items.get(&self.head)
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some(redemption) = self.items.remove(&self.head) {
+            self.total_filled += redemption.near;
+            self.head += 1;
+        }
+    }
+
+    fn view(&self, id: u64, redemption: Redemption, available_near: Balance) -> RedemptionView {
+        let near_ahead = redemption.queued_before.saturating_sub(self.total_filled);
+        RedemptionView {
+            id: id.into(),
+            account_id: redemption.account,
+            usn: redemption.usn.into(),
+            near: redemption.near.into(),
+            position: (id - self.head).into(),
+            near_to_fill: (near_ahead + redemption.near)
+                .saturating_sub(available_near)
+                .into(),
+            timestamp: redemption.timestamp.into(),
+        }
+    }
+}
+
+impl Contract {
+    /// NEAR which can be paid out keeping the storage staking floor and the collateral of vaults.
+    pub(crate) fn internal_available_near(&self) -> Balance {
+        let storage_floor =
+            env::storage_usage() as Balance * env::storage_byte_cost() + STORAGE_RESERVE_BUFFER;
+        env::account_balance()
+            .saturating_sub(storage_floor)
+            .saturating_sub(self.vaults.total_collateral())
+    }
+
+    /// Checks whether the sell payout should wait in the queue.
+    pub(crate) fn should_queue_redemption(&self, deposit: Balance) -> bool {
+        !self.redemptions.is_empty() || deposit > self.internal_available_near()
+    }
+
+    /// NEAR to pay out for the redemption: the sold USN at the last oracle price,
+    /// but not more than NEAR fixed by the sell.
+    fn internal_redemption_payout(&self, redemption: &Redemption) -> Balance {
+        match &self.oracle.last_report {
+            Some(rate) => {
+                let sell = U256::from(redemption.usn - redemption.fee);
+                let near = sell
+                    * U256::from(10u128.pow(u32::from(rate.decimals() - TOKEN_DECIMAL)))
+                    / rate.multiplier();
+                std::cmp::min(redemption.near, near.as_u128())
+            }
+            None => redemption.near,
+        }
+    }
+
+    /// Queues the redemption of already locked USN.
+    pub(crate) fn internal_queue_redemption(
+        &mut self,
+        account: AccountId,
+        usn: Balance,
+        near: Balance,
+        fee: Balance,
+        operation: Option<OperationKey>,
+    ) -> u64 {
+        let id = self
+            .redemptions
+            .push(account.clone(), usn, fee, near, operation);
+        event::emit::redemption_queued(id, &account, usn, near);
+        id
+    }
+
+    /// Pays out queued redemptions in order while there is enough NEAR and gas.
+    /// Returns the number of filled redemptions.
+    pub(crate) fn internal_fill_redemptions(&mut self, limit: u64) -> u64 {
+        // Reserves are redeemed pro rata after the settlement.
+        if self.status == ContractStatus::Settled {
+            return 0;
+        }
+
+        let mut available = self.internal_available_near();
+        let mut filled = 0;
+
+        while filled < limit && env::prepaid_gas() - env::used_gas() > GAS_FOR_REDEMPTION_FILL {
+            let redemption = match self.redemptions.front() {
+                Some(redemption) => redemption,
+                None => break,
+            };
+
+            let near = self.internal_redemption_payout(&redemption);
+            if near > available {
+                break;
+            }
+
+            let id = self.redemptions.head;
+            self.redemptions.pop();
+            available -= near;
+            filled += 1;
+
+            event::emit::redemption_filled(id, &redemption.account, near);
+
+            Promise::new(redemption.account.clone())
+                .transfer(near)
+                .then(ext_self::handle_sell_payout(
+                    redemption.account,
+                    redemption.usn.into(),
+                    near.into(),
+                    redemption.fee.into(),
+                    redemption.operation,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_RETURN_VALUE_PROMISE,
+                ));
+        }
+
+        filled
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Adds NEAR to the reserves, e.g. from the treasury, and fills queued redemptions.
+    #[payable]
+    pub fn top_up_reserve(&mut self) -> u64 {
+        self.internal_fill_redemptions(MAX_REDEMPTIONS_PER_CALL)
+    }
+
+    /// Fills queued redemptions if there is enough NEAR. Can be called by anyone.
+    /// Returns the number of filled redemptions.
+    pub fn process_redemptions(&mut self, limit: Option<u64>) -> u64 {
+        let limit = std::cmp::min(
+            limit.unwrap_or(MAX_REDEMPTIONS_PER_CALL),
+            MAX_REDEMPTIONS_PER_CALL,
+        );
+        self.internal_fill_redemptions(limit)
+    }
+
+    /// Returns the queued redemption with its position and NEAR required to fill it.
+    pub fn redemption(&self, id: U64) -> Option<RedemptionView> {
+        let available_near = self.internal_available_near();
+        self.redemptions
+            .items
+            .get(&id.0)
+            .map(|redemption| self.redemptions.view(id.0, redemption, available_near))
+    }
+
+    /// Returns queued redemptions in order, starting from the head of the queue.
+    pub fn redemptions(&self, from_index: Option<U64>, limit: Option<U64>) -> Vec<RedemptionView> {
+        let available_near = self.internal_available_near();
+        let from = self.redemptions.head + from_index.map_or(0, |index| index.0);
+        let to = std::cmp::min(
+            from.saturating_add(limit.map_or(MAX_REDEMPTIONS_PER_CALL, |limit| limit.0)),
+            self.redemptions.tail,
+        );
+        (from..to)
+            .filter_map(|id| {
+                self.redemptions
+                    .items
+                    .get(&id)
+                    .map(|redemption| self.redemptions.view(id, redemption, available_near))
+            })
+            .collect()
+    }
+
+    pub fn redemption_queue(&self) -> RedemptionQueueView {
+        RedemptionQueueView {
+            length: (self.redemptions.tail - self.redemptions.head).into(),
+            queued_near: self.redemptions.queued_near().into(),
+            available_near: self.internal_available_near().into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn set_available_near(context: &mut VMContextBuilder, near: Balance) {
+        let storage_floor = context.context.storage_usage as Balance * env::storage_byte_cost()
+            + STORAGE_RESERVE_BUFFER;
+        testing_env!(context.account_balance(storage_floor + near).build());
+    }
+
+    #[test]
+    fn test_redemption_queue() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.token.internal_deposit(&accounts(2), 20 * ONE_NEAR);
+        contract.token.internal_deposit(&accounts(3), 20 * ONE_NEAR);
+
+        // Only the storage staking is left: nothing can be paid out.
+        set_available_near(&mut context, 0);
+        assert!(contract.should_queue_redemption(1));
+
+        contract.internal_lock(&accounts(2), 10 * ONE_NEAR);
+        contract.internal_queue_redemption(accounts(2), 10 * ONE_NEAR, 3 * ONE_NEAR, 0, None);
+        contract.internal_lock(&accounts(3), 10 * ONE_NEAR);
+        contract.internal_queue_redemption(accounts(3), 10 * ONE_NEAR, 2 * ONE_NEAR, 0, None);
+
+        // The queue is not empty, so next sells wait.
+        set_available_near(&mut context, 100 * ONE_NEAR);
+        assert!(contract.should_queue_redemption(1));
+
+        let second = contract.redemption(1.into()).unwrap();
+        assert_eq!(second.position.0, 1);
+        assert_eq!(second.near_to_fill.0, 0);
+
+        // 4 NEAR available: only the 1st redemption gets filled.
+        set_available_near(&mut context, 4 * ONE_NEAR);
+        let second = contract.redemption(1.into()).unwrap();
+        assert_eq!(second.near_to_fill.0, ONE_NEAR);
+
+        assert_eq!(contract.process_redemptions(None), 1);
+        assert!(contract.redemption(0.into()).is_none());
+
+        let queue = contract.redemption_queue();
+        assert_eq!(queue.length.0, 1);
+        assert_eq!(queue.queued_near.0, 2 * ONE_NEAR);
+
+        let redemptions = contract.redemptions(None, None);
+        assert_eq!(redemptions.len(), 1);
+        assert_eq!(redemptions[0].account_id, accounts(3));
+        assert_eq!(redemptions[0].position.0, 0);
+    }
+
+    #[test]
+    fn test_queue_sell() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.token.internal_deposit(&accounts(2), 20 * ONE_NEAR);
+
+        // The oracle callback of the sell, having no NEAR to pay out.
+        set_available_near(&mut context, 0);
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let result = contract.sell_with_price_callback(
+            accounts(2),
+            (10 * ONE_NEAR).into(),
+            None,
+            None,
+            PriceData::test_price_data(111439, 28),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.locked_balance(accounts(2)).0, 10 * ONE_NEAR);
+
+        let redemption = contract.redemption(0.into()).unwrap();
+        assert_eq!(redemption.account_id, accounts(2));
+        assert_eq!(redemption.usn.0, 10 * ONE_NEAR);
+
+        // NEAR price has doubled: the payout is halved.
+        contract.oracle.last_report = Some(ExchangeRate::test_rate(222878, 28));
+        let queued = contract.redemptions.front().unwrap();
+        assert_eq!(
+            contract.internal_redemption_payout(&queued),
+            queued.near / 2
+        );
+
+        // NEAR price has dropped: the payout is still fixed.
+        contract.oracle.last_report = Some(ExchangeRate::test_rate(55719, 28));
+        assert_eq!(contract.internal_redemption_payout(&queued), queued.near);
+    }
+}
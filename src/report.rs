@@ -0,0 +1,310 @@
+//! Proof of reserves.
+//!
+//! The report combines the contract accounting with balances at external contracts:
+//! the staking pool, ref-finance deposits and LP shares, and USDT. External balances
+//! can't be read by a view, so they are refreshed asynchronously and cached with the time of the refresh.
+
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::{PromiseResult, Timestamp};
+use std::collections::HashMap;
+
+use crate::pool::{ext_ft, ext_ref_finance};
+use crate::staking::ext_staking_pool;
+use crate::*;
+
+const GAS_FOR_BALANCE_QUERY: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_HANDLE_REPORT: Gas = Gas(10_000_000_000_000);
+
+/// Balances of the contract at external contracts. `None` if the query has failed.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ReservesSnapshot {
+    ref_deposits: Option<Vec<(AccountId, Balance)>>,
+    /// Shares of every registered stable pool.
+    lp_shares: Vec<(u64, Option<Balance>)>,
+    usdt_balance: Option<Balance>,
+    staked_near: Option<Balance>,
+    updated_at: Timestamp,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReservesSnapshotView {
+    pub ref_deposits: Option<HashMap<AccountId, U128>>,
+    /// Shares of registered stable pools by pool ID.
+    pub lp_shares: HashMap<u64, Option<U128>>,
+    pub usdt_balance: Option<U128>,
+    /// NEAR at the staking pool, including rewards.
+    pub staked_near: Option<U128>,
+    pub updated_at: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleRateView {
+    pub multiplier: U128,
+    pub decimals: u8,
+    pub timestamp: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReservesReport {
+    /// Native balance of the contract account.
+    pub near_balance: U128,
+    /// NEAR reserves backing USN, including staked.
+    pub near_reserve: U128,
+    /// NEAR at the staking pool, including harvested rewards.
+    pub staked_near: U128,
+    pub usdt_reserve: U128,
+    /// Stablecoins of the peg stability module.
+    pub psm_reserves: HashMap<AccountId, U128>,
+    pub usn_supply: U128,
+    /// USN held by the contract itself, e.g. for the stable pool.
+    pub usn_held: U128,
+    /// USN of the savings vault, which is held by the contract account.
+    pub usn_savings: U128,
+    /// The cached oracle rate, which values NEAR reserves.
+    pub oracle_rate: Option<OracleRateView>,
+    pub collateral_ratio: Option<U128>,
+    /// Balances at external contracts as of the last refresh.
+    pub snapshot: Option<ReservesSnapshotView>,
+    pub timestamp: U64,
+}
+
+impl From<&ExchangeRate> for OracleRateView {
+    fn from(rate: &ExchangeRate) -> Self {
+        Self {
+            multiplier: rate.multiplier().into(),
+            decimals: rate.decimals(),
+            timestamp: rate.timestamp().into(),
+        }
+    }
+}
+
+impl From<&ReservesSnapshot> for ReservesSnapshotView {
+    fn from(snapshot: &ReservesSnapshot) -> Self {
+        Self {
+            ref_deposits: snapshot.ref_deposits.as_ref().map(|deposits| {
+                deposits
+                    .iter()
+                    .map(|(token_id, amount)| (token_id.clone(), U128(*amount)))
+                    .collect()
+            }),
+            lp_shares: snapshot
+                .lp_shares
+                .iter()
+                .map(|(pool_id, shares)| (*pool_id, shares.map(U128)))
+                .collect(),
+            usdt_balance: snapshot.usdt_balance.map(U128),
+            staked_near: snapshot.staked_near.map(U128),
+            updated_at: snapshot.updated_at.into(),
+        }
+    }
+}
+
+/// Deserializes the result of the promise, if it has succeeded.
+fn promise_value<T: DeserializeOwned>(index: u64) -> Option<T> {
+    if index >= env::promise_results_count() {
+        return None;
+    }
+    match env::promise_result(index) {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<T>(&value).ok(),
+        _ => None,
+    }
+}
+
+#[ext_contract(ext_report_self)]
+trait ReportHandler {
+    #[private]
+    fn handle_reserves_report(&mut self, pool_ids: Vec<u64>);
+}
+
+pub(crate) trait ReportHandler {
+    fn handle_reserves_report(&mut self, pool_ids: Vec<u64>);
+}
+
+#[near_bindgen]
+impl ReportHandler for Contract {
+    /// Caches balances queried by `refresh_reserves_report`: ref-finance deposits, USDT,
+    /// shares of `pool_ids`, then the staking pool balance.
+    #[private]
+    fn handle_reserves_report(&mut self, pool_ids: Vec<u64>) {
+        let ref_deposits = promise_value::<HashMap<AccountId, U128>>(0).map(|deposits| {
+            let mut deposits = deposits
+                .into_iter()
+                .map(|(token_id, amount)| (token_id, amount.0))
+                .collect::<Vec<_>>();
+            deposits.sort();
+            deposits
+        });
+
+        let lp_shares = pool_ids
+            .iter()
+            .enumerate()
+            .map(|(index, pool_id)| {
+                let shares = promise_value::<U128>(2 + index as u64).map(|shares| shares.0);
+                (*pool_id, shares)
+            })
+            .collect::<Vec<_>>();
+        let staked_index = 2 + pool_ids.len() as u64;
+
+        self.reserves_snapshot = Some(ReservesSnapshot {
+            ref_deposits,
+            lp_shares,
+            usdt_balance: promise_value::<U128>(1).map(|balance| balance.0),
+            staked_near: promise_value::<U128>(staked_index).map(|balance| balance.0),
+            updated_at: env::block_timestamp(),
+        });
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Queries balances of the contract at external contracts for the reserves report.
+    /// Can be called by anyone.
+    pub fn refresh_reserves_report(&mut self) -> Promise {
+        let usn_addr = env::current_account_id();
+        let ref_addr = self.pool_settings.ref_address.clone();
+
+        let mut queries = ext_ref_finance::get_deposits(
+            usn_addr.clone(),
+            ref_addr.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_BALANCE_QUERY,
+        )
+        .and(ext_ft::ft_balance_of(
+            usn_addr.clone(),
+            self.pool_settings.usdt_address.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_BALANCE_QUERY,
+        ));
+
+        let pool_ids = self.stable_pools.keys().collect::<Vec<u64>>();
+        for pool_id in &pool_ids {
+            queries = queries.and(ext_ref_finance::get_pool_shares(
+                *pool_id,
+                usn_addr.clone(),
+                ref_addr.clone(),
+                NO_DEPOSIT,
+                GAS_FOR_BALANCE_QUERY,
+            ));
+        }
+
+        if let Some(pool_id) = self.staking.pool() {
+            queries = queries.and(ext_staking_pool::get_account_total_balance(
+                usn_addr.clone(),
+                pool_id,
+                NO_DEPOSIT,
+                GAS_FOR_BALANCE_QUERY,
+            ));
+        }
+
+        queries.then(ext_report_self::handle_reserves_report(
+            pool_ids,
+            usn_addr,
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_REPORT,
+        ))
+    }
+
+    /// Returns the full report of reserves backing USN.
+    pub fn reserves_report(&self) -> ReservesReport {
+        let usn_addr = env::current_account_id();
+
+        ReservesReport {
+            near_balance: env::account_balance().into(),
+            near_reserve: self.treasury.reserve().into(),
+            staked_near: self.staking.total().into(),
+            usdt_reserve: self.reserves.usdt.into(),
+            psm_reserves: self
+                .psm
+                .iter()
+                .map(|(asset_id, asset)| (asset_id, asset.reserve.into()))
+                .collect(),
+            usn_supply: self.token.total_supply.into(),
+            usn_held: self.token.internal_unwrap_balance_of(&usn_addr).into(),
+            usn_savings: self.savings.assets().into(),
+            oracle_rate: self.oracle.last_report.as_ref().map(|rate| rate.into()),
+            collateral_ratio: self.collateral_ratio(),
+            snapshot: self
+                .reserves_snapshot
+                .as_ref()
+                .map(|snapshot| snapshot.into()),
+            timestamp: env::block_timestamp().into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn json_result(value: &str) -> PromiseResult {
+        PromiseResult::Successful(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_reserves_report() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.account_balance(150 * ONE_NEAR).build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+        contract.finish_buy(
+            accounts(2),
+            accounts(2),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+        contract.token.internal_deposit(&accounts(0), 1000);
+
+        let report = contract.reserves_report();
+        assert_eq!(report.near_balance.0, 150 * ONE_NEAR);
+        assert_eq!(report.near_reserve.0, 100 * ONE_NEAR);
+        assert_eq!(report.usn_supply.0, 1114390000000000000000 + 1000);
+        assert_eq!(report.usn_held.0, 1000);
+        assert_eq!(report.oracle_rate.unwrap().multiplier.0, 111439);
+        assert!(report.snapshot.is_none());
+
+        // The staking pool isn't set: 4 queries, a LP shares query has failed.
+        testing_env!(
+            context
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(1000)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![
+                json_result(r#"{"usdt.test.near": "5000000", "charlie": "7"}"#),
+                json_result(r#""1000000000""#),
+                json_result(r#""2000""#),
+                PromiseResult::Failed,
+            ],
+        );
+        contract.handle_reserves_report(vec![0, 7]);
+
+        let snapshot = contract.reserves_report().snapshot.unwrap();
+        let deposits = snapshot.ref_deposits.unwrap();
+        assert_eq!(deposits.len(), 2);
+        assert_eq!(deposits[&"usdt.test.near".parse().unwrap()].0, 5000000);
+        assert_eq!(snapshot.lp_shares.len(), 2);
+        assert_eq!(snapshot.lp_shares[&0].unwrap().0, 2000);
+        assert!(snapshot.lp_shares[&7].is_none());
+        assert_eq!(snapshot.usdt_balance.unwrap().0, 1000000000);
+        assert!(snapshot.staked_near.is_none());
+        assert_eq!(snapshot.updated_at.0, 1000);
+    }
+}
@@ -0,0 +1,690 @@
+//! Composition of reserves backing USN.
+//!
+//! Part of NEAR reserves can be swapped into USDT on a ref-finance pool,
+//! so the backing doesn't fall 1:1 with NEAR. A swap goes through the deposit at ref-finance:
+//! NEAR is wrapped as wNEAR, deposited and swapped, then the result is withdrawn and unwrapped.
+//! Every step is confirmed by a callback, and reserves are credited only when they are back
+//! in the contract. If the swap fails, the input is brought back from wherever it has stopped.
+//! Tokens left at ref-finance or as wNEAR after a failed step are recovered with `recover_reserves`.
+
+use near_sdk::{require, PromiseResult, ONE_YOCTO};
+
+use crate::pool::{
+    ext_ft, ext_ref_finance, extend_decimals, gas_for_ref_withdraw, ref_withdrawn_amounts,
+    SwapAction, CONFIG, USDT_DECIMALS,
+};
+use crate::*;
+
+const GAS_FOR_WRAP: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_DEPOSIT: Gas = Gas(35_000_000_000_000);
+const GAS_FOR_SWAP: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_WITHDRAW: Gas = gas_for_ref_withdraw(1);
+const GAS_FOR_UNWRAP: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_HANDLE_UNWRAP: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_HANDLE_WITHDRAW: Gas =
+    Gas(GAS_FOR_UNWRAP.0 + GAS_FOR_HANDLE_UNWRAP.0 + 5_000_000_000_000);
+const GAS_FOR_HANDLE_SWAP: Gas =
+    Gas(GAS_FOR_WITHDRAW.0 + GAS_FOR_HANDLE_WITHDRAW.0 + 5_000_000_000_000);
+const GAS_FOR_HANDLE_DEPOSIT: Gas =
+    Gas(GAS_FOR_SWAP.0 + GAS_FOR_HANDLE_SWAP.0 + 10_000_000_000_000);
+const GAS_FOR_HANDLE_WRAP: Gas =
+    Gas(GAS_FOR_DEPOSIT.0 + GAS_FOR_HANDLE_DEPOSIT.0 + 5_000_000_000_000);
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SwapDirection {
+    NearToUsdt,
+    UsdtToNear,
+}
+
+impl SwapDirection {
+    /// Input and output tokens.
+    fn tokens(&self, wnear: AccountId, usdt: AccountId) -> (AccountId, AccountId) {
+        match self {
+            SwapDirection::NearToUsdt => (wnear, usdt),
+            SwapDirection::UsdtToNear => (usdt, wnear),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Reserves {
+    /// ref-finance pool of wNEAR and USDT.
+    swap_pool_id: Option<u64>,
+    /// USDT reserves.
+    pub usdt: Balance,
+    wnear_address: AccountId,
+    /// wNEAR at the ref-finance deposit after a failed withdrawal.
+    ref_wnear: Balance,
+    /// USDT at the ref-finance deposit after a failed withdrawal.
+    ref_usdt: Balance,
+    /// wNEAR of the contract after a failed unwrapping.
+    wnear: Balance,
+}
+
+impl Default for Reserves {
+    fn default() -> Self {
+        Self {
+            swap_pool_id: None,
+            usdt: 0,
+            wnear_address: CONFIG.wnear_address.parse().unwrap(),
+            ref_wnear: 0,
+            ref_usdt: 0,
+            wnear: 0,
+        }
+    }
+}
+
+impl Reserves {
+    /// Checks whether there are USDT reserves, including USDT left at ref-finance.
+    pub fn has_usdt(&self) -> bool {
+        self.usdt > 0 || self.ref_usdt > 0
+    }
+
+    /// Checks whether tokens are left at the ref-finance deposit.
+    pub fn has_ref_deposits(&self) -> bool {
+        self.ref_wnear > 0 || self.ref_usdt > 0
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReservesView {
+    pub swap_pool_id: Option<u64>,
+    pub wnear_address: AccountId,
+    /// NEAR reserves, including staked.
+    pub near: U128,
+    pub staked_near: U128,
+    pub usdt: U128,
+    /// USN value of NEAR reserves at the last oracle price.
+    pub near_value: Option<U128>,
+    pub usdt_value: U128,
+    /// Reserves to recover after failed steps of swaps: wNEAR and USDT at ref-finance, and wNEAR.
+    pub ref_wnear: U128,
+    pub ref_usdt: U128,
+    pub wnear: U128,
+}
+
+#[ext_contract(ext_wnear)]
+trait WrappedNear {
+    #[payable]
+    fn near_deposit(&mut self);
+
+    #[payable]
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+#[ext_contract(ext_reserves_self)]
+trait ReservesHandler {
+    #[private]
+    fn handle_reserve_wrap(&mut self, amount: U128, min_amount_out: U128) -> PromiseOrValue<U128>;
+
+    #[private]
+    fn handle_reserve_deposit(
+        &mut self,
+        direction: SwapDirection,
+        amount_in: U128,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<U128>;
+
+    #[private]
+    fn handle_reserve_swap(&mut self, direction: SwapDirection, amount_in: U128) -> U128;
+
+    #[private]
+    fn handle_reserve_withdraw(&mut self, token_id: AccountId, amount: U128);
+
+    #[private]
+    fn handle_reserve_unwrap(&mut self, amount: U128);
+}
+
+pub(crate) trait ReservesHandler {
+    fn handle_reserve_wrap(&mut self, amount: U128, min_amount_out: U128) -> PromiseOrValue<U128>;
+
+    fn handle_reserve_deposit(
+        &mut self,
+        direction: SwapDirection,
+        amount_in: U128,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn handle_reserve_swap(&mut self, direction: SwapDirection, amount_in: U128) -> U128;
+
+    fn handle_reserve_withdraw(&mut self, token_id: AccountId, amount: U128);
+
+    fn handle_reserve_unwrap(&mut self, amount: U128);
+}
+
+#[near_bindgen]
+impl ReservesHandler for Contract {
+    /// Deposits wNEAR to ref-finance, or takes back NEAR, which hasn't been wrapped.
+    #[private]
+    fn handle_reserve_wrap(&mut self, amount: U128, min_amount_out: U128) -> PromiseOrValue<U128> {
+        if !is_promise_success() {
+            // The attached NEAR has been refunded.
+            self.treasury.add_reserve(amount.0);
+            return PromiseOrValue::Value(0.into());
+        }
+
+        self.internal_deposit_and_swap(SwapDirection::NearToUsdt, amount.0, min_amount_out.0)
+            .into()
+    }
+
+    /// Swaps the input deposited to ref-finance. Otherwise, brings the input back:
+    /// refunded tokens are in the contract account, the rest is at ref-finance.
+    #[private]
+    fn handle_reserve_deposit(
+        &mut self,
+        direction: SwapDirection,
+        amount_in: U128,
+        min_amount_out: U128,
+    ) -> PromiseOrValue<U128> {
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map_or(0, |used| std::cmp::min(used.0, amount_in.0)),
+            _ => 0,
+        };
+        let (token_in, token_out) = self.swap_tokens(direction);
+
+        if used < amount_in.0 {
+            let refunded = amount_in.0 - used;
+            match direction {
+                SwapDirection::NearToUsdt => {
+                    self.internal_unwrap(refunded);
+                }
+                SwapDirection::UsdtToNear => self.reserves.usdt += refunded,
+            }
+            if used > 0 {
+                self.internal_withdraw_from_ref(token_in, used);
+            }
+            return PromiseOrValue::Value(0.into());
+        }
+
+        let action = SwapAction {
+            pool_id: self.swap_pool_id(),
+            token_in,
+            amount_in: Some(amount_in),
+            token_out,
+            min_amount_out,
+        };
+
+        ext_ref_finance::swap(
+            vec![action],
+            None,
+            self.pool_settings.ref_address.clone(),
+            ONE_YOCTO,
+            GAS_FOR_SWAP,
+        )
+        .then(ext_reserves_self::handle_reserve_swap(
+            direction,
+            amount_in,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_SWAP,
+        ))
+        .into()
+    }
+
+    /// Withdraws the swap result from ref-finance, or the input if the swap has failed.
+    /// Returns the output amount, 0 if the swap has failed.
+    #[private]
+    fn handle_reserve_swap(&mut self, direction: SwapDirection, amount_in: U128) -> U128 {
+        let amount_out = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map_or(0, |amount_out| amount_out.0),
+            _ => 0,
+        };
+        let (token_in, token_out) = self.swap_tokens(direction);
+
+        if amount_out == 0 {
+            // The input is still at the ref-finance deposit.
+            self.internal_withdraw_from_ref(token_in, amount_in.0);
+            return 0.into();
+        }
+
+        self.internal_withdraw_from_ref(token_out, amount_out);
+        amount_out.into()
+    }
+
+    /// Credits withdrawn USDT, or unwraps withdrawn wNEAR.
+    /// Tokens, which haven't left the ref-finance deposit, stay there until recovered.
+    #[private]
+    fn handle_reserve_withdraw(&mut self, token_id: AccountId, amount: U128) {
+        let is_wnear = token_id == self.reserves.wnear_address;
+        let withdrawn = std::cmp::min(ref_withdrawn_amounts(1)[0], amount.0);
+
+        let left = amount.0 - withdrawn;
+        if left > 0 {
+            if is_wnear {
+                self.reserves.ref_wnear += left;
+            } else {
+                self.reserves.ref_usdt += left;
+            }
+        }
+        if withdrawn == 0 {
+            return;
+        }
+
+        if is_wnear {
+            self.internal_unwrap(withdrawn);
+        } else {
+            self.reserves.usdt += withdrawn;
+        }
+    }
+
+    /// Credits unwrapped NEAR. If unwrapping has failed, wNEAR stays in the contract until recovered.
+    #[private]
+    fn handle_reserve_unwrap(&mut self, amount: U128) {
+        if is_promise_success() {
+            self.treasury.add_reserve(amount.0);
+        } else {
+            self.reserves.wnear += amount.0;
+        }
+    }
+}
+
+impl Contract {
+    /// USN value of NEAR reserves, if there is a cached oracle price.
+    pub(crate) fn near_reserve_value(&self) -> Option<Balance> {
+        let rate = self.oracle.last_report.as_ref()?;
+
+        // NEAR -> USN, the same way as `buy` does.
+        let value = U256::from(self.treasury.reserve()) * U256::from(rate.multiplier())
+            / U256::from(10u128.pow(u32::from(rate.decimals() - TOKEN_DECIMAL)));
+        Some(value.as_u128())
+    }
+
+    /// USN value of USDT reserves at par.
+    pub(crate) fn usdt_reserve_value(&self) -> Balance {
+        extend_decimals(self.reserves.usdt, TOKEN_DECIMAL - USDT_DECIMALS)
+    }
+
+    fn swap_pool_id(&self) -> u64 {
+        self.reserves
+            .swap_pool_id
+            .unwrap_or_else(|| env::panic_str("The swap pool is not set"))
+    }
+
+    fn swap_tokens(&self, direction: SwapDirection) -> (AccountId, AccountId) {
+        direction.tokens(
+            self.reserves.wnear_address.clone(),
+            self.pool_settings.usdt_address.clone(),
+        )
+    }
+
+    /// Deposits the input token to ref-finance, then swaps it.
+    fn internal_deposit_and_swap(
+        &self,
+        direction: SwapDirection,
+        amount_in: Balance,
+        min_amount_out: Balance,
+    ) -> Promise {
+        let (token_in, _) = self.swap_tokens(direction);
+
+        ext_ft::ft_transfer_call(
+            self.pool_settings.ref_address.clone(),
+            amount_in.into(),
+            None,
+            "".to_string(), // Empty message == deposit action on the ref-finance.
+            token_in,
+            ONE_YOCTO,
+            GAS_FOR_DEPOSIT,
+        )
+        .then(ext_reserves_self::handle_reserve_deposit(
+            direction,
+            amount_in.into(),
+            min_amount_out.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_DEPOSIT,
+        ))
+    }
+
+    /// Withdraws the token deposit from ref-finance.
+    fn internal_withdraw_from_ref(&self, token_id: AccountId, amount: Balance) -> Promise {
+        self.internal_ref_withdraw(vec![(token_id.clone(), amount.into())])
+            .then(ext_reserves_self::handle_reserve_withdraw(
+                token_id,
+                amount.into(),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_WITHDRAW,
+            ))
+    }
+
+    /// Unwraps wNEAR of the contract.
+    fn internal_unwrap(&self, amount: Balance) -> Promise {
+        ext_wnear::near_withdraw(
+            amount.into(),
+            self.reserves.wnear_address.clone(),
+            ONE_YOCTO,
+            GAS_FOR_UNWRAP,
+        )
+        .then(ext_reserves_self::handle_reserve_unwrap(
+            amount.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_UNWRAP,
+        ))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the ref-finance pool of wNEAR and USDT for reserve swaps. `None` disables swaps.
+    pub fn set_reserve_swap_pool(&mut self, pool_id: Option<u64>) {
+        self.assert_owner();
+        self.reserves.swap_pool_id = pool_id;
+    }
+
+    /// Sets the wNEAR contract, which wraps NEAR reserves for swaps.
+    pub fn set_wnear_address(&mut self, wnear_address: AccountId) {
+        self.assert_owner();
+        require!(
+            self.reserves.ref_wnear == 0 && self.reserves.wnear == 0,
+            "wNEAR reserves must be recovered before changing wNEAR"
+        );
+        self.reserves.wnear_address = wnear_address;
+    }
+
+    /// Swaps NEAR reserves into USDT, getting at least `min_amount_out` USDT.
+    pub fn swap_near_to_usdt(&mut self, amount: U128, min_amount_out: U128) -> Promise {
+        self.assert_owner_or_guardian();
+        self.abort_if_settled();
+
+        require!(amount.0 > 0, "The amount should be a positive number");
+        require!(
+            min_amount_out.0 > 0,
+            "The minimum amount out should be a positive number"
+        );
+        require!(
+            amount.0
+                <= self
+                    .treasury
+                    .reserve()
+                    .saturating_sub(self.staking.staked_reserve()),
+            "Not enough liquid NEAR reserves"
+        );
+        self.assert_liquid_floor(amount.0);
+        require!(
+            self.reserves.swap_pool_id.is_some(),
+            "The swap pool is not set"
+        );
+
+        self.treasury.take_reserve(amount.0);
+
+        ext_wnear::near_deposit(self.reserves.wnear_address.clone(), amount.0, GAS_FOR_WRAP).then(
+            ext_reserves_self::handle_reserve_wrap(
+                amount,
+                min_amount_out,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_WRAP,
+            ),
+        )
+    }
+
+    /// Swaps USDT reserves back into NEAR, getting at least `min_amount_out` NEAR.
+    pub fn swap_usdt_to_near(&mut self, amount: U128, min_amount_out: U128) -> Promise {
+        self.assert_owner_or_guardian();
+        self.abort_if_settled();
+
+        require!(amount.0 > 0, "The amount should be a positive number");
+        require!(
+            min_amount_out.0 > 0,
+            "The minimum amount out should be a positive number"
+        );
+        require!(amount.0 <= self.reserves.usdt, "Not enough USDT reserves");
+        require!(
+            self.reserves.swap_pool_id.is_some(),
+            "The swap pool is not set"
+        );
+
+        self.reserves.usdt -= amount.0;
+
+        self.internal_deposit_and_swap(SwapDirection::UsdtToNear, amount.0, min_amount_out.0)
+    }
+
+    /// Withdraws and unwraps reserves left at ref-finance or as wNEAR after failed steps of swaps.
+    pub fn recover_reserves(&mut self) {
+        self.assert_owner_or_guardian();
+
+        let ref_wnear = std::mem::take(&mut self.reserves.ref_wnear);
+        if ref_wnear > 0 {
+            self.internal_withdraw_from_ref(self.reserves.wnear_address.clone(), ref_wnear);
+        }
+
+        let ref_usdt = std::mem::take(&mut self.reserves.ref_usdt);
+        if ref_usdt > 0 {
+            self.internal_withdraw_from_ref(self.pool_settings.usdt_address.clone(), ref_usdt);
+        }
+
+        let wnear = std::mem::take(&mut self.reserves.wnear);
+        if wnear > 0 {
+            self.internal_unwrap(wnear);
+        }
+    }
+
+    pub fn reserves(&self) -> ReservesView {
+        ReservesView {
+            swap_pool_id: self.reserves.swap_pool_id,
+            wnear_address: self.reserves.wnear_address.clone(),
+            near: self.treasury.reserve().into(),
+            staked_near: self.staking.staked_reserve().into(),
+            usdt: self.reserves.usdt.into(),
+            near_value: self.near_reserve_value().map(U128),
+            usdt_value: self.usdt_reserve_value().into(),
+            ref_wnear: self.reserves.ref_wnear.into(),
+            ref_usdt: self.reserves.ref_usdt.into(),
+            wnear: self.reserves.wnear.into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn set_promise_result(context: &mut VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![result],
+        );
+    }
+
+    fn swap_result(amount_out: Balance) -> PromiseResult {
+        PromiseResult::Successful(format!("\"{}\"", amount_out).into_bytes())
+    }
+
+    fn withdraw_result(withdrawn: Balance) -> PromiseResult {
+        PromiseResult::Successful(format!("[\"{}\"]", withdrawn).into_bytes())
+    }
+
+    /// 100 NEAR of reserves, all of them are liquid.
+    fn reserves_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.account_balance(1000 * ONE_NEAR).build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+        contract.finish_buy(
+            accounts(2),
+            accounts(2),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+        contract.set_reserve_swap_pool(Some(7));
+        contract
+    }
+
+    fn usdt() -> AccountId {
+        "usdt.test.near".parse().unwrap()
+    }
+
+    fn wnear() -> AccountId {
+        "wrap.test.near".parse().unwrap()
+    }
+
+    #[test]
+    fn test_swap_near_to_usdt() {
+        let mut context = get_context(accounts(1));
+        let mut contract = reserves_contract(&mut context);
+
+        contract.swap_near_to_usdt((10 * ONE_NEAR).into(), 100_000_000.into());
+        assert_eq!(contract.reserves().near.0, 90 * ONE_NEAR);
+
+        set_promise_result(&mut context, swap_result(10 * ONE_NEAR));
+        contract.handle_reserve_deposit(
+            SwapDirection::NearToUsdt,
+            (10 * ONE_NEAR).into(),
+            100_000_000.into(),
+        );
+
+        set_promise_result(&mut context, swap_result(111_000_000));
+        let amount_out =
+            contract.handle_reserve_swap(SwapDirection::NearToUsdt, (10 * ONE_NEAR).into());
+        assert_eq!(amount_out.0, 111_000_000);
+
+        // USDT is credited after the withdrawal only.
+        assert_eq!(contract.reserves().usdt.0, 0);
+        set_promise_result(&mut context, withdraw_result(111_000_000));
+        contract.handle_reserve_withdraw(usdt(), 111_000_000.into());
+
+        let reserves = contract.reserves();
+        assert_eq!(reserves.near.0, 90 * ONE_NEAR);
+        assert_eq!(reserves.usdt.0, 111_000_000);
+        assert_eq!(reserves.near_value.unwrap().0, 1002951000000000000000);
+        assert_eq!(reserves.usdt_value.0, 111 * 10u128.pow(18));
+
+        // USDT reserves are collateral at par.
+        assert_eq!(contract.collateral_ratio().unwrap().0, 999_606);
+    }
+
+    #[test]
+    fn test_swap_usdt_to_near() {
+        let mut context = get_context(accounts(1));
+        let mut contract = reserves_contract(&mut context);
+        contract.reserves.usdt = 111_000_000;
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.swap_usdt_to_near(111_000_000.into(), (9 * ONE_NEAR).into());
+        assert_eq!(contract.reserves().usdt.0, 0);
+
+        set_promise_result(&mut context, swap_result(99 * ONE_NEAR / 10));
+        contract.handle_reserve_swap(SwapDirection::UsdtToNear, 111_000_000.into());
+
+        // NEAR is credited after the withdrawal and unwrapping.
+        set_promise_result(&mut context, withdraw_result(99 * ONE_NEAR / 10));
+        contract.handle_reserve_withdraw(wnear(), (99 * ONE_NEAR / 10).into());
+        assert_eq!(contract.reserves().near.0, 100 * ONE_NEAR);
+        contract.handle_reserve_unwrap((99 * ONE_NEAR / 10).into());
+
+        let reserves = contract.reserves();
+        assert_eq!(reserves.near.0, 1099 * ONE_NEAR / 10);
+        assert_eq!(reserves.usdt.0, 0);
+    }
+
+    #[test]
+    fn test_failed_swap() {
+        let mut context = get_context(accounts(1));
+        let mut contract = reserves_contract(&mut context);
+
+        contract.swap_near_to_usdt((10 * ONE_NEAR).into(), 200_000_000.into());
+
+        // The minimum amount out hasn't been reached.
+        set_promise_result(&mut context, PromiseResult::Failed);
+        let amount_out =
+            contract.handle_reserve_swap(SwapDirection::NearToUsdt, (10 * ONE_NEAR).into());
+        assert_eq!(amount_out.0, 0);
+        assert_eq!(contract.reserves().near.0, 90 * ONE_NEAR);
+
+        // The input can't be withdrawn from ref-finance.
+        contract.handle_reserve_withdraw(wnear(), (10 * ONE_NEAR).into());
+        assert_eq!(contract.reserves().ref_wnear.0, 10 * ONE_NEAR);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.recover_reserves();
+        assert_eq!(contract.reserves().ref_wnear.0, 0);
+
+        set_promise_result(&mut context, withdraw_result(10 * ONE_NEAR));
+        contract.handle_reserve_withdraw(wnear(), (10 * ONE_NEAR).into());
+        contract.handle_reserve_unwrap((10 * ONE_NEAR).into());
+
+        let reserves = contract.reserves();
+        assert_eq!(reserves.near.0, 100 * ONE_NEAR);
+        assert_eq!(reserves.usdt.0, 0);
+    }
+
+    #[test]
+    fn test_failed_withdrawal_transfer() {
+        let mut context = get_context(accounts(1));
+        let mut contract = reserves_contract(&mut context);
+
+        // ref-finance has restored the deposit after the failed transfer, or a part of it.
+        set_promise_result(&mut context, withdraw_result(0));
+        contract.handle_reserve_withdraw(usdt(), 111_000_000.into());
+        set_promise_result(&mut context, withdraw_result(4 * ONE_NEAR));
+        contract.handle_reserve_withdraw(wnear(), (10 * ONE_NEAR).into());
+
+        let reserves = contract.reserves();
+        assert_eq!(reserves.usdt.0, 0);
+        assert_eq!(reserves.ref_usdt.0, 111_000_000);
+        assert_eq!(reserves.ref_wnear.0, 6 * ONE_NEAR);
+        assert_eq!(reserves.wnear.0, 0);
+    }
+
+    #[test]
+    fn test_failed_wrap_and_deposit() {
+        let mut context = get_context(accounts(1));
+        let mut contract = reserves_contract(&mut context);
+        contract.reserves.usdt = 111_000_000;
+
+        // NEAR hasn't been wrapped.
+        contract.swap_near_to_usdt((10 * ONE_NEAR).into(), 100_000_000.into());
+        set_promise_result(&mut context, PromiseResult::Failed);
+        contract.handle_reserve_wrap((10 * ONE_NEAR).into(), 100_000_000.into());
+        assert_eq!(contract.reserves().near.0, 100 * ONE_NEAR);
+
+        // USDT hasn't reached ref-finance.
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.swap_usdt_to_near(111_000_000.into(), (9 * ONE_NEAR).into());
+        set_promise_result(&mut context, swap_result(0));
+        let result = contract.handle_reserve_deposit(
+            SwapDirection::UsdtToNear,
+            111_000_000.into(),
+            (9 * ONE_NEAR).into(),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.reserves().usdt.0, 111_000_000);
+
+        // wNEAR hasn't been unwrapped.
+        set_promise_result(&mut context, PromiseResult::Failed);
+        contract.handle_reserve_unwrap(ONE_NEAR.into());
+        assert_eq!(contract.reserves().wnear.0, ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough USDT reserves")]
+    fn test_swap_usdt_over_reserves() {
+        let mut context = get_context(accounts(1));
+        let mut contract = reserves_contract(&mut context);
+
+        contract.swap_usdt_to_near(1.into(), 1.into());
+    }
+}
@@ -0,0 +1,519 @@
+//! Savings vault.
+//!
+//! Holders deposit USN and get shares of the vault, ERC-4626 style. The share of the spread revenue
+//! set by the owner is streamed into the vault: it's minted to the vault evenly over the stream period,
+//! so the USN value of every share grows over time. The NEAR value of the streamed revenue moves to the reserves.
+//! Deposited USN is held at the contract account. The vault has virtual shares and assets, and shares have
+//! more decimals than USN, so inflating the share price with streamed revenue doesn't round deposits down.
+
+use near_sdk::collections::LookupMap;
+use near_sdk::{require, IntoStorageKey, Timestamp};
+
+use crate::supply::MintSource;
+use crate::*;
+
+/// The revenue share has 6 decimals: 1_000_000 is 100%.
+const REVENUE_SHARE_DECIMAL: u8 = 6;
+/// Shares have 6 more decimals than USN: the vault has 10^6 virtual shares of 1 virtual yoctoUSN.
+const SHARES_DECIMALS_OFFSET: u8 = 6;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Savings {
+    /// The share of the spread revenue streamed to the vault.
+    revenue_share: u128,
+    /// Seconds, during which the collected revenue is streamed.
+    stream_period: u64,
+    /// USN of the vault.
+    assets: Balance,
+    total_shares: Balance,
+    /// Collected revenue, which isn't streamed yet.
+    pending: Balance,
+    streamed_at: Timestamp,
+    shares: LookupMap<AccountId, Balance>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SavingsView {
+    pub revenue_share: U128,
+    pub stream_period: U64,
+    /// USN of the vault including the streamed revenue.
+    pub total_assets: U128,
+    pub total_shares: U128,
+    /// Revenue to be streamed.
+    pub pending: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SavingsAccountView {
+    pub shares: U128,
+    pub assets: U128,
+}
+
+impl Savings {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            revenue_share: 0,
+            stream_period: 0,
+            assets: 0,
+            total_shares: 0,
+            pending: 0,
+            streamed_at: 0,
+            shares: LookupMap::new(prefix),
+        }
+    }
+
+    /// USN held by the contract account for the vault.
+    pub fn assets(&self) -> Balance {
+        self.assets
+    }
+
+    /// Pending revenue, which is streamed by now.
+    fn streamable(&self) -> Balance {
+        let period = self.stream_period as u128 * 1_000_000_000;
+        let elapsed = env::block_timestamp().saturating_sub(self.streamed_at) as u128;
+        if elapsed >= period {
+            return self.pending;
+        }
+        (U256::from(self.pending) * U256::from(elapsed) / U256::from(period)).as_u128()
+    }
+
+    fn shares_of(&self, account_id: &AccountId) -> Balance {
+        self.shares.get(account_id).unwrap_or(0)
+    }
+}
+
+/// Calculates `value * numerator / denominator` rounding up or down.
+fn convert(value: Balance, numerator: Balance, denominator: Balance, round_up: bool) -> Balance {
+    if denominator == 0 {
+        return value;
+    }
+    let product = U256::from(value) * U256::from(numerator);
+    let mut result = product / U256::from(denominator);
+    if round_up && result * U256::from(denominator) < product {
+        result += U256::one();
+    }
+    result.as_u128()
+}
+
+impl Contract {
+    /// Queues the savings share of the spread revenue: `fee` USN worth `fee_near`.
+    pub(crate) fn internal_share_revenue(&mut self, fee: Balance, fee_near: Balance) {
+        if self.savings.revenue_share == 0 || self.savings.total_shares == 0 {
+            return;
+        }
+
+        let denominator = 10u128.pow(REVENUE_SHARE_DECIMAL as u32);
+        let usn = convert(fee, self.savings.revenue_share, denominator, false);
+        if usn == 0 {
+            return;
+        }
+
+        self.internal_stream_savings();
+        self.treasury.record_savings_revenue(convert(
+            fee_near,
+            self.savings.revenue_share,
+            denominator,
+            false,
+        ));
+        self.savings.pending += usn;
+    }
+
+    /// Mints the streamed revenue to the vault within the supply caps.
+    fn internal_stream_savings(&mut self) {
+        if self.status == ContractStatus::Settled {
+            return;
+        }
+
+        let mut amount = self.savings.streamable();
+        if let Some(headroom) = self.mint_headroom(MintSource::Savings) {
+            amount = std::cmp::min(amount, headroom);
+        }
+        self.savings.streamed_at = env::block_timestamp();
+
+        if amount > 0 {
+            self.savings.pending -= amount;
+            self.savings.assets += amount;
+            self.internal_mint(
+                &env::current_account_id(),
+                amount,
+                MintSource::Savings,
+                Some("Savings revenue"),
+            );
+        }
+    }
+
+    /// USN of the vault including the revenue streamed by now.
+    fn savings_total_assets(&self) -> Balance {
+        let mut streamable = self.savings.streamable();
+        if let Some(headroom) = self.mint_headroom(MintSource::Savings) {
+            streamable = std::cmp::min(streamable, headroom);
+        }
+        self.savings.assets + streamable
+    }
+
+    /// USN of the vault backing shares. USN of the vault without shares is swept by the next deposit.
+    fn savings_backing_assets(&self) -> Balance {
+        if self.savings.total_shares == 0 {
+            return 0;
+        }
+        self.savings_total_assets()
+    }
+
+    fn savings_shares_for(&self, assets: Balance, round_up: bool) -> Balance {
+        convert(
+            assets,
+            self.savings.total_shares + 10u128.pow(SHARES_DECIMALS_OFFSET as u32),
+            self.savings_backing_assets() + 1,
+            round_up,
+        )
+    }
+
+    fn savings_assets_for(&self, shares: Balance, round_up: bool) -> Balance {
+        convert(
+            shares,
+            self.savings_backing_assets() + 1,
+            self.savings.total_shares + 10u128.pow(SHARES_DECIMALS_OFFSET as u32),
+            round_up,
+        )
+    }
+
+    /// USN left in the vault without shares, e.g. rounding dust or revenue streamed after the last withdrawal,
+    /// belongs to nobody: it's swept to the contract, so it doesn't go to the next depositor.
+    fn internal_sweep_savings(&mut self) {
+        if self.savings.total_shares == 0 && self.savings.assets > 0 {
+            self.savings.assets = 0;
+        }
+    }
+
+    fn internal_savings_exit(&mut self, account: &AccountId, assets: Balance, shares: Balance) {
+        require!(
+            self.status != ContractStatus::Paused,
+            "The contract is under maintenance"
+        );
+        require!(assets > 0 && shares > 0, "The amount should be positive");
+
+        let balance = self
+            .savings
+            .shares_of(account)
+            .checked_sub(shares)
+            .unwrap_or_else(|| env::panic_str("Not enough savings shares"));
+
+        if balance > 0 {
+            self.savings.shares.insert(account, &balance);
+        } else {
+            self.savings.shares.remove(account);
+        }
+        self.savings.total_shares -= shares;
+        self.savings.assets -= assets;
+
+        self.token.internal_transfer(
+            &env::current_account_id(),
+            account,
+            assets,
+            Some("Savings withdrawal".to_string()),
+        );
+        event::emit::savings_withdraw(account, assets, shares);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Deposits USN to the savings vault. Returns the minted shares.
+    #[payable]
+    pub fn savings_deposit(&mut self, amount: U128) -> U128 {
+        assert_one_yocto();
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        self.internal_stream_savings();
+        self.internal_sweep_savings();
+
+        let account = env::predecessor_account_id();
+        let shares = self.savings_shares_for(amount.0, false);
+        require!(shares > 0, "Not enough USN to get a share");
+
+        self.token.internal_transfer(
+            &account,
+            &env::current_account_id(),
+            amount.0,
+            Some("Savings deposit".to_string()),
+        );
+
+        let balance = self.savings.shares_of(&account) + shares;
+        self.savings.shares.insert(&account, &balance);
+        self.savings.total_shares += shares;
+        self.savings.assets += amount.0;
+
+        event::emit::savings_deposit(&account, amount.0, shares);
+        shares.into()
+    }
+
+    /// Withdraws USN from the savings vault. Returns the burned shares.
+    #[payable]
+    pub fn savings_withdraw(&mut self, amount: U128) -> U128 {
+        assert_one_yocto();
+        self.internal_stream_savings();
+
+        let shares = self.savings_shares_for(amount.0, true);
+        self.internal_savings_exit(&env::predecessor_account_id(), amount.0, shares);
+        shares.into()
+    }
+
+    /// Burns shares of the savings vault for USN. Returns the withdrawn USN.
+    #[payable]
+    pub fn savings_redeem(&mut self, shares: U128) -> U128 {
+        assert_one_yocto();
+        self.internal_stream_savings();
+
+        let assets = self.savings_assets_for(shares.0, false);
+        self.internal_savings_exit(&env::predecessor_account_id(), assets, shares.0);
+        assets.into()
+    }
+
+    /// Sets the share of the spread revenue streamed to the savings vault,
+    /// and the period in seconds, during which it's streamed.
+    pub fn set_savings_policy(&mut self, revenue_share: U128, stream_period: U64) {
+        self.assert_owner();
+        require!(
+            revenue_share.0 <= 10u128.pow(REVENUE_SHARE_DECIMAL as u32),
+            "The revenue share should be at most 100%"
+        );
+
+        self.internal_stream_savings();
+        self.savings.revenue_share = revenue_share.0;
+        self.savings.stream_period = stream_period.0;
+    }
+
+    pub fn savings(&self) -> SavingsView {
+        let total_assets = self.savings_total_assets();
+        SavingsView {
+            revenue_share: self.savings.revenue_share.into(),
+            stream_period: self.savings.stream_period.into(),
+            total_assets: total_assets.into(),
+            total_shares: self.savings.total_shares.into(),
+            pending: (self.savings.pending + self.savings.assets - total_assets).into(),
+        }
+    }
+
+    pub fn savings_account(&self, account_id: AccountId) -> SavingsAccountView {
+        let shares = self.savings.shares_of(&account_id);
+        SavingsAccountView {
+            shares: shares.into(),
+            assets: self.savings_assets_for(shares, false).into(),
+        }
+    }
+
+    /// Returns shares minted for the deposit of `amount` USN.
+    pub fn preview_savings_deposit(&self, amount: U128) -> U128 {
+        self.savings_shares_for(amount.0, false).into()
+    }
+
+    /// Returns shares burned for the withdrawal of `amount` USN.
+    pub fn preview_savings_withdraw(&self, amount: U128) -> U128 {
+        self.savings_shares_for(amount.0, true).into()
+    }
+
+    /// Returns USN withdrawn for `shares`.
+    pub fn preview_savings_redeem(&self, shares: U128) -> U128 {
+        self.savings_assets_for(shares.0, false).into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_NEAR, ONE_YOCTO};
+
+    use super::*;
+
+    const ONE_USN: Balance = 1_000_000_000_000_000_000;
+    /// A share of 1 USN.
+    const ONE_USN_SHARES: Balance = ONE_USN * 1_000_000;
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    /// accounts(2) has deposited 1000 USN, a half of the spread revenue is streamed during a day.
+    fn setup() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_savings_policy(500_000.into(), DAY.into());
+        contract.set_fixed_spread(10000.into()); // 1%
+        contract
+            .token
+            .internal_deposit(&accounts(2), 1000 * ONE_USN);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        assert_eq!(
+            contract.savings_deposit((1000 * ONE_USN).into()).0,
+            1000 * ONE_USN_SHARES
+        );
+        (context, contract)
+    }
+
+    #[test]
+    fn test_savings_deposit_withdraw() {
+        let (_, mut contract) = setup();
+
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 1000 * ONE_USN);
+        assert_eq!(
+            contract.savings_account(accounts(2)).assets.0,
+            1000 * ONE_USN
+        );
+
+        assert_eq!(
+            contract.savings_withdraw((400 * ONE_USN).into()).0,
+            400 * ONE_USN_SHARES
+        );
+        assert_eq!(
+            contract.savings_redeem((600 * ONE_USN_SHARES).into()).0,
+            600 * ONE_USN
+        );
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 1000 * ONE_USN);
+        assert_eq!(contract.savings().total_shares.0, 0);
+    }
+
+    #[test]
+    fn test_savings_revenue_stream() {
+        let (mut context, mut contract) = setup();
+
+        // The spread of a 100 NEAR buy is $11.1439, a half of it goes to savers.
+        contract.finish_buy(
+            accounts(3),
+            accounts(3),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+        let revenue = 5571950000000000000;
+        assert_eq!(contract.savings().pending.0, revenue);
+        assert_eq!(contract.treasury().savings_revenue.0, ONE_NEAR / 2);
+        assert_eq!(contract.treasury().reserve.0, 99 * ONE_NEAR + ONE_NEAR / 2);
+
+        // A half is streamed in a half of the day.
+        testing_env!(context
+            .block_timestamp(DAY * 1_000_000_000 / 2)
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let savings = contract.savings();
+        assert_eq!(savings.total_assets.0, 1000 * ONE_USN + revenue / 2);
+        assert_eq!(savings.pending.0, revenue / 2);
+
+        // Virtual shares get a tiny part of the revenue.
+        let deposited = 1000 * ONE_USN + revenue / 2 - 1;
+        assert_eq!(
+            contract
+                .preview_savings_redeem((1000 * ONE_USN_SHARES).into())
+                .0,
+            deposited
+        );
+        assert_eq!(
+            contract.savings_redeem((1000 * ONE_USN_SHARES).into()).0,
+            deposited
+        );
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, deposited);
+        assert_eq!(contract.supply_caps().sources[6].minted.0, revenue / 2);
+    }
+
+    #[test]
+    fn test_savings_deposit_to_empty_vault() {
+        let (mut context, mut contract) = setup();
+
+        contract.finish_buy(
+            accounts(3),
+            accounts(3),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.savings_redeem((1000 * ONE_USN_SHARES).into());
+        assert_eq!(contract.savings().total_shares.0, 0);
+
+        // The revenue is streamed to the vault without shares.
+        testing_env!(context
+            .block_timestamp(DAY * 1_000_000_000)
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        assert_eq!(contract.savings().total_assets.0, 5571950000000000000);
+        assert_eq!(
+            contract.preview_savings_deposit((100 * ONE_USN).into()).0,
+            100 * ONE_USN_SHARES
+        );
+
+        // The new deposit gets shares at the initial price, and the leftover is swept.
+        assert_eq!(
+            contract.savings_deposit((100 * ONE_USN).into()).0,
+            100 * ONE_USN_SHARES
+        );
+        let savings = contract.savings();
+        assert_eq!(savings.total_assets.0, 100 * ONE_USN);
+        assert_eq!(savings.total_shares.0, 100 * ONE_USN_SHARES);
+        assert_eq!(
+            contract.savings_account(accounts(2)).assets.0,
+            100 * ONE_USN
+        );
+    }
+
+    #[test]
+    fn test_savings_inflated_share_price() {
+        let (mut context, mut contract) = setup();
+        contract.savings_redeem((1000 * ONE_USN_SHARES).into());
+
+        // The attacker gets the only share for 1 yoctoUSN, then the revenue is streamed.
+        contract.token.internal_deposit(&accounts(3), 1);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        assert_eq!(contract.savings_deposit(1.into()).0, 1_000_000);
+        contract.finish_buy(
+            accounts(4),
+            accounts(4),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+        testing_env!(context
+            .block_timestamp(DAY * 1_000_000_000)
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+
+        // The deposit loses less than 1 microUSN to rounding, and the attacker gets about a half of the revenue.
+        let revenue = 5571950000000000000;
+        assert!(contract.savings_deposit((1000 * ONE_USN).into()).0 > 0);
+        assert!(
+            contract.savings_account(accounts(2)).assets.0 > 1000 * ONE_USN - ONE_USN / 1_000_000
+        );
+        assert!(contract.savings_account(accounts(3)).assets.0 < revenue / 2 + ONE_USN / 1_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough savings shares")]
+    fn test_savings_withdraw_over_shares() {
+        let (_, mut contract) = setup();
+
+        contract.savings_withdraw((1001 * ONE_USN).into());
+    }
+}
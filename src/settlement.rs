@@ -0,0 +1,598 @@
+//! Emergency global settlement.
+//!
+//! The owner, or a quorum of guardians, can shut the system down for good.
+//! The last oracle price is frozen, and the reserves are recorded. Minting, exchanges and transfers stop,
+//! and every holder can redeem USN for a pro-rata share of the recorded reserves with `redeem_settlement`.
+
+use near_sdk::collections::LookupMap;
+use near_sdk::{require, IntoStorageKey, Timestamp, ONE_YOCTO};
+use std::collections::HashMap;
+
+use crate::psm::ext_stable_ft;
+use crate::report::OracleRateView;
+use crate::*;
+
+const GAS_FOR_SETTLEMENT_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_SETTLEMENT_CALLBACK: Gas = Gas(5_000_000_000_000);
+
+/// Reserves recorded at the settlement.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SettledReserves {
+    /// The frozen oracle rate.
+    rate: ExchangeRate,
+    /// USN in circulation, except USN of the contract itself.
+    supply: Balance,
+    near: Balance,
+    /// USDT reserves and stablecoins of the peg stability module.
+    stables: Vec<(AccountId, Balance)>,
+    /// USN redeemed since the settlement.
+    redeemed: Balance,
+    timestamp: Timestamp,
+}
+
+/// Reserves paid out for redeemed USN.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct SettlementPayout {
+    near: Balance,
+    stables: Vec<(AccountId, Balance)>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Settlement {
+    /// Number of guardian votes, which triggers the settlement. 0 disables voting.
+    quorum: u32,
+    votes: Vec<AccountId>,
+    settled: Option<SettledReserves>,
+    /// Payouts which have failed to be transferred.
+    failed_payouts: LookupMap<AccountId, SettlementPayout>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SettledReservesView {
+    pub rate: OracleRateView,
+    pub supply: U128,
+    pub near: U128,
+    pub stables: HashMap<AccountId, U128>,
+    pub redeemed: U128,
+    pub timestamp: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SettlementView {
+    pub quorum: u32,
+    pub votes: Vec<AccountId>,
+    pub settled: Option<SettledReservesView>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SettlementPayoutView {
+    pub near: U128,
+    pub stables: HashMap<AccountId, U128>,
+}
+
+impl Settlement {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            quorum: 0,
+            votes: Vec::new(),
+            settled: None,
+            failed_payouts: LookupMap::new(prefix),
+        }
+    }
+}
+
+impl SettledReserves {
+    /// Pro-rata share of the recorded reserves for the USN amount.
+    fn payout(&self, amount: Balance) -> SettlementPayout {
+        let share = |reserve: Balance| -> Balance {
+            (U256::from(reserve) * U256::from(amount) / U256::from(self.supply)).as_u128()
+        };
+
+        SettlementPayout {
+            near: share(self.near),
+            stables: self
+                .stables
+                .iter()
+                .map(|(asset_id, reserve)| (asset_id.clone(), share(*reserve)))
+                .collect(),
+        }
+    }
+}
+
+impl From<&SettlementPayout> for SettlementPayoutView {
+    fn from(payout: &SettlementPayout) -> Self {
+        Self {
+            near: payout.near.into(),
+            stables: payout
+                .stables
+                .iter()
+                .map(|(asset_id, amount)| (asset_id.clone(), U128(*amount)))
+                .collect(),
+        }
+    }
+}
+
+#[ext_contract(ext_settlement_self)]
+trait SettlementHandler {
+    #[private]
+    fn handle_settlement_payout(
+        &mut self,
+        account: AccountId,
+        asset_id: Option<AccountId>,
+        amount: U128,
+    ) -> U128;
+}
+
+pub(crate) trait SettlementHandler {
+    fn handle_settlement_payout(
+        &mut self,
+        account: AccountId,
+        asset_id: Option<AccountId>,
+        amount: U128,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl SettlementHandler for Contract {
+    /// Remembers the failed payout of NEAR (`asset_id` is `None`) or a stablecoin,
+    /// making it claimable with `claim_settlement_payout` later.
+    #[private]
+    fn handle_settlement_payout(
+        &mut self,
+        account: AccountId,
+        asset_id: Option<AccountId>,
+        amount: U128,
+    ) -> U128 {
+        if is_promise_success() {
+            return amount;
+        }
+
+        let mut payout = self
+            .settlement
+            .failed_payouts
+            .get(&account)
+            .unwrap_or_default();
+
+        match asset_id {
+            None => {
+                self.treasury.add_reserve(amount.0);
+                payout.near += amount.0;
+            }
+            Some(asset_id) => {
+                self.internal_add_stable_reserve(&asset_id, amount.0);
+                match payout.stables.iter_mut().find(|(id, _)| *id == asset_id) {
+                    Some((_, failed)) => *failed += amount.0,
+                    None => payout.stables.push((asset_id, amount.0)),
+                }
+            }
+        }
+
+        self.settlement.failed_payouts.insert(&account, &payout);
+        0.into()
+    }
+}
+
+impl Contract {
+    pub(crate) fn abort_if_settled(&self) {
+        if self.status == ContractStatus::Settled {
+            env::panic_str("The contract is settled");
+        }
+    }
+
+    fn internal_settle(&mut self) {
+        self.abort_if_settled();
+
+        let rate = self
+            .oracle
+            .last_report
+            .clone()
+            .unwrap_or_else(|| env::panic_str("There is no oracle price to freeze"));
+
+        let usn_addr = env::current_account_id();
+        // USN of the savings vault belongs to savers, who can withdraw and redeem it.
+        let usn_held = self.token.internal_unwrap_balance_of(&usn_addr) - self.savings.assets();
+        let supply = self.token.total_supply - usn_held;
+
+        let usdt_addr = self.pool_settings.usdt_address.clone();
+        let mut stables = vec![(usdt_addr, self.reserves.usdt)];
+        for (asset_id, asset) in self.psm.iter() {
+            match stables.iter_mut().find(|(id, _)| *id == asset_id) {
+                Some((_, reserve)) => *reserve += asset.reserve,
+                None => stables.push((asset_id, asset.reserve)),
+            }
+        }
+
+        // Debts of vaults are settled by their collateral.
+        self.internal_settle_vaults(&rate);
+        let near = self.treasury.reserve();
+
+        self.settlement.settled = Some(SettledReserves {
+            rate,
+            supply,
+            near,
+            stables,
+            redeemed: 0,
+            timestamp: env::block_timestamp(),
+        });
+        self.settlement.votes.clear();
+        self.status = ContractStatus::Settled;
+
+        event::emit::global_settlement(near, supply);
+    }
+
+    /// Takes a paid out stablecoin from USDT reserves first, then from the PSM reserve.
+    fn internal_take_stable_reserve(&mut self, asset_id: &AccountId, mut amount: Balance) {
+        if *asset_id == self.pool_settings.usdt_address {
+            let taken = std::cmp::min(amount, self.reserves.usdt);
+            self.reserves.usdt -= taken;
+            amount -= taken;
+        }
+
+        if amount > 0 {
+            let mut asset = self.psm.get(asset_id).unwrap_or_else(|| {
+                env::panic_str(&format!("Not enough {} in the reserve", asset_id))
+            });
+            require!(
+                amount <= asset.reserve,
+                &format!("Not enough {} in the reserve", asset_id)
+            );
+            asset.reserve -= amount;
+            self.psm.insert(asset_id, &asset);
+        }
+    }
+
+    /// Returns a stablecoin of a failed payout to the reserve, which it has been taken from.
+    fn internal_add_stable_reserve(&mut self, asset_id: &AccountId, amount: Balance) {
+        if *asset_id == self.pool_settings.usdt_address {
+            self.reserves.usdt += amount;
+        } else if let Some(mut asset) = self.psm.get(asset_id) {
+            asset.reserve += amount;
+            self.psm.insert(asset_id, &asset);
+        }
+    }
+
+    /// Transfers the payout, remembering failed transfers.
+    fn internal_pay_settlement(&mut self, account: &AccountId, payout: &SettlementPayout) {
+        if payout.near > 0 {
+            require!(
+                payout.near <= self.internal_available_near(),
+                "Not enough liquid NEAR: staked reserves must be withdrawn first"
+            );
+            self.treasury.take_reserve(payout.near);
+
+            Promise::new(account.clone()).transfer(payout.near).then(
+                ext_settlement_self::handle_settlement_payout(
+                    account.clone(),
+                    None,
+                    payout.near.into(),
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_SETTLEMENT_CALLBACK,
+                ),
+            );
+        }
+
+        for (asset_id, amount) in &payout.stables {
+            if *amount == 0 {
+                continue;
+            }
+            self.internal_take_stable_reserve(asset_id, *amount);
+
+            ext_stable_ft::ft_transfer(
+                account.clone(),
+                U128(*amount),
+                None,
+                asset_id.clone(),
+                ONE_YOCTO,
+                GAS_FOR_SETTLEMENT_FT_TRANSFER,
+            )
+            .then(ext_settlement_self::handle_settlement_payout(
+                account.clone(),
+                Some(asset_id.clone()),
+                U128(*amount),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_SETTLEMENT_CALLBACK,
+            ));
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the number of guardian votes, which triggers the settlement. 0 disables voting.
+    pub fn set_settlement_quorum(&mut self, quorum: u32) {
+        self.assert_owner();
+        self.settlement.quorum = quorum;
+    }
+
+    /// Starts the global settlement. Only can be called by owner.
+    #[payable]
+    pub fn settle(&mut self) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_settle();
+    }
+
+    /// Votes for the global settlement, which starts with the quorum of guardians.
+    #[payable]
+    pub fn vote_settlement(&mut self) {
+        assert_one_yocto();
+        self.abort_if_settled();
+
+        let guardian_id = env::predecessor_account_id();
+        require!(
+            self.guardians.contains(&guardian_id),
+            "This method can be called only by guardian"
+        );
+        require!(
+            self.settlement.quorum > 0,
+            "The settlement can't be started by guardians"
+        );
+
+        if !self.settlement.votes.contains(&guardian_id) {
+            self.settlement.votes.push(guardian_id);
+        }
+
+        // Removed guardians don't count.
+        let guardians = &self.guardians;
+        self.settlement
+            .votes
+            .retain(|guardian_id| guardians.contains(guardian_id));
+
+        if self.settlement.votes.len() >= self.settlement.quorum as usize {
+            self.internal_settle();
+        }
+    }
+
+    pub fn revoke_settlement_vote(&mut self) {
+        let guardian_id = env::predecessor_account_id();
+        self.settlement.votes.retain(|vote| *vote != guardian_id);
+    }
+
+    /// Burns USN, including USN locked by queued redemptions, and transfers
+    /// the pro-rata share of the reserves recorded at the settlement.
+    #[payable]
+    pub fn redeem_settlement(&mut self, amount: U128) -> SettlementPayoutView {
+        assert_one_yocto();
+        self.abort_if_blacklisted();
+
+        let account = env::predecessor_account_id();
+        let amount = amount.0;
+        require!(amount > 0, "The amount should be a positive number");
+
+        let payout = match &self.settlement.settled {
+            Some(settled) => settled.payout(amount),
+            None => env::panic_str("The contract is not settled"),
+        };
+
+        let balance = self.token.internal_unwrap_balance_of(&account);
+        let locked = self.locked.get(&account).unwrap_or(0);
+        require!(amount <= balance + locked, "Not enough USN to redeem");
+
+        let from_balance = std::cmp::min(amount, balance);
+        if from_balance > 0 {
+            self.token.internal_withdraw(&account, from_balance);
+            event::emit::ft_burn(&account, from_balance, None);
+        }
+        if amount > from_balance {
+            self.internal_burn_locked(&account, amount - from_balance);
+        }
+
+        if let Some(settled) = self.settlement.settled.as_mut() {
+            settled.redeemed += amount;
+        }
+
+        event::emit::settlement_redeemed(&account, amount, payout.near);
+
+        self.internal_pay_settlement(&account, &payout);
+        (&payout).into()
+    }
+
+    /// Transfers settlement payouts, which have failed before.
+    #[payable]
+    pub fn claim_settlement_payout(&mut self) -> SettlementPayoutView {
+        assert_one_yocto();
+
+        let account = env::predecessor_account_id();
+        let payout = self
+            .settlement
+            .failed_payouts
+            .remove(&account)
+            .unwrap_or_else(|| env::panic_str("No failed payouts to claim"));
+
+        self.internal_pay_settlement(&account, &payout);
+        (&payout).into()
+    }
+
+    pub fn settlement(&self) -> SettlementView {
+        SettlementView {
+            quorum: self.settlement.quorum,
+            votes: self.settlement.votes.clone(),
+            settled: self
+                .settlement
+                .settled
+                .as_ref()
+                .map(|settled| SettledReservesView {
+                    rate: (&settled.rate).into(),
+                    supply: settled.supply.into(),
+                    near: settled.near.into(),
+                    stables: settled
+                        .stables
+                        .iter()
+                        .map(|(asset_id, reserve)| (asset_id.clone(), U128(*reserve)))
+                        .collect(),
+                    redeemed: settled.redeemed.into(),
+                    timestamp: settled.timestamp.into(),
+                }),
+        }
+    }
+
+    /// Returns reserves, which the USN amount is redeemed for.
+    pub fn settlement_payout(&self, amount: U128) -> Option<SettlementPayoutView> {
+        self.settlement
+            .settled
+            .as_ref()
+            .map(|settled| (&settled.payout(amount.0)).into())
+    }
+
+    pub fn failed_settlement_payout(&self, account_id: AccountId) -> Option<SettlementPayoutView> {
+        self.settlement
+            .failed_payouts
+            .get(&account_id)
+            .map(|payout| (&payout).into())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    /// 100 NEAR and 111 USDT back $1114.39 of USN held by `accounts(2)`.
+    fn settlement_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context
+            .account_balance(1000 * ONE_NEAR)
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+        contract.finish_buy(
+            accounts(2),
+            accounts(2),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+        contract.reserves.usdt = 111_000_000;
+        contract
+    }
+
+    #[test]
+    fn test_redeem_settlement() {
+        let mut context = get_context(accounts(1));
+        let mut contract = settlement_contract(&mut context);
+
+        contract.settle();
+        assert_eq!(contract.contract_status(), ContractStatus::Settled);
+
+        let settled = contract.settlement().settled.unwrap();
+        assert_eq!(settled.rate.multiplier.0, 111439);
+        assert_eq!(settled.supply.0, 1114390000000000000000);
+        assert_eq!(settled.near.0, 100 * ONE_NEAR);
+
+        // A half of USN is redeemed for a half of reserves.
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let payout = contract.redeem_settlement(557195000000000000000.into());
+        assert_eq!(payout.near.0, 50 * ONE_NEAR);
+        assert_eq!(
+            payout.stables[&"usdt.test.near".parse().unwrap()].0,
+            55_500_000
+        );
+        assert_eq!(contract.reserves().usdt.0, 55_500_000);
+
+        assert_eq!(contract.ft_total_supply().0, 557195000000000000000);
+        assert_eq!(contract.treasury().reserve.0, 50 * ONE_NEAR);
+        assert_eq!(
+            contract.settlement().settled.unwrap().redeemed.0,
+            557195000000000000000
+        );
+    }
+
+    #[test]
+    fn test_guardian_quorum() {
+        let mut context = get_context(accounts(1));
+        let mut contract = settlement_contract(&mut context);
+        contract.extend_guardians(vec![accounts(3), accounts(4)]);
+        contract.set_settlement_quorum(2);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.vote_settlement();
+        contract.vote_settlement();
+        assert_eq!(contract.contract_status(), ContractStatus::Working);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.vote_settlement();
+        assert_eq!(contract.contract_status(), ContractStatus::Settled);
+        assert!(contract.settlement().votes.is_empty());
+    }
+
+    #[test]
+    fn test_failed_settlement_payout() {
+        let mut context = get_context(accounts(1));
+        let mut contract = settlement_contract(&mut context);
+        contract.settle();
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.redeem_settlement(557195000000000000000.into());
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.handle_settlement_payout(accounts(2), None, (50 * ONE_NEAR).into());
+        assert_eq!(contract.treasury().reserve.0, 100 * ONE_NEAR);
+        contract.handle_settlement_payout(
+            accounts(2),
+            Some("usdt.test.near".parse().unwrap()),
+            55_500_000.into(),
+        );
+        assert_eq!(contract.reserves().usdt.0, 111_000_000);
+
+        let failed = contract.failed_settlement_payout(accounts(2)).unwrap();
+        assert_eq!(failed.near.0, 50 * ONE_NEAR);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_settlement_payout();
+        assert!(contract.failed_settlement_payout(accounts(2)).is_none());
+        assert_eq!(contract.treasury().reserve.0, 50 * ONE_NEAR);
+        assert_eq!(contract.reserves().usdt.0, 55_500_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract is settled")]
+    fn test_no_transfers_after_settlement() {
+        let mut context = get_context(accounts(1));
+        let mut contract = settlement_contract(&mut context);
+        contract.settle();
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_transfer(accounts(3), 1.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract is settled")]
+    fn test_no_mint_after_settlement() {
+        let mut context = get_context(accounts(1));
+        let mut contract = settlement_contract(&mut context);
+        contract.settle();
+
+        contract.finish_buy(
+            accounts(2),
+            accounts(2),
+            ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Spread math: the exponential curve in fixed-point arithmetic,
+//! and piecewise schedules, which differ for buys and sells.
+
+use near_sdk::require;
+
+use crate::*;
+
+const MAX_SPREAD_TIERS: usize = 16;
+
+/// Fixed-point precision of the exponent calculation (18 decimals).
+pub(crate) const EXP_PRECISION: u128 = 1_000_000_000_000_000_000;
+
+/// e^-1 with 18 decimals.
+const EXP_NEG_ONE: u128 = 367_879_441_171_442_322;
+
+/// e^-x is less than 10^-27 when x >= 64, which is negligible for the spread.
+const EXP_MAX_EXPONENT: u128 = 64;
+
+/// Number of Taylor series terms for e^x, x in [0, 1): 1/25! < 10^-25.
+const EXP_TAYLOR_TERMS: u128 = 25;
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SpreadSide {
+    Buy,
+    Sell,
+}
+
+/// The spread applied to the exchange of the given USN amount.
+/// Between thresholds the spread is interpolated linearly.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SpreadTier {
+    pub amount: U128,
+    pub spread: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PiecewiseSpread {
+    pub buy: Vec<SpreadTier>,
+    pub sell: Vec<SpreadTier>,
+}
+
+impl PiecewiseSpread {
+    pub fn new(buy: Vec<SpreadTier>, sell: Vec<SpreadTier>) -> Self {
+        validate_schedule(&buy, "buy");
+        validate_schedule(&sell, "sell");
+        Self { buy, sell }
+    }
+
+    pub fn spread(&self, amount: Balance, side: SpreadSide) -> Balance {
+        match side {
+            SpreadSide::Buy => interpolate(&self.buy, amount),
+            SpreadSide::Sell => interpolate(&self.sell, amount),
+        }
+    }
+}
+
+/// The spread layout of the version 0.1.7 with floating point params.
+#[derive(BorshDeserialize)]
+pub enum LegacySpread {
+    Fixed(Balance),
+    Exponential(LegacyExponentialSpreadParams),
+}
+
+#[derive(BorshDeserialize)]
+pub struct LegacyExponentialSpreadParams {
+    pub min: f64,
+    pub max: f64,
+    pub scaler: f64,
+}
+
+impl From<LegacySpread> for Spread {
+    fn from(spread: LegacySpread) -> Self {
+        match spread {
+            LegacySpread::Fixed(spread) => Spread::Fixed(spread),
+            LegacySpread::Exponential(params) => Spread::Exponential(params.into()),
+        }
+    }
+}
+
+impl From<LegacyExponentialSpreadParams> for ExponentialSpreadParams {
+    fn from(params: LegacyExponentialSpreadParams) -> Self {
+        let to_fixed = |value: f64, decimals: u8| -> U128 {
+            U128((value * 10f64.powi(decimals as i32)).round() as u128)
+        };
+        Self {
+            min: to_fixed(params.min, SPREAD_DECIMAL),
+            max: to_fixed(params.max, SPREAD_DECIMAL),
+            scaler: to_fixed(params.scaler, SPREAD_SCALER_DECIMAL),
+        }
+    }
+}
+
+/// Calculates `min + (max - min) * e ^ (-scaler * amount)`, rounding to the nearest spread unit.
+/// The amount is a number of whole tokens.
+pub fn exponential_spread(params: &ExponentialSpreadParams, amount: u128) -> Balance {
+    let precision = U256::from(EXP_PRECISION);
+    // The scaler has the same 18 decimals as the exponent.
+    let exponent = U256::from(params.scaler.0) * U256::from(amount);
+    let exp = exp_neg(exponent);
+    let range = params.max.0.saturating_sub(params.min.0);
+    let delta = (U256::from(range) * exp + precision / 2) / precision;
+    params.min.0 + delta.as_u128()
+}
+
+/// Calculates e^-x for x with 18 decimals, the result has 18 decimals.
+pub(crate) fn exp_neg(x: U256) -> U256 {
+    let precision = U256::from(EXP_PRECISION);
+    let whole = x / precision;
+    if whole >= U256::from(EXP_MAX_EXPONENT) {
+        return U256::zero();
+    }
+    let fraction = x % precision;
+
+    // e^f = 1 + f + f^2/2! + ... for f in [0, 1).
+    let mut term = precision;
+    let mut exp_fraction = precision;
+    for k in 1..EXP_TAYLOR_TERMS {
+        term = term * fraction / precision / U256::from(k);
+        if term.is_zero() {
+            break;
+        }
+        exp_fraction += term;
+    }
+
+    // e^-x = e^-f * (e^-1)^n
+    let mut result = precision * precision / exp_fraction;
+    for _ in 0..whole.as_u32() {
+        result = result * U256::from(EXP_NEG_ONE) / precision;
+    }
+    result
+}
+
+fn validate_schedule(tiers: &[SpreadTier], side: &str) {
+    require!(
+        !tiers.is_empty(),
+        &format!("The {} schedule is empty", side)
+    );
+    require!(
+        tiers.len() <= MAX_SPREAD_TIERS,
+        &format!(
+            "The {} schedule has more than {} tiers",
+            side, MAX_SPREAD_TIERS
+        )
+    );
+
+    for tier in tiers {
+        if tier.spread.0 > MAX_SPREAD {
+            env::panic_str(&format!(
+                "The {} schedule spread is greater than {}",
+                side, MAX_SPREAD
+            ));
+        }
+    }
+
+    for pair in tiers.windows(2) {
+        if pair[0].amount.0 >= pair[1].amount.0 {
+            env::panic_str(&format!(
+                "The {} schedule amounts must be strictly increasing",
+                side
+            ));
+        }
+    }
+}
+
+/// Interpolates the spread linearly between tiers.
+/// Amounts out of the schedule get the spread of the nearest tier.
+fn interpolate(tiers: &[SpreadTier], amount: Balance) -> Balance {
+    let first = &tiers[0];
+    if amount <= first.amount.0 {
+        return first.spread.0;
+    }
+
+    for pair in tiers.windows(2) {
+        let (low, high) = (&pair[0], &pair[1]);
+        if amount <= high.amount.0 {
+            let offset = U256::from(amount - low.amount.0);
+            let width = U256::from(high.amount.0 - low.amount.0);
+            return if high.spread.0 >= low.spread.0 {
+                let delta = U256::from(high.spread.0 - low.spread.0) * offset / width;
+                low.spread.0 + delta.as_u128()
+            } else {
+                let delta = U256::from(low.spread.0 - high.spread.0) * offset / width;
+                low.spread.0 - delta.as_u128()
+            };
+        }
+    }
+
+    tiers[tiers.len() - 1].spread.0
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    fn tier(amount: Balance, spread: Balance) -> SpreadTier {
+        SpreadTier {
+            amount: amount.into(),
+            spread: spread.into(),
+        }
+    }
+
+    /// The spread of the version 0.1.7, calculated with `f64`.
+    fn float_spread(min: f64, max: f64, scaler: f64, amount: u128) -> Balance {
+        let exp = (-scaler * amount as f64).exp();
+        let spread = min + (max - min) * exp;
+        (spread * (10u32.pow(SPREAD_DECIMAL as u32) as f64)).round() as u128
+    }
+
+    #[test]
+    fn test_exp_neg() {
+        assert_eq!(exp_neg(U256::zero()).as_u128(), EXP_PRECISION);
+        assert_eq!(exp_neg(U256::from(EXP_PRECISION)).as_u128(), EXP_NEG_ONE);
+        let half = exp_neg(U256::from(EXP_PRECISION / 2)).as_u128();
+        assert!(half.abs_diff(606_530_659_712_633_423) <= 10);
+        let ten = exp_neg(U256::from(10 * EXP_PRECISION)).as_u128();
+        assert!(ten.abs_diff(45_399_929_762_484) <= 10);
+        assert!(exp_neg(U256::from(EXP_MAX_EXPONENT * EXP_PRECISION)).is_zero());
+    }
+
+    #[test]
+    fn test_exponential_spread_matches_float_curve() {
+        let curves = [
+            (0.001, 0.005, 0.0000075),
+            (0.002, 0.006, 0.00001),
+            (0.0, 0.05, 0.4),
+            (0.003, 0.003, 0.001),
+            (0.0001, 0.0499, 0.0000001),
+        ];
+
+        for (min, max, scaler) in curves {
+            let params: ExponentialSpreadParams =
+                LegacyExponentialSpreadParams { min, max, scaler }.into();
+
+            let mut amount = 0;
+            while amount <= 10_000_000 {
+                let expected = float_spread(min, max, scaler, amount);
+                let actual = exponential_spread(&params, amount);
+                assert!(
+                    actual.abs_diff(expected) <= 1,
+                    "amount {}: {} != {}",
+                    amount,
+                    actual,
+                    expected
+                );
+                amount = amount * 3 / 2 + 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_legacy_spread_migration() {
+        let legacy = LegacySpread::Exponential(LegacyExponentialSpreadParams {
+            min: 0.001,
+            max: 0.005,
+            scaler: 0.0000075,
+        });
+        match legacy.into() {
+            Spread::Exponential(params) => {
+                assert_eq!(params, ExponentialSpreadParams::default())
+            }
+            _ => panic!("Unexpected spread"),
+        }
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let tiers = vec![tier(100, 5000), tier(1100, 1000), tier(2100, 3000)];
+
+        assert_eq!(interpolate(&tiers, 0), 5000);
+        assert_eq!(interpolate(&tiers, 100), 5000);
+        assert_eq!(interpolate(&tiers, 600), 3000);
+        assert_eq!(interpolate(&tiers, 1100), 1000);
+        assert_eq!(interpolate(&tiers, 1350), 1500);
+        assert_eq!(interpolate(&tiers, 2100), 3000);
+        assert_eq!(interpolate(&tiers, u128::MAX), 3000);
+    }
+
+    #[test]
+    #[should_panic(expected = "The sell schedule amounts must be strictly increasing")]
+    fn test_unordered_schedule() {
+        PiecewiseSpread::new(vec![tier(0, 1000)], vec![tier(100, 1000), tier(100, 2000)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The buy schedule spread is greater than 50000")]
+    fn test_schedule_spread_limit() {
+        PiecewiseSpread::new(vec![tier(0, MAX_SPREAD + 1)], vec![tier(0, 1000)]);
+    }
+}
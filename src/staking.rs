@@ -0,0 +1,449 @@
+//! Staking of NEAR reserves.
+//!
+//! A configurable share of the reserves is deposited and staked with a whitelisted
+//! staking pool. The liquid part of the reserves never goes below the floor,
+//! so `sell` payouts keep working. Staking rewards are treasury revenue.
+
+use near_sdk::require;
+
+use crate::*;
+
+/// The staked share has 6 decimals: 1_000_000 is 100%.
+const STAKE_SHARE_DECIMAL: u8 = 6;
+
+const GAS_FOR_STAKING_POOL_CALL: Gas = Gas(50_000_000_000_000);
+const GAS_FOR_STAKING_BALANCE: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_STAKING_CALLBACK: Gas = Gas(5_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Staking {
+    pool_id: Option<AccountId>,
+    /// The maximum share of reserves at the pool with `STAKE_SHARE_DECIMAL` decimals.
+    max_share: u128,
+    /// Liquid NEAR, which always stays for `sell` payouts.
+    liquid_floor: Balance,
+    /// Reserves deposited to the pool.
+    principal: Balance,
+    /// Harvested rewards, which are still at the pool.
+    rewards: Balance,
+    /// NEAR unstaked at the pool, but not withdrawn yet.
+    unstaked: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingView {
+    pub pool_id: Option<AccountId>,
+    pub max_share: U128,
+    pub liquid_floor: U128,
+    pub principal: U128,
+    pub rewards: U128,
+    pub unstaked: U128,
+    pub liquid: U128,
+}
+
+impl Default for Staking {
+    fn default() -> Self {
+        Self {
+            pool_id: None,
+            max_share: 0,
+            liquid_floor: 0,
+            principal: 0,
+            rewards: 0,
+            unstaked: 0,
+        }
+    }
+}
+
+impl Staking {
+    /// Reserves, which are not in the account balance.
+    pub fn staked_reserve(&self) -> Balance {
+        self.principal
+    }
+
+    /// NEAR at the pool, including harvested rewards.
+    pub fn total(&self) -> Balance {
+        self.principal + self.rewards
+    }
+
+    pub fn pool(&self) -> Option<AccountId> {
+        self.pool_id.clone()
+    }
+
+    fn pool_id(&self) -> AccountId {
+        self.pool_id
+            .clone()
+            .unwrap_or_else(|| env::panic_str("The staking pool is not set"))
+    }
+}
+
+#[ext_contract(ext_staking_pool)]
+pub(crate) trait StakingPool {
+    #[payable]
+    fn deposit_and_stake(&mut self);
+
+    fn unstake(&mut self, amount: U128);
+
+    fn withdraw(&mut self, amount: U128);
+
+    fn get_account_total_balance(&self, account_id: AccountId) -> U128;
+}
+
+#[ext_contract(ext_staking_self)]
+trait StakingHandler {
+    #[private]
+    fn handle_stake(&mut self, amount: U128) -> U128;
+
+    #[private]
+    fn handle_unstake(&mut self, amount: U128) -> U128;
+
+    #[private]
+    fn handle_withdraw(&mut self, amount: U128) -> U128;
+
+    #[private]
+    fn handle_staking_rewards(&mut self, #[callback] total_balance: U128) -> U128;
+}
+
+pub(crate) trait StakingHandler {
+    fn handle_stake(&mut self, amount: U128) -> U128;
+
+    fn handle_unstake(&mut self, amount: U128) -> U128;
+
+    fn handle_withdraw(&mut self, amount: U128) -> U128;
+
+    fn handle_staking_rewards(&mut self, total_balance: U128) -> U128;
+}
+
+#[near_bindgen]
+impl StakingHandler for Contract {
+    /// Returns the staked amount. The deposit comes back, if the pool has failed.
+    #[private]
+    fn handle_stake(&mut self, amount: U128) -> U128 {
+        if is_promise_success() {
+            amount
+        } else {
+            self.staking.principal -= amount.0;
+            0.into()
+        }
+    }
+
+    #[private]
+    fn handle_unstake(&mut self, amount: U128) -> U128 {
+        if is_promise_success() {
+            self.staking.unstaked += amount.0;
+            amount
+        } else {
+            0.into()
+        }
+    }
+
+    /// Withdrawn NEAR covers harvested rewards first, so they become withdrawable revenue.
+    #[private]
+    fn handle_withdraw(&mut self, amount: U128) -> U128 {
+        if !is_promise_success() {
+            return 0.into();
+        }
+
+        let from_rewards = std::cmp::min(amount.0, self.staking.rewards);
+        self.staking.rewards -= from_rewards;
+        self.staking.principal = self
+            .staking
+            .principal
+            .saturating_sub(amount.0 - from_rewards);
+        self.staking.unstaked = self.staking.unstaked.saturating_sub(amount.0);
+        amount
+    }
+
+    /// Records the growth of the pool balance as revenue.
+    #[private]
+    fn handle_staking_rewards(&mut self, #[callback] total_balance: U128) -> U128 {
+        let rewards = total_balance.0.saturating_sub(self.staking.total());
+        self.staking.rewards += rewards;
+        self.treasury.record_staking_rewards(rewards);
+        rewards.into()
+    }
+}
+
+impl Contract {
+    /// Panics if taking the amount out of the account leaves liquid NEAR below the floor.
+    pub(crate) fn assert_liquid_floor(&self, amount: Balance) {
+        let liquid = self.internal_available_near();
+        require!(
+            liquid >= amount && liquid - amount >= self.staking.liquid_floor,
+            "The liquid reserves can't go below the floor"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Whitelists the staking pool. `None` disables staking.
+    /// The pool can be changed only after all reserves have been withdrawn.
+    pub fn set_staking_pool(&mut self, pool_id: Option<AccountId>) {
+        self.assert_owner();
+        require!(
+            self.staking.total() == 0,
+            "Reserves are still at the staking pool"
+        );
+        self.staking.pool_id = pool_id;
+    }
+
+    /// Sets the maximum staked share of reserves with 6 decimals, and the liquid NEAR floor.
+    pub fn set_staking_policy(&mut self, max_share: U128, liquid_floor: U128) {
+        self.assert_owner();
+        require!(
+            max_share.0 <= 10u128.pow(STAKE_SHARE_DECIMAL as u32),
+            "The staked share can't be greater than 100%"
+        );
+        self.staking.max_share = max_share.0;
+        self.staking.liquid_floor = liquid_floor.0;
+    }
+
+    /// Deposits and stakes the given amount of reserves.
+    pub fn stake_reserves(&mut self, amount: U128) -> Promise {
+        self.assert_owner_or_guardian();
+        self.abort_if_settled();
+
+        let pool_id = self.staking.pool_id();
+        require!(amount.0 > 0, "The amount should be a positive number");
+        require!(
+            self.redemptions.is_empty(),
+            "Reserves can't be staked while redemptions are queued"
+        );
+
+        let max_staked = U256::from(self.treasury.reserve()) * U256::from(self.staking.max_share)
+            / U256::from(10u128.pow(STAKE_SHARE_DECIMAL as u32));
+        require!(
+            U256::from(self.staking.principal + amount.0) <= max_staked,
+            "The staked share of reserves is above the maximum"
+        );
+
+        self.assert_liquid_floor(amount.0);
+
+        self.staking.principal += amount.0;
+
+        ext_staking_pool::deposit_and_stake(pool_id, amount.0, GAS_FOR_STAKING_POOL_CALL).then(
+            ext_staking_self::handle_stake(
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_STAKING_CALLBACK,
+            ),
+        )
+    }
+
+    /// Unstakes NEAR at the pool. It can be withdrawn in 4 epochs.
+    pub fn unstake_reserves(&mut self, amount: U128) -> Promise {
+        self.assert_owner_or_guardian();
+
+        let pool_id = self.staking.pool_id();
+        require!(amount.0 > 0, "The amount should be a positive number");
+        require!(
+            amount.0 <= self.staking.total() - self.staking.unstaked,
+            "Not enough staked reserves"
+        );
+
+        ext_staking_pool::unstake(amount, pool_id, NO_DEPOSIT, GAS_FOR_STAKING_POOL_CALL).then(
+            ext_staking_self::handle_unstake(
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_STAKING_CALLBACK,
+            ),
+        )
+    }
+
+    /// Withdraws unstaked NEAR from the pool back to the liquid reserves.
+    pub fn withdraw_staked_reserves(&mut self, amount: U128) -> Promise {
+        self.assert_owner_or_guardian();
+
+        let pool_id = self.staking.pool_id();
+        require!(amount.0 > 0, "The amount should be a positive number");
+        require!(
+            amount.0 <= self.staking.unstaked,
+            "Not enough unstaked reserves"
+        );
+
+        ext_staking_pool::withdraw(amount, pool_id, NO_DEPOSIT, GAS_FOR_STAKING_POOL_CALL).then(
+            ext_staking_self::handle_withdraw(
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_STAKING_CALLBACK,
+            ),
+        )
+    }
+
+    /// Checks the pool balance and records new rewards as treasury revenue.
+    pub fn harvest_staking_rewards(&mut self) -> Promise {
+        self.assert_owner_or_guardian();
+
+        ext_staking_pool::get_account_total_balance(
+            env::current_account_id(),
+            self.staking.pool_id(),
+            NO_DEPOSIT,
+            GAS_FOR_STAKING_BALANCE,
+        )
+        .then(ext_staking_self::handle_staking_rewards(
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_STAKING_CALLBACK,
+        ))
+    }
+
+    pub fn staking(&self) -> StakingView {
+        StakingView {
+            pool_id: self.staking.pool_id.clone(),
+            max_share: self.staking.max_share.into(),
+            liquid_floor: self.staking.liquid_floor.into(),
+            principal: self.staking.principal.into(),
+            rewards: self.staking.rewards.into(),
+            unstaked: self.staking.unstaked.into(),
+            liquid: self.internal_available_near().into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn set_promise_result(context: &mut VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![result],
+        );
+    }
+
+    /// 100 NEAR of reserves, 100 NEAR are liquid, a half can be staked keeping 40 NEAR liquid.
+    fn staking_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.account_balance(1000 * ONE_NEAR).build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+        contract.finish_buy(
+            accounts(2),
+            accounts(2),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        );
+
+        testing_env!(context.account_balance(1000 * ONE_NEAR).build());
+        let storage_floor = 1000 * ONE_NEAR - contract.internal_available_near();
+        testing_env!(context
+            .account_balance(storage_floor + 100 * ONE_NEAR)
+            .build());
+
+        contract.set_staking_pool(Some(accounts(4)));
+        contract.set_staking_policy(500_000.into(), (40 * ONE_NEAR).into());
+        contract
+    }
+
+    #[test]
+    fn test_stake_reserves() {
+        let mut context = get_context(accounts(1));
+        let mut contract = staking_contract(&mut context);
+
+        contract.stake_reserves((30 * ONE_NEAR).into());
+        assert_eq!(contract.staking().principal.0, 30 * ONE_NEAR);
+
+        // The pool has failed: the deposit is back.
+        set_promise_result(&mut context, PromiseResult::Failed);
+        assert_eq!(contract.handle_stake((30 * ONE_NEAR).into()).0, 0);
+        assert_eq!(contract.staking().principal.0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.stake_reserves((50 * ONE_NEAR).into());
+        set_promise_result(&mut context, PromiseResult::Successful(vec![]));
+        assert_eq!(
+            contract.handle_stake((50 * ONE_NEAR).into()).0,
+            50 * ONE_NEAR
+        );
+        assert_eq!(contract.staking().principal.0, 50 * ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "The staked share of reserves is above the maximum")]
+    fn test_stake_share_limit() {
+        let mut context = get_context(accounts(1));
+        let mut contract = staking_contract(&mut context);
+
+        contract.stake_reserves((51 * ONE_NEAR).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "The liquid reserves can't go below the floor")]
+    fn test_liquid_floor() {
+        let mut context = get_context(accounts(1));
+        let mut contract = staking_contract(&mut context);
+        contract.set_staking_policy(1_000_000.into(), (40 * ONE_NEAR).into());
+
+        contract.stake_reserves((61 * ONE_NEAR).into());
+    }
+
+    #[test]
+    fn test_staking_rewards() {
+        let mut context = get_context(accounts(1));
+        let mut contract = staking_contract(&mut context);
+        contract.set_treasury(accounts(3));
+
+        contract.stake_reserves((50 * ONE_NEAR).into());
+        set_promise_result(&mut context, PromiseResult::Successful(vec![]));
+        contract.handle_stake((50 * ONE_NEAR).into());
+
+        // The staked NEAR has left the account balance: the reserves are intact.
+        let balance = context.context.account_balance;
+        testing_env!(context.account_balance(balance - 50 * ONE_NEAR).build());
+        assert_eq!(contract.treasury().withdrawable.0, 0);
+
+        assert_eq!(
+            contract.handle_staking_rewards((52 * ONE_NEAR).into()).0,
+            2 * ONE_NEAR
+        );
+        assert_eq!(contract.staking().rewards.0, 2 * ONE_NEAR);
+        assert_eq!(contract.treasury().staking_revenue.0, 2 * ONE_NEAR);
+        // Nothing new to harvest.
+        assert_eq!(contract.handle_staking_rewards((52 * ONE_NEAR).into()).0, 0);
+
+        // The rewards are at the pool yet.
+        assert_eq!(contract.treasury().withdrawable.0, 0);
+
+        set_promise_result(&mut context, PromiseResult::Successful(vec![]));
+        contract.handle_unstake((10 * ONE_NEAR).into());
+        assert_eq!(contract.staking().unstaked.0, 10 * ONE_NEAR);
+        contract.handle_withdraw((10 * ONE_NEAR).into());
+        testing_env!(context.account_balance(balance - 40 * ONE_NEAR).build());
+
+        let staking = contract.staking();
+        assert_eq!(staking.principal.0, 42 * ONE_NEAR);
+        assert_eq!(staking.rewards.0, 0);
+        assert_eq!(staking.unstaked.0, 0);
+        assert_eq!(contract.treasury().withdrawable.0, 2 * ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reserves are still at the staking pool")]
+    fn test_change_pool_with_stake() {
+        let mut context = get_context(accounts(1));
+        let mut contract = staking_contract(&mut context);
+
+        contract.stake_reserves((10 * ONE_NEAR).into());
+        contract.set_staking_pool(Some(accounts(5)));
+    }
+}
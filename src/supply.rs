@@ -0,0 +1,308 @@
+//! Supply caps of USN.
+//!
+//! The owner can limit the total supply of USN and, separately, USN minted by each mint source.
+//! USN of a source counts against its cap until it is burned through the same source,
+//! e.g. USN bought for NEAR until it is sold.
+
+use near_sdk::require;
+
+use crate::*;
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MintSource {
+    /// USN bought for NEAR.
+    Buy,
+    /// USN minted as liquidity of the USN/USDT stable pool.
+    Pool,
+    /// USN minted for stablecoins of the peg stability module.
+    Psm,
+    /// USN minted by configured minters.
+    Minter,
+    /// USN flash minted within a promise chain.
+    Flash,
+    /// USN borrowed against NEAR vaults.
+    Vault,
+    /// Spread revenue streamed to the savings vault.
+    Savings,
+}
+
+impl MintSource {
+    const ALL: [MintSource; 7] = [
+        MintSource::Buy,
+        MintSource::Pool,
+        MintSource::Psm,
+        MintSource::Minter,
+        MintSource::Flash,
+        MintSource::Vault,
+        MintSource::Savings,
+    ];
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct SourceSupply {
+    source: MintSource,
+    cap: Option<Balance>,
+    minted: Balance,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct SupplyCaps {
+    max_supply: Option<Balance>,
+    sources: Vec<SourceSupply>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SourceSupplyView {
+    pub source: MintSource,
+    pub cap: Option<U128>,
+    pub minted: U128,
+    /// USN which can still be minted by the source, `None` if it's unlimited.
+    pub headroom: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SupplyCapsView {
+    pub total_supply: U128,
+    pub max_supply: Option<U128>,
+    /// USN which can still be minted under the max supply, `None` if it's unlimited.
+    pub headroom: Option<U128>,
+    pub sources: Vec<SourceSupplyView>,
+}
+
+impl SupplyCaps {
+    fn source(&self, source: MintSource) -> Option<&SourceSupply> {
+        self.sources.iter().find(|supply| supply.source == source)
+    }
+
+    fn source_mut(&mut self, source: MintSource) -> &mut SourceSupply {
+        match self
+            .sources
+            .iter()
+            .position(|supply| supply.source == source)
+        {
+            Some(idx) => &mut self.sources[idx],
+            None => {
+                self.sources.push(SourceSupply {
+                    source,
+                    cap: None,
+                    minted: 0,
+                });
+                self.sources.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn cap_of(&self, source: MintSource) -> Option<Balance> {
+        self.source(source).and_then(|supply| supply.cap)
+    }
+
+    fn minted_by(&self, source: MintSource) -> Balance {
+        self.source(source).map_or(0, |supply| supply.minted)
+    }
+
+    fn record_mint(&mut self, source: MintSource, amount: Balance) {
+        self.source_mut(source).minted += amount;
+    }
+
+    fn record_burn(&mut self, source: MintSource, amount: Balance) {
+        let supply = self.source_mut(source);
+        // USN minted before the caps were introduced isn't counted.
+        supply.minted = supply.minted.saturating_sub(amount);
+    }
+}
+
+impl Contract {
+    /// Mints USN of the source, if it fits under the supply caps.
+    pub(crate) fn internal_mint(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+        source: MintSource,
+        memo: Option<&str>,
+    ) {
+        if let Some(headroom) = self.supply_headroom() {
+            require!(amount <= headroom, "The USN supply cap is reached");
+        }
+        if let Some(headroom) = self.source_headroom(source) {
+            require!(
+                amount <= headroom,
+                &format!("The {:?} mint cap is reached", source)
+            );
+        }
+
+        self.internal_remint(account_id, amount, source, memo);
+    }
+
+    /// Mints back USN burned by a failed operation. It isn't new supply, so the caps aren't checked.
+    pub(crate) fn internal_remint(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+        source: MintSource,
+        memo: Option<&str>,
+    ) {
+        self.supply.record_mint(source, amount);
+        self.token.internal_deposit(account_id, amount);
+        event::emit::ft_mint(account_id, amount, memo);
+    }
+
+    /// Releases the cap of the source for burned USN.
+    pub(crate) fn record_burn(&mut self, source: MintSource, amount: Balance) {
+        self.supply.record_burn(source, amount);
+    }
+
+    /// USN which can still be minted by the source under both caps, `None` if it's unlimited.
+    pub(crate) fn mint_headroom(&self, source: MintSource) -> Option<Balance> {
+        match (self.source_headroom(source), self.supply_headroom()) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn supply_headroom(&self) -> Option<Balance> {
+        self.supply
+            .max_supply
+            .map(|max_supply| max_supply.saturating_sub(self.token.total_supply))
+    }
+
+    fn source_headroom(&self, source: MintSource) -> Option<Balance> {
+        self.supply
+            .cap_of(source)
+            .map(|cap| cap.saturating_sub(self.supply.minted_by(source)))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the maximum total supply of USN. `None` removes the limit.
+    pub fn set_max_supply(&mut self, max_supply: Option<U128>) {
+        self.assert_owner();
+        self.supply.max_supply = max_supply.map(|max_supply| max_supply.0);
+    }
+
+    /// Sets the maximum amount of USN minted by the source. `None` removes the limit.
+    pub fn set_mint_cap(&mut self, source: MintSource, cap: Option<U128>) {
+        self.assert_owner();
+        self.supply.source_mut(source).cap = cap.map(|cap| cap.0);
+    }
+
+    /// Returns the supply caps and the headroom left under each of them.
+    /// The headroom of a source includes the max supply limit.
+    pub fn supply_caps(&self) -> SupplyCapsView {
+        let headroom = self.supply_headroom();
+
+        let sources = MintSource::ALL
+            .iter()
+            .map(|&source| SourceSupplyView {
+                source,
+                cap: self.supply.cap_of(source).map(U128),
+                minted: self.supply.minted_by(source).into(),
+                headroom: self.mint_headroom(source).map(U128),
+            })
+            .collect();
+
+        SupplyCapsView {
+            total_supply: self.token.total_supply.into(),
+            max_supply: self.supply.max_supply.map(U128),
+            headroom: headroom.map(U128),
+            sources,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_NEAR};
+
+    use super::*;
+
+    const BOUGHT: Balance = 1114390000000000000000; // USN for 100 NEAR
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn buy_100_near(contract: &mut Contract) -> Balance {
+        contract.finish_buy(
+            accounts(2),
+            accounts(2),
+            100 * ONE_NEAR,
+            None,
+            ExchangeRate::test_fresh_rate(),
+        )
+    }
+
+    #[test]
+    fn test_supply_caps() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+
+        let caps = contract.supply_caps();
+        assert!(caps.headroom.is_none());
+        assert!(caps.sources.iter().all(|source| source.headroom.is_none()));
+
+        contract.set_max_supply(Some((3 * BOUGHT).into()));
+        contract.set_mint_cap(MintSource::Buy, Some((2 * BOUGHT).into()));
+        assert_eq!(buy_100_near(&mut contract), BOUGHT);
+
+        let caps = contract.supply_caps();
+        assert_eq!(caps.total_supply.0, BOUGHT);
+        assert_eq!(caps.headroom.unwrap().0, 2 * BOUGHT);
+        assert_eq!(caps.sources[0].source, MintSource::Buy);
+        assert_eq!(caps.sources[0].minted.0, BOUGHT);
+        assert_eq!(caps.sources[0].headroom.unwrap().0, BOUGHT);
+        // The max supply limits other sources.
+        assert_eq!(caps.sources[2].headroom.unwrap().0, 2 * BOUGHT);
+
+        contract.record_burn(MintSource::Buy, BOUGHT);
+        assert_eq!(
+            contract.supply_caps().sources[0].headroom.unwrap().0,
+            2 * BOUGHT
+        );
+
+        contract.set_mint_cap(MintSource::Buy, None);
+        assert_eq!(
+            contract.supply_caps().sources[0].headroom.unwrap().0,
+            2 * BOUGHT
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The USN supply cap is reached")]
+    fn test_max_supply() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+        contract.set_max_supply(Some((BOUGHT + BOUGHT / 2).into()));
+
+        buy_100_near(&mut contract);
+        buy_100_near(&mut contract);
+    }
+
+    #[test]
+    #[should_panic(expected = "The Buy mint cap is reached")]
+    fn test_mint_cap() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(0.into());
+        contract.set_mint_cap(MintSource::Buy, Some(BOUGHT.into()));
+
+        buy_100_near(&mut contract);
+        buy_100_near(&mut contract);
+    }
+}
@@ -0,0 +1,349 @@
+//! Per-account fee tiers.
+//!
+//! The owner assigns a discount or the spread exemption to market makers and partners.
+//! Other accounts get automatic discounts based on their 30-day volume of minted and redeemed USN.
+//! Volume counts completed exchanges only: buys paid by the account, and sells paid out to it.
+
+use near_sdk::{require, Timestamp};
+
+use crate::*;
+
+const VOLUME_PERIOD: Timestamp = 30 * 24 * 60 * 60 * 1_000_000_000; // 30 days
+const MAX_VOLUME_TIERS: usize = 16;
+const MAX_DISCOUNT: u8 = 100;
+const MAX_PRUNED_VOLUMES: usize = 100;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FeeTier {
+    /// The spread is reduced by the given percentage.
+    Discount(u8),
+    /// No spread at all.
+    Exempt,
+}
+
+/// The discount percentage for accounts with the 30-day volume of at least `volume` USN.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VolumeTier {
+    pub volume: U128,
+    pub discount: u8,
+}
+
+/// Volume of the current and the previous 30-day periods.
+/// The 30-day volume is estimated as a sliding window over both periods.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct AccountVolume {
+    period: u64,
+    current: Balance,
+    previous: Balance,
+}
+
+impl AccountVolume {
+    fn rotate(&mut self, period: u64) {
+        if period == self.period + 1 {
+            self.previous = self.current;
+            self.current = 0;
+        } else if period > self.period + 1 {
+            self.previous = 0;
+            self.current = 0;
+        }
+        self.period = period;
+    }
+
+    fn volume_at(&self, timestamp: Timestamp) -> Balance {
+        let period = timestamp / VOLUME_PERIOD;
+        let (current, previous) = if period == self.period {
+            (self.current, self.previous)
+        } else if period == self.period + 1 {
+            (0, self.current)
+        } else {
+            (0, 0)
+        };
+
+        // Part of the previous period, which is still in the window.
+        let elapsed = timestamp % VOLUME_PERIOD;
+        let previous =
+            U256::from(previous) * U256::from(VOLUME_PERIOD - elapsed) / U256::from(VOLUME_PERIOD);
+        current + previous.as_u128()
+    }
+}
+
+impl Contract {
+    /// Adds minted or redeemed USN to the 30-day volume of the account.
+    pub(crate) fn record_volume(&mut self, account_id: &AccountId, amount: Balance) {
+        let period = env::block_timestamp() / VOLUME_PERIOD;
+        let mut volume = self.volumes.get(account_id).unwrap_or_default();
+        volume.rotate(period);
+        volume.current = volume.current.saturating_add(amount);
+        self.volumes.insert(account_id, &volume);
+    }
+
+    /// Removes volumes of accounts, which have no exchanges during the last 30 days.
+    /// Returns the number of removed volumes.
+    fn internal_prune_volumes(&mut self, account_ids: &[AccountId]) -> u32 {
+        let mut pruned = 0;
+        for account_id in account_ids {
+            if self.volumes.contains_key(account_id)
+                && self.account_volume(account_id.clone()).0 == 0
+            {
+                self.volumes.remove(account_id);
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+
+    /// Returns the spread for the account, taking its fee tier into account.
+    pub(crate) fn account_spread(
+        &self,
+        account_id: &AccountId,
+        amount: Balance,
+        side: SpreadSide,
+    ) -> Balance {
+        let discount = match self.fee_tiers.get(account_id) {
+            Some(FeeTier::Exempt) => return 0,
+            Some(FeeTier::Discount(discount)) => discount,
+            None => 0,
+        };
+        let discount = std::cmp::max(discount, self.volume_discount(account_id));
+
+        let spread = self.spread_u128(amount, side);
+        spread - spread * discount as Balance / MAX_DISCOUNT as Balance
+    }
+
+    fn volume_discount(&self, account_id: &AccountId) -> u8 {
+        if self.volume_tiers.is_empty() {
+            return 0;
+        }
+
+        let volume = self.account_volume(account_id.clone()).0;
+        self.volume_tiers
+            .iter()
+            .rev()
+            .find(|tier| volume >= tier.volume.0)
+            .map_or(0, |tier| tier.discount)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Assigns the fee tier to the account. `None` removes the tier.
+    pub fn set_fee_tier(&mut self, account_id: AccountId, tier: Option<FeeTier>) {
+        self.assert_owner();
+
+        match tier {
+            Some(tier) => {
+                if let FeeTier::Discount(discount) = tier {
+                    require!(
+                        discount <= MAX_DISCOUNT,
+                        "The discount can't be greater than 100%"
+                    );
+                }
+                self.fee_tiers.insert(&account_id, &tier);
+            }
+            None => {
+                self.fee_tiers.remove(&account_id);
+            }
+        }
+    }
+
+    /// Sets discounts by the 30-day volume, ordered by the volume.
+    pub fn set_volume_tiers(&mut self, tiers: Vec<VolumeTier>) {
+        self.assert_owner();
+
+        require!(
+            tiers.len() <= MAX_VOLUME_TIERS,
+            &format!(
+                "The number of volume tiers is limited to {}",
+                MAX_VOLUME_TIERS
+            )
+        );
+        for tier in &tiers {
+            require!(
+                tier.discount <= MAX_DISCOUNT,
+                "The discount can't be greater than 100%"
+            );
+        }
+        for pair in tiers.windows(2) {
+            require!(
+                pair[0].volume.0 < pair[1].volume.0,
+                "Volume tiers must be ordered by the volume"
+            );
+        }
+
+        self.volume_tiers = tiers;
+    }
+
+    /// Removes outdated volumes of the given accounts. Can be called by anyone.
+    pub fn prune_volumes(&mut self, account_ids: Vec<AccountId>) -> u32 {
+        require!(
+            account_ids.len() <= MAX_PRUNED_VOLUMES,
+            &format!(
+                "The number of accounts is limited to {}",
+                MAX_PRUNED_VOLUMES
+            )
+        );
+        self.internal_prune_volumes(&account_ids)
+    }
+
+    pub fn fee_tier(&self, account_id: AccountId) -> Option<FeeTier> {
+        self.fee_tiers.get(&account_id)
+    }
+
+    pub fn volume_tiers(&self) -> Vec<VolumeTier> {
+        self.volume_tiers.clone()
+    }
+
+    /// Returns USN minted and redeemed by the account during the last 30 days.
+    pub fn account_volume(&self, account_id: AccountId) -> U128 {
+        self.volumes
+            .get(&account_id)
+            .map_or(0, |volume| volume.volume_at(env::block_timestamp()))
+            .into()
+    }
+
+    /// Returns the spread, which the account pays for the given amount.
+    pub fn effective_spread(
+        &self,
+        account_id: AccountId,
+        amount: Option<U128>,
+        side: Option<SpreadSide>,
+    ) -> U128 {
+        let amount = amount.unwrap_or(U128::from(0));
+        let side = side.unwrap_or(SpreadSide::Buy);
+        self.account_spread(&account_id, amount.0, side).into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    #[test]
+    fn test_fee_tiers() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(10000.into());
+
+        contract.set_fee_tier(accounts(2), Some(FeeTier::Exempt));
+        contract.set_fee_tier(accounts(3), Some(FeeTier::Discount(25)));
+
+        assert_eq!(contract.effective_spread(accounts(2), None, None).0, 0);
+        assert_eq!(contract.effective_spread(accounts(3), None, None).0, 7500);
+        assert_eq!(contract.effective_spread(accounts(4), None, None).0, 10000);
+
+        contract.set_fee_tier(accounts(2), None);
+        assert_eq!(contract.fee_tier(accounts(2)), None);
+        assert_eq!(contract.effective_spread(accounts(2), None, None).0, 10000);
+    }
+
+    #[test]
+    fn test_volume_tiers() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(10000.into());
+
+        contract.set_volume_tiers(vec![
+            VolumeTier {
+                volume: (1000 * ONE_NEAR).into(),
+                discount: 10,
+            },
+            VolumeTier {
+                volume: (10000 * ONE_NEAR).into(),
+                discount: 50,
+            },
+        ]);
+        contract.set_fee_tier(accounts(3), Some(FeeTier::Discount(20)));
+
+        contract.record_volume(&accounts(2), 600 * ONE_NEAR);
+        contract.record_volume(&accounts(3), 600 * ONE_NEAR);
+        assert_eq!(contract.effective_spread(accounts(2), None, None).0, 10000);
+
+        contract.record_volume(&accounts(2), 600 * ONE_NEAR);
+        contract.record_volume(&accounts(3), 600 * ONE_NEAR);
+        assert_eq!(contract.account_volume(accounts(2)).0, 1200 * ONE_NEAR);
+        assert_eq!(contract.effective_spread(accounts(2), None, None).0, 9000);
+        // The best discount applies.
+        assert_eq!(contract.effective_spread(accounts(3), None, None).0, 8000);
+
+        // A half of the previous 30-day period is still counted.
+        testing_env!(context
+            .block_timestamp(VOLUME_PERIOD + VOLUME_PERIOD / 2)
+            .build());
+        assert_eq!(contract.account_volume(accounts(2)).0, 600 * ONE_NEAR);
+        assert_eq!(contract.effective_spread(accounts(2), None, None).0, 10000);
+
+        testing_env!(context.block_timestamp(2 * VOLUME_PERIOD).build());
+        assert_eq!(contract.account_volume(accounts(2)).0, 0);
+
+        assert_eq!(contract.prune_volumes(vec![accounts(2), accounts(4)]), 1);
+        assert!(contract.volumes.get(&accounts(2)).is_none());
+    }
+
+    #[test]
+    fn test_exchange_volume() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        let rate = ExchangeRate::test_fresh_rate();
+
+        // The payer gets the volume of the buy, not the receiver.
+        let usn = contract.finish_buy(accounts(2), accounts(3), ONE_NEAR, None, rate.clone());
+        assert!(contract.account_volume(accounts(2)).0 > usn);
+        assert_eq!(contract.account_volume(accounts(3)).0, 0);
+
+        // The sell counts after the payout only.
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let (deposit, fee) = contract.finish_sell(accounts(3), usn, None, rate);
+        assert_eq!(contract.account_volume(accounts(3)).0, 0);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.handle_sell_payout(accounts(3), usn.into(), deposit.into(), fee.into(), None);
+        assert_eq!(contract.account_volume(accounts(3)).0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let (deposit, fee) =
+            contract.finish_sell(accounts(3), usn, None, ExchangeRate::test_fresh_rate());
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.handle_sell_payout(accounts(3), usn.into(), deposit.into(), fee.into(), None);
+        assert_eq!(contract.account_volume(accounts(3)).0, usn);
+    }
+
+    #[test]
+    #[should_panic(expected = "The discount can't be greater than 100%")]
+    fn test_discount_limit() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        contract.set_fee_tier(accounts(2), Some(FeeTier::Discount(101)));
+    }
+}
@@ -0,0 +1,351 @@
+//! Treasury accounting: NEAR reserves backing USN and collected spread revenue.
+//!
+//! Buys add NEAR to the reserves, sells take it out. The spread part of every exchange
+//! is revenue, which the owner can withdraw to the treasury account, but never the reserves.
+//! Rewards of staked reserves are revenue too. The share of the revenue streamed to the savings vault
+//! backs the USN minted for savers, so it moves to the reserves.
+
+use near_sdk::{require, Timestamp};
+
+use crate::*;
+
+const REVENUE_WITHDRAWAL_INTERVAL: Timestamp = 24 * 60 * 60 * 1_000_000_000; // 1 day
+const GAS_FOR_REVENUE_WITHDRAWAL: Gas = Gas(5_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Revenue {
+    pub usn: Balance,
+    pub near: Balance,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Treasury {
+    treasury_id: Option<AccountId>,
+    /// NEAR backing USN.
+    reserve: Balance,
+    buy_revenue: Revenue,
+    sell_revenue: Revenue,
+    /// NEAR rewards of staked reserves.
+    staking_revenue: Balance,
+    /// NEAR revenue moved to the reserves for the savings vault.
+    savings_revenue: Balance,
+    /// NEAR revenue withdrawn to the treasury account.
+    withdrawn: Balance,
+    last_withdrawal: Timestamp,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RevenueView {
+    pub usn: U128,
+    pub near: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryView {
+    pub treasury_id: Option<AccountId>,
+    pub reserve: U128,
+    pub buy_revenue: RevenueView,
+    pub sell_revenue: RevenueView,
+    pub staking_revenue: U128,
+    pub savings_revenue: U128,
+    pub withdrawn: U128,
+    pub withdrawable: U128,
+    pub last_withdrawal: U64,
+}
+
+impl From<&Revenue> for RevenueView {
+    fn from(revenue: &Revenue) -> Self {
+        Self {
+            usn: revenue.usn.into(),
+            near: revenue.near.into(),
+        }
+    }
+}
+
+impl Treasury {
+    pub fn new(reserve: Balance) -> Self {
+        Self {
+            treasury_id: None,
+            reserve,
+            buy_revenue: Revenue::default(),
+            sell_revenue: Revenue::default(),
+            staking_revenue: 0,
+            savings_revenue: 0,
+            withdrawn: 0,
+            last_withdrawal: 0,
+        }
+    }
+
+    pub fn reserve(&self) -> Balance {
+        self.reserve
+    }
+
+    /// NEAR reserves converted from other assets.
+    pub fn add_reserve(&mut self, near: Balance) {
+        self.reserve += near;
+    }
+
+    /// NEAR reserves converted into other assets.
+    pub fn take_reserve(&mut self, near: Balance) {
+        self.reserve = self.reserve.saturating_sub(near);
+    }
+
+    /// Records the buy of `usn` tokens for `near`, `fee` tokens of which are the spread.
+    /// Returns the NEAR value of the spread.
+    pub fn record_buy(&mut self, near: Balance, usn: Balance, fee: Balance) -> Balance {
+        let fee_near = share(near, fee, usn);
+        self.reserve += near - fee_near;
+        self.buy_revenue.usn += fee;
+        self.buy_revenue.near += fee_near;
+        fee_near
+    }
+
+    /// Records the paid out sell of `usn` tokens for `near`, `fee` tokens of which are the spread.
+    /// Returns the NEAR value of the spread.
+    pub fn record_sell(&mut self, usn: Balance, near: Balance, fee: Balance) -> Balance {
+        // The payout is the NEAR value of the tokens without the spread.
+        let fee_near = share(near, fee, usn - fee);
+        self.reserve = self.reserve.saturating_sub(near + fee_near);
+        self.sell_revenue.usn += fee;
+        self.sell_revenue.near += fee_near;
+        fee_near
+    }
+
+    pub fn record_staking_rewards(&mut self, rewards: Balance) {
+        self.staking_revenue += rewards;
+    }
+
+    /// Moves NEAR revenue to the reserves backing USN streamed to the savings vault.
+    pub fn record_savings_revenue(&mut self, near: Balance) {
+        self.reserve += near;
+        self.savings_revenue += near;
+    }
+
+    fn revenue(&self) -> Balance {
+        self.buy_revenue.near + self.sell_revenue.near + self.staking_revenue - self.savings_revenue
+    }
+}
+
+/// Calculates `value * numerator / denominator`.
+fn share(value: Balance, numerator: Balance, denominator: Balance) -> Balance {
+    if denominator == 0 {
+        return 0;
+    }
+    (U256::from(value) * U256::from(numerator) / U256::from(denominator)).as_u128()
+}
+
+#[ext_contract(ext_treasury_self)]
+trait TreasuryHandler {
+    #[private]
+    fn handle_revenue_withdrawal(&mut self, amount: U128) -> U128;
+}
+
+trait TreasuryHandler {
+    fn handle_revenue_withdrawal(&mut self, amount: U128) -> U128;
+}
+
+#[near_bindgen]
+impl TreasuryHandler for Contract {
+    /// Makes the revenue withdrawable again, if the transfer has failed.
+    #[private]
+    fn handle_revenue_withdrawal(&mut self, amount: U128) -> U128 {
+        if is_promise_success() {
+            amount
+        } else {
+            self.treasury.withdrawn -= amount.0;
+            0.into()
+        }
+    }
+}
+
+impl Contract {
+    /// NEAR revenue, which can be withdrawn without touching the reserves.
+    fn withdrawable_revenue(&self) -> Balance {
+        let unspent = self.treasury.revenue() - self.treasury.withdrawn;
+        // Staked reserves are not in the account balance.
+        let liquid_reserve = self
+            .treasury
+            .reserve
+            .saturating_sub(self.staking.staked_reserve());
+        let free = self
+            .internal_available_near()
+            .saturating_sub(liquid_reserve);
+        std::cmp::min(unspent, free)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_treasury(&mut self, treasury_id: AccountId) {
+        self.assert_owner();
+        self.treasury.treasury_id = Some(treasury_id);
+    }
+
+    /// Transfers the collected spread revenue to the treasury account.
+    /// Can be called once a day.
+    pub fn withdraw_revenue(&mut self, amount: U128) -> Promise {
+        self.assert_owner();
+
+        let treasury_id = self
+            .treasury
+            .treasury_id
+            .clone()
+            .unwrap_or_else(|| env::panic_str("The treasury account is not set"));
+
+        require!(
+            env::block_timestamp() >= self.treasury.last_withdrawal + REVENUE_WITHDRAWAL_INTERVAL,
+            "The revenue can be withdrawn once a day"
+        );
+        require!(amount.0 > 0, "The amount should be a positive number");
+
+        let withdrawable = self.withdrawable_revenue();
+        if amount.0 > withdrawable {
+            env::panic_str(&format!(
+                "Not enough revenue: {} is available",
+                withdrawable
+            ));
+        }
+
+        self.treasury.withdrawn += amount.0;
+        self.treasury.last_withdrawal = env::block_timestamp();
+
+        Promise::new(treasury_id).transfer(amount.0).then(
+            ext_treasury_self::handle_revenue_withdrawal(
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_REVENUE_WITHDRAWAL,
+            ),
+        )
+    }
+
+    pub fn treasury(&self) -> TreasuryView {
+        TreasuryView {
+            treasury_id: self.treasury.treasury_id.clone(),
+            reserve: self.treasury.reserve.into(),
+            buy_revenue: (&self.treasury.buy_revenue).into(),
+            sell_revenue: (&self.treasury.sell_revenue).into(),
+            staking_revenue: self.treasury.staking_revenue.into(),
+            savings_revenue: self.treasury.savings_revenue.into(),
+            withdrawn: self.treasury.withdrawn.into(),
+            withdrawable: self.withdrawable_revenue().into(),
+            last_withdrawal: self.treasury.last_withdrawal.into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    #[test]
+    fn test_revenue_accounting() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(10000.into()); // 1%
+
+        let rate = ExchangeRate::test_fresh_rate();
+
+        // 100 NEAR = $1114.39: $11.1439 (1 NEAR) is the spread.
+        let usn = contract.finish_buy(accounts(2), accounts(2), 100 * ONE_NEAR, None, rate.clone());
+        let treasury = contract.treasury();
+        assert_eq!(treasury.reserve.0, 99 * ONE_NEAR);
+        assert_eq!(treasury.buy_revenue.usn.0, 11143900000000000000);
+        assert_eq!(treasury.buy_revenue.near.0, ONE_NEAR);
+
+        // Sell all: 0.99 NEAR is the spread.
+        let (deposit, fee) = contract.finish_sell(accounts(2), usn, None, rate);
+        assert_eq!(deposit, 9801 * ONE_NEAR / 100);
+        contract.treasury.record_sell(usn, deposit, fee);
+
+        let treasury = contract.treasury();
+        assert_eq!(treasury.reserve.0, 0);
+        assert_eq!(treasury.sell_revenue.usn.0, 11032461000000000000);
+        assert_eq!(treasury.sell_revenue.near.0, 99 * ONE_NEAR / 100);
+    }
+
+    #[test]
+    fn test_withdraw_revenue() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context
+            .block_timestamp(REVENUE_WITHDRAWAL_INTERVAL)
+            .account_balance(100 * ONE_NEAR)
+            .build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(10000.into());
+        contract.set_treasury(accounts(3));
+
+        let rate = ExchangeRate::test_fresh_rate();
+        contract.finish_buy(accounts(2), accounts(2), 100 * ONE_NEAR, None, rate);
+
+        // 99 NEAR of reserves and the storage staking leave nothing to withdraw.
+        assert_eq!(contract.treasury().withdrawable.0, 0);
+
+        testing_env!(context.account_balance(200 * ONE_NEAR).build());
+        assert_eq!(contract.treasury().withdrawable.0, ONE_NEAR);
+
+        contract.withdraw_revenue((ONE_NEAR / 2).into());
+        assert_eq!(contract.treasury().withdrawable.0, ONE_NEAR / 2);
+
+        // Too early for the next withdrawal.
+        let result = std::panic::catch_unwind(move || {
+            let mut contract = contract;
+            contract.withdraw_revenue((ONE_NEAR / 2).into());
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_failed_revenue_withdrawal() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context
+            .block_timestamp(REVENUE_WITHDRAWAL_INTERVAL)
+            .account_balance(200 * ONE_NEAR)
+            .build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_fixed_spread(10000.into());
+        contract.set_treasury(accounts(3));
+
+        let rate = ExchangeRate::test_fresh_rate();
+        contract.finish_buy(accounts(2), accounts(2), 100 * ONE_NEAR, None, rate);
+        contract.withdraw_revenue(ONE_NEAR.into());
+        assert_eq!(contract.treasury().withdrawable.0, 0);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert_eq!(contract.handle_revenue_withdrawal(ONE_NEAR.into()).0, 0);
+        assert_eq!(contract.treasury().withdrawable.0, ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough revenue")]
+    fn test_cannot_withdraw_reserves() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.block_timestamp(REVENUE_WITHDRAWAL_INTERVAL).build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_treasury(accounts(3));
+
+        contract.withdraw_revenue(ONE_NEAR.into());
+    }
+}
@@ -0,0 +1,815 @@
+//! Over-collateralized NEAR vaults.
+//!
+//! An account locks NEAR in its vault and borrows USN up to the minimum collateral ratio at the oracle price.
+//! The debt grows with the stability fee, which accrues every second. Repaying burns the borrowed USN,
+//! and the fee part goes to the contract account. A vault below the liquidation ratio can be liquidated
+//! by anyone, who repays the whole debt and gets its NEAR value plus the discount from the collateral.
+//! The global settlement settles debts of all vaults at the frozen price: the NEAR value of the debts
+//! joins the settled reserves, and owners withdraw the rest of the collateral.
+
+use near_sdk::collections::LookupMap;
+use near_sdk::{require, IntoStorageKey, Timestamp};
+
+use crate::supply::MintSource;
+use crate::*;
+
+const GAS_FOR_VAULT_CALLBACK: Gas = Gas(30_000_000_000_000);
+
+/// Ratios, the discount and the fee have 6 decimals: 1_000_000 is 100%.
+const VAULT_RATIO_DECIMAL: u8 = 6;
+/// The debt index has 18 decimals.
+const DEBT_INDEX_DECIMAL: u8 = 18;
+const YEAR: Timestamp = 365 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Vault parameters: `min_ratio` to borrow, `liquidation_ratio`, `liquidation_discount`
+/// and the annual `stability_fee` have 6 decimals. `debt_ceiling` limits the debt of all vaults.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VaultParams {
+    pub min_ratio: U128,
+    pub liquidation_ratio: U128,
+    pub liquidation_discount: U128,
+    pub stability_fee: U128,
+    pub debt_ceiling: U128,
+}
+
+impl Default for VaultParams {
+    fn default() -> Self {
+        Self {
+            min_ratio: U128(1_500_000),         // 150%
+            liquidation_ratio: U128(1_300_000), // 130%
+            liquidation_discount: U128(50_000), // 5%
+            stability_fee: U128(20_000),        // 2% a year
+            debt_ceiling: U128(0),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Vault {
+    /// Locked NEAR.
+    collateral: Balance,
+    /// Borrowed USN without the fee.
+    principal: Balance,
+    /// The debt divided by the debt index.
+    shares: Balance,
+    /// The debt has been settled by the global settlement.
+    settled: bool,
+}
+
+/// Vaults at the global settlement.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct VaultSettlement {
+    /// The frozen oracle rate.
+    rate: ExchangeRate,
+    /// Collateral left after the settlement, which hasn't been assigned to settled vaults yet.
+    unassigned: Balance,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Vaults {
+    params: VaultParams,
+    /// The debt of a share, which grows with the stability fee.
+    debt_index: Balance,
+    accrued_at: Timestamp,
+    total_collateral: Balance,
+    total_principal: Balance,
+    total_shares: Balance,
+    /// Stability fees collected at the contract account.
+    collected: Balance,
+    vaults: LookupMap<AccountId, Vault>,
+    settlement: Option<VaultSettlement>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VaultView {
+    pub collateral: U128,
+    pub principal: U128,
+    /// The principal plus the accrued stability fee.
+    pub debt: U128,
+    /// The collateral ratio at the cached oracle price.
+    pub collateral_ratio: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VaultsView {
+    pub params: VaultParams,
+    pub total_collateral: U128,
+    pub total_debt: U128,
+    pub collected: U128,
+}
+
+impl Vaults {
+    pub fn new<S>(prefix: S) -> Self
+    where
+        S: IntoStorageKey,
+    {
+        Self {
+            params: VaultParams::default(),
+            debt_index: 10u128.pow(DEBT_INDEX_DECIMAL as u32),
+            accrued_at: env::block_timestamp(),
+            total_collateral: 0,
+            total_principal: 0,
+            total_shares: 0,
+            collected: 0,
+            vaults: LookupMap::new(prefix),
+            settlement: None,
+        }
+    }
+
+    pub fn total_collateral(&self) -> Balance {
+        self.total_collateral
+    }
+
+    /// The debt index grown by the stability fee up to now. The settlement stops the growth.
+    fn current_index(&self) -> Balance {
+        if self.settlement.is_some() {
+            return self.debt_index;
+        }
+        let elapsed = env::block_timestamp().saturating_sub(self.accrued_at);
+        let growth = U256::from(self.debt_index)
+            * U256::from(self.params.stability_fee.0)
+            * U256::from(elapsed)
+            / U256::from(10u128.pow(VAULT_RATIO_DECIMAL as u32))
+            / U256::from(YEAR);
+        self.debt_index + growth.as_u128()
+    }
+
+    fn accrue(&mut self) {
+        self.debt_index = self.current_index();
+        self.accrued_at = env::block_timestamp();
+    }
+
+    fn debt_of(&self, shares: Balance, index: Balance) -> Balance {
+        (U256::from(shares) * U256::from(index) / U256::from(10u128.pow(DEBT_INDEX_DECIMAL as u32)))
+            .as_u128()
+    }
+
+    fn shares_of(&self, debt: Balance) -> Balance {
+        (U256::from(debt) * U256::from(10u128.pow(DEBT_INDEX_DECIMAL as u32))
+            / U256::from(self.debt_index))
+        .as_u128()
+    }
+
+    fn total_debt(&self) -> Balance {
+        self.debt_of(self.total_shares, self.current_index())
+    }
+
+    fn get(&self, account_id: &AccountId) -> Vault {
+        self.vaults
+            .get(account_id)
+            .unwrap_or_else(|| env::panic_str("The vault doesn't exist"))
+    }
+}
+
+/// USN value of NEAR at the rate, the same way as `buy` does.
+fn near_value(near: Balance, rate: &ExchangeRate) -> Balance {
+    (U256::from(near) * U256::from(rate.multiplier())
+        / U256::from(10u128.pow(u32::from(rate.decimals() - TOKEN_DECIMAL))))
+    .as_u128()
+}
+
+/// NEAR amount of USN value at the rate.
+fn near_amount(usn: Balance, rate: &ExchangeRate) -> Balance {
+    (U256::from(usn) * U256::from(10u128.pow(u32::from(rate.decimals() - TOKEN_DECIMAL)))
+        / U256::from(rate.multiplier()))
+    .as_u128()
+}
+
+/// Collateral value divided by the debt with 6 decimals, `None` without debt.
+fn collateral_ratio(collateral: Balance, debt: Balance, rate: &ExchangeRate) -> Option<u128> {
+    if debt == 0 {
+        return None;
+    }
+    let ratio = U256::from(near_value(collateral, rate))
+        * U256::from(10u128.pow(VAULT_RATIO_DECIMAL as u32))
+        / U256::from(debt);
+    Some(ratio.as_u128())
+}
+
+#[ext_contract(ext_vault_self)]
+trait VaultHandler {
+    #[private]
+    fn handle_vault_borrow(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        #[callback] price: PriceData,
+    ) -> U128;
+
+    #[private]
+    fn handle_vault_withdraw(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        #[callback] price: PriceData,
+    ) -> Promise;
+
+    #[private]
+    fn handle_vault_liquidation(
+        &mut self,
+        keeper: AccountId,
+        account: AccountId,
+        #[callback] price: PriceData,
+    ) -> Promise;
+}
+
+trait VaultHandler {
+    fn handle_vault_borrow(&mut self, account: AccountId, amount: U128, price: PriceData) -> U128;
+
+    fn handle_vault_withdraw(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        price: PriceData,
+    ) -> Promise;
+
+    fn handle_vault_liquidation(
+        &mut self,
+        keeper: AccountId,
+        account: AccountId,
+        price: PriceData,
+    ) -> Promise;
+}
+
+#[near_bindgen]
+impl VaultHandler for Contract {
+    #[private]
+    fn handle_vault_borrow(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        #[callback] price: PriceData,
+    ) -> U128 {
+        self.abort_if_settled();
+        let rate: ExchangeRate = price.into();
+        self.oracle.last_report = Some(rate.clone());
+        self.finish_vault_borrow(&account, amount.0, &rate).into()
+    }
+
+    #[private]
+    fn handle_vault_withdraw(
+        &mut self,
+        account: AccountId,
+        amount: U128,
+        #[callback] price: PriceData,
+    ) -> Promise {
+        self.abort_if_settled();
+        let rate: ExchangeRate = price.into();
+        self.oracle.last_report = Some(rate.clone());
+        self.finish_vault_withdraw(&account, amount.0, Some(&rate))
+    }
+
+    #[private]
+    fn handle_vault_liquidation(
+        &mut self,
+        keeper: AccountId,
+        account: AccountId,
+        #[callback] price: PriceData,
+    ) -> Promise {
+        self.abort_if_settled();
+        let rate: ExchangeRate = price.into();
+        self.oracle.last_report = Some(rate.clone());
+        self.finish_vault_liquidation(&keeper, &account, &rate)
+    }
+}
+
+impl Contract {
+    /// USN value of the vault collateral backing USN: the collateral at the cached oracle price,
+    /// but not more than the debt of vaults.
+    pub(crate) fn vault_collateral_value(&self) -> Option<Balance> {
+        let rate = self.oracle.last_report.as_ref()?;
+        let value = near_value(self.vaults.total_collateral, rate);
+        Some(std::cmp::min(value, self.vaults.total_debt()))
+    }
+
+    /// Settles debts of all vaults at the frozen rate: the NEAR value of the debts, up to the collateral,
+    /// moves to the NEAR reserve. Returns the moved amount.
+    pub(crate) fn internal_settle_vaults(&mut self, rate: &ExchangeRate) -> Balance {
+        self.vaults.accrue();
+        let settled = std::cmp::min(
+            self.vaults.total_collateral,
+            near_amount(self.vaults.total_debt(), rate),
+        );
+
+        self.vaults.total_collateral -= settled;
+        self.treasury.add_reserve(settled);
+        self.vaults.settlement = Some(VaultSettlement {
+            rate: rate.clone(),
+            unassigned: self.vaults.total_collateral,
+        });
+        settled
+    }
+
+    /// Settles the vault debt after the global settlement. The owner keeps the rest of the collateral,
+    /// unless debts of undercollateralized vaults have taken it.
+    fn internal_settle_vault(&mut self, account: &AccountId) {
+        let vault = self.vaults.get(account);
+        if vault.settled {
+            return;
+        }
+        let debt = self.vaults.debt_of(vault.shares, self.vaults.debt_index);
+        let settlement = self
+            .vaults
+            .settlement
+            .as_mut()
+            .unwrap_or_else(|| env::panic_str("The contract is not settled"));
+
+        let rest = vault
+            .collateral
+            .saturating_sub(near_amount(debt, &settlement.rate));
+        let rest = std::cmp::min(rest, settlement.unassigned);
+        settlement.unassigned -= rest;
+
+        self.vaults.total_principal -= vault.principal;
+        self.vaults.total_shares -= vault.shares;
+        self.vaults.vaults.insert(
+            account,
+            &Vault {
+                collateral: rest,
+                principal: 0,
+                shares: 0,
+                settled: true,
+            },
+        );
+    }
+
+    /// Mints USN against the vault collateral. Returns the borrowed amount.
+    fn finish_vault_borrow(
+        &mut self,
+        account: &AccountId,
+        amount: Balance,
+        rate: &ExchangeRate,
+    ) -> Balance {
+        self.vaults.accrue();
+        let mut vault = self.vaults.get(account);
+
+        let shares = self.vaults.shares_of(amount);
+        vault.principal += amount;
+        vault.shares += shares;
+        self.vaults.total_principal += amount;
+        self.vaults.total_shares += shares;
+
+        let debt = self.vaults.debt_of(vault.shares, self.vaults.debt_index);
+        require!(
+            collateral_ratio(vault.collateral, debt, rate).unwrap_or(0)
+                >= self.vaults.params.min_ratio.0,
+            "The vault collateral ratio is below the minimum"
+        );
+        require!(
+            self.vaults.total_debt() <= self.vaults.params.debt_ceiling.0,
+            "The vault debt ceiling is reached"
+        );
+
+        self.vaults.vaults.insert(account, &vault);
+        self.internal_mint(account, amount, MintSource::Vault, Some("Vault borrow"));
+        amount
+    }
+
+    /// Releases the vault collateral. The rate is required if the vault has a debt.
+    fn finish_vault_withdraw(
+        &mut self,
+        account: &AccountId,
+        amount: Balance,
+        rate: Option<&ExchangeRate>,
+    ) -> Promise {
+        self.vaults.accrue();
+        let mut vault = self.vaults.get(account);
+
+        vault.collateral = vault
+            .collateral
+            .checked_sub(amount)
+            .unwrap_or_else(|| env::panic_str("Not enough collateral in the vault"));
+
+        if vault.shares > 0 {
+            let rate = rate.unwrap_or_else(|| env::panic_str("The vault has a debt"));
+            let debt = self.vaults.debt_of(vault.shares, self.vaults.debt_index);
+            require!(
+                collateral_ratio(vault.collateral, debt, rate).unwrap_or(0)
+                    >= self.vaults.params.min_ratio.0,
+                "The vault collateral ratio is below the minimum"
+            );
+        }
+
+        self.vaults.total_collateral -= amount;
+        self.vaults.vaults.insert(account, &vault);
+        Promise::new(account.clone())
+            .transfer(amount)
+            .then(ext_self::return_value(
+                account.clone(),
+                amount.into(),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RETURN_VALUE_PROMISE,
+            ))
+    }
+
+    /// Repays the whole debt of an unsafe vault by the keeper, and transfers the seized collateral.
+    fn finish_vault_liquidation(
+        &mut self,
+        keeper: &AccountId,
+        account: &AccountId,
+        rate: &ExchangeRate,
+    ) -> Promise {
+        self.vaults.accrue();
+        let mut vault = self.vaults.get(account);
+        let debt = self.vaults.debt_of(vault.shares, self.vaults.debt_index);
+
+        require!(
+            matches!(collateral_ratio(vault.collateral, debt, rate), Some(ratio) if ratio < self.vaults.params.liquidation_ratio.0),
+            "The vault can't be liquidated"
+        );
+
+        self.internal_repay_vault(keeper, &mut vault, debt);
+
+        let discount = U256::from(near_amount(debt, rate))
+            * U256::from(self.vaults.params.liquidation_discount.0)
+            / U256::from(10u128.pow(VAULT_RATIO_DECIMAL as u32));
+        let seized = std::cmp::min(
+            vault.collateral,
+            near_amount(debt, rate) + discount.as_u128(),
+        );
+
+        vault.collateral -= seized;
+        self.vaults.total_collateral -= seized;
+        self.vaults.vaults.insert(account, &vault);
+
+        event::emit::vault_liquidated(account, keeper, debt, seized);
+
+        Promise::new(keeper.clone())
+            .transfer(seized)
+            .then(ext_self::return_value(
+                keeper.clone(),
+                seized.into(),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RETURN_VALUE_PROMISE,
+            ))
+    }
+
+    /// Takes USN from the payer: the accrued fee goes to the contract account, the principal is burned.
+    /// The vault debt index must be accrued.
+    fn internal_repay_vault(&mut self, payer: &AccountId, vault: &mut Vault, amount: Balance) {
+        let debt = self.vaults.debt_of(vault.shares, self.vaults.debt_index);
+        let amount = std::cmp::min(amount, debt);
+        require!(amount > 0, "The vault has no debt");
+
+        let fee = std::cmp::min(debt.saturating_sub(vault.principal), amount);
+        let burned = amount - fee;
+
+        let shares = if amount == debt {
+            vault.shares
+        } else {
+            std::cmp::min(self.vaults.shares_of(amount), vault.shares)
+        };
+
+        vault.shares -= shares;
+        vault.principal -= burned;
+        self.vaults.total_shares -= shares;
+        self.vaults.total_principal -= burned;
+
+        if fee > 0 {
+            self.token.internal_transfer(
+                payer,
+                &env::current_account_id(),
+                fee,
+                Some("Vault stability fee".to_string()),
+            );
+            self.vaults.collected += fee;
+        }
+        if burned > 0 {
+            self.token.internal_withdraw(payer, burned);
+            self.record_burn(MintSource::Vault, burned);
+            event::emit::ft_burn(payer, burned, Some("Vault repay"));
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Locks the attached NEAR in the vault of the caller.
+    #[payable]
+    pub fn vault_deposit(&mut self) -> U128 {
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        let amount = env::attached_deposit();
+        require!(
+            amount > 0,
+            "Requires attached deposit of at least 1 yoctoNEAR"
+        );
+
+        let account = env::predecessor_account_id();
+        let mut vault = self.vaults.vaults.get(&account).unwrap_or_default();
+        vault.collateral += amount;
+        self.vaults.total_collateral += amount;
+        self.vaults.vaults.insert(&account, &vault);
+
+        vault.collateral.into()
+    }
+
+    /// Borrows USN against the vault collateral at a fresh oracle price.
+    #[payable]
+    pub fn vault_borrow(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        let account = env::predecessor_account_id();
+        self.vaults.get(&account);
+
+        self.oracle
+            .get_exchange_rate_promise()
+            .then(ext_vault_self::handle_vault_borrow(
+                account,
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_VAULT_CALLBACK,
+            ))
+    }
+
+    /// Repays the vault debt of the caller, the fee first. Returns the remaining debt.
+    #[payable]
+    pub fn vault_repay(&mut self, amount: U128) -> U128 {
+        assert_one_yocto();
+        self.abort_if_pause();
+
+        let account = env::predecessor_account_id();
+        self.vaults.accrue();
+        let mut vault = self.vaults.get(&account);
+
+        self.internal_repay_vault(&account, &mut vault, amount.0);
+        self.vaults.vaults.insert(&account, &vault);
+
+        self.vaults
+            .debt_of(vault.shares, self.vaults.debt_index)
+            .into()
+    }
+
+    /// Withdraws NEAR from the vault of the caller. With a debt, the vault must stay above the minimum ratio
+    /// at a fresh oracle price. After the global settlement, the rest of the collateral can be withdrawn.
+    #[payable]
+    pub fn vault_withdraw(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        self.abort_if_maintenance();
+        self.abort_if_blacklisted();
+
+        let account = env::predecessor_account_id();
+        if self.status == ContractStatus::Settled {
+            self.internal_settle_vault(&account);
+            return self.finish_vault_withdraw(&account, amount.0, None);
+        }
+        if self.vaults.get(&account).shares == 0 {
+            return self.finish_vault_withdraw(&account, amount.0, None);
+        }
+
+        self.oracle
+            .get_exchange_rate_promise()
+            .then(ext_vault_self::handle_vault_withdraw(
+                account,
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_VAULT_CALLBACK,
+            ))
+    }
+
+    /// Liquidates the vault below the liquidation ratio. The caller repays the whole debt
+    /// and gets the NEAR value of it plus the discount.
+    #[payable]
+    pub fn liquidate_vault(&mut self, account_id: AccountId) -> Promise {
+        assert_one_yocto();
+        self.abort_if_pause();
+        self.abort_if_blacklisted();
+
+        self.vaults.get(&account_id);
+
+        self.oracle
+            .get_exchange_rate_promise()
+            .then(ext_vault_self::handle_vault_liquidation(
+                env::predecessor_account_id(),
+                account_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_VAULT_CALLBACK,
+            ))
+    }
+
+    pub fn set_vault_params(&mut self, params: VaultParams) {
+        self.assert_owner();
+
+        let one = 10u128.pow(VAULT_RATIO_DECIMAL as u32);
+        require!(
+            params.liquidation_ratio.0 > one && params.liquidation_ratio.0 <= params.min_ratio.0,
+            "The liquidation ratio should be above 100% and not above the minimum ratio"
+        );
+        require!(
+            params.liquidation_discount.0 < one,
+            "The liquidation discount should be below 100%"
+        );
+
+        self.vaults.accrue();
+        self.vaults.params = params;
+    }
+
+    pub fn vault(&self, account_id: AccountId) -> Option<VaultView> {
+        self.vaults.vaults.get(&account_id).map(|vault| {
+            let debt = self
+                .vaults
+                .debt_of(vault.shares, self.vaults.current_index());
+            VaultView {
+                collateral: vault.collateral.into(),
+                principal: vault.principal.into(),
+                debt: debt.into(),
+                collateral_ratio: self
+                    .oracle
+                    .last_report
+                    .as_ref()
+                    .and_then(|rate| collateral_ratio(vault.collateral, debt, rate))
+                    .map(U128),
+            }
+        })
+    }
+
+    pub fn vaults(&self) -> VaultsView {
+        VaultsView {
+            params: self.vaults.params.clone(),
+            total_collateral: self.vaults.total_collateral.into(),
+            total_debt: self.vaults.total_debt().into(),
+            collected: self.vaults.collected.into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_NEAR, ONE_YOCTO};
+
+    use super::*;
+
+    const ONE_USN: Balance = 1_000_000_000_000_000_000;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    /// A vault of accounts(2) with 100 NEAR ($1114.39) of collateral.
+    fn setup() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_vault_params(VaultParams {
+            debt_ceiling: U128(10_000 * ONE_USN),
+            ..VaultParams::default()
+        });
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(100 * ONE_NEAR)
+            .build());
+        contract.vault_deposit();
+        (context, contract)
+    }
+
+    #[test]
+    fn test_vault_borrow_repay() {
+        let (mut context, mut contract) = setup();
+        let rate = ExchangeRate::test_fresh_rate();
+
+        contract.finish_vault_borrow(&accounts(2), 700 * ONE_USN, &rate);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 700 * ONE_USN);
+        assert_eq!(contract.supply_caps().sources[5].minted.0, 700 * ONE_USN);
+
+        // 2% a year accrues 14 USN.
+        testing_env!(context
+            .block_timestamp(YEAR)
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        let vault = contract.vault(accounts(2)).unwrap();
+        assert_eq!(vault.debt.0, 714 * ONE_USN);
+        assert_eq!(vault.principal.0, 700 * ONE_USN);
+
+        contract.token.internal_deposit(&accounts(2), 14 * ONE_USN);
+        assert_eq!(
+            contract.vault_repay((100 * ONE_USN).into()).0,
+            614 * ONE_USN
+        );
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 14 * ONE_USN);
+        assert_eq!(
+            contract.vault(accounts(2)).unwrap().principal.0,
+            614 * ONE_USN
+        );
+
+        assert_eq!(contract.vault_repay((1000 * ONE_USN).into()).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert_eq!(contract.vaults().collected.0, 14 * ONE_USN);
+
+        contract.vault_withdraw((100 * ONE_NEAR).into());
+        assert_eq!(contract.vaults().total_collateral.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The vault collateral ratio is below the minimum")]
+    fn test_vault_borrow_over_ratio() {
+        let (_, mut contract) = setup();
+        let rate = ExchangeRate::test_fresh_rate();
+
+        // $1114.39 / 150% = $742.92
+        contract.finish_vault_borrow(&accounts(2), 743 * ONE_USN, &rate);
+    }
+
+    #[test]
+    fn test_vault_liquidation() {
+        let (_, mut contract) = setup();
+
+        contract.finish_vault_borrow(
+            &accounts(2),
+            700 * ONE_USN,
+            &ExchangeRate::test_fresh_rate(),
+        );
+
+        // NEAR falls to $9: 100 NEAR back $900, which is below 130%.
+        let rate = ExchangeRate::test_rate(90000, 28);
+        contract.token.internal_deposit(&accounts(3), 700 * ONE_USN);
+        contract.finish_vault_liquidation(&accounts(3), &accounts(2), &rate);
+
+        // 77.78 NEAR of the debt plus 5%.
+        let vault = contract.vault(accounts(2)).unwrap();
+        assert_eq!(vault.debt.0, 0);
+        assert_eq!(vault.collateral.0, 18333333333333333333333335);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 0);
+        assert_eq!(contract.ft_total_supply().0, 700 * ONE_USN);
+    }
+
+    #[test]
+    #[should_panic(expected = "The vault can't be liquidated")]
+    fn test_vault_safe_liquidation() {
+        let (_, mut contract) = setup();
+        let rate = ExchangeRate::test_fresh_rate();
+
+        contract.finish_vault_borrow(&accounts(2), 700 * ONE_USN, &rate);
+        contract.finish_vault_liquidation(&accounts(3), &accounts(2), &rate);
+    }
+
+    #[test]
+    fn test_vault_settlement() {
+        let (mut context, mut contract) = setup();
+        let rate = ExchangeRate::test_fresh_rate();
+        contract.finish_vault_borrow(&accounts(2), 700 * ONE_USN, &rate);
+        contract.oracle.last_report = Some(rate.clone());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.settle();
+
+        // The NEAR value of the debt backs the vault USN.
+        let settled = near_amount(700 * ONE_USN, &rate);
+        assert_eq!(contract.settlement().settled.unwrap().near.0, settled);
+
+        // The debt doesn't grow anymore, and the owner withdraws the rest of the collateral.
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(YEAR)
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        assert_eq!(contract.vault(accounts(2)).unwrap().debt.0, 700 * ONE_USN);
+        contract.vault_withdraw((100 * ONE_NEAR - settled).into());
+
+        let vault = contract.vault(accounts(2)).unwrap();
+        assert_eq!(vault.collateral.0, 0);
+        assert_eq!(vault.debt.0, 0);
+        assert_eq!(contract.vaults().total_collateral.0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 700 * ONE_USN);
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract is settled")]
+    fn test_vault_borrow_after_settlement() {
+        let (mut context, mut contract) = setup();
+        contract.oracle.last_report = Some(ExchangeRate::test_fresh_rate());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        contract.settle();
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.handle_vault_borrow(
+            accounts(2),
+            (100 * ONE_USN).into(),
+            PriceData::test_price_data(111439, 28),
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Spread component driven by the realized NEAR/USD volatility.
+//!
+//! Every exchange rate used by `buy` and `sell` is recorded. The relative move
+//! from the previous rate is added to the volatility index, which decays exponentially over time.
+//! The component widens the spread during turbulent markets, so a lagging oracle
+//! can't be used to drain the reserves.
+
+use near_sdk::{require, Timestamp};
+
+use crate::spread::EXP_PRECISION;
+use crate::*;
+
+const NANOS_IN_SECOND: u64 = 1_000_000_000;
+
+/// Parameters of the volatility spread: `floor`, `cap` and `multiplier` have `SPREAD_DECIMAL` decimals,
+/// `decay` is the time in seconds, during which the index decreases by e (~2.718) times.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VolatilitySpreadParams {
+    pub floor: U128,
+    pub cap: U128,
+    pub multiplier: U128,
+    pub decay: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Volatility {
+    params: Option<VolatilitySpreadParams>,
+    /// The last recorded rate: multiplier and decimals.
+    last_rate: Option<(u128, u8)>,
+    /// Decaying sum of relative rate moves with `SPREAD_DECIMAL` decimals.
+    index: Balance,
+    updated_at: Timestamp,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VolatilityView {
+    pub params: Option<VolatilitySpreadParams>,
+    pub index: U128,
+    pub spread: U128,
+}
+
+impl Default for Volatility {
+    fn default() -> Self {
+        Self {
+            params: None,
+            last_rate: None,
+            index: 0,
+            updated_at: 0,
+        }
+    }
+}
+
+impl Volatility {
+    /// Records the exchange rate, adding its relative move to the index.
+    /// The index is tracked only while the volatility spread is enabled.
+    pub fn record(&mut self, rate: &ExchangeRate) {
+        self.index = match (&self.params, self.last_rate) {
+            (Some(_), Some(last_rate)) => {
+                let change = relative_change(last_rate, (rate.multiplier(), rate.decimals()));
+                self.index_at(env::block_timestamp()).saturating_add(change)
+            }
+            _ => 0,
+        };
+        self.last_rate = Some((rate.multiplier(), rate.decimals()));
+        self.updated_at = env::block_timestamp();
+    }
+
+    /// Returns the volatility spread component, which is 0 if it's disabled.
+    pub fn spread(&self) -> Balance {
+        match &self.params {
+            None => 0,
+            Some(params) => {
+                let index = U256::from(self.index_at(env::block_timestamp()));
+                let spread = index * U256::from(params.multiplier.0)
+                    / U256::from(10u128.pow(SPREAD_DECIMAL as u32));
+                let spread = std::cmp::min(spread, U256::from(params.cap.0)).as_u128();
+                std::cmp::max(spread, params.floor.0)
+            }
+        }
+    }
+
+    /// The index decayed to the given time.
+    fn index_at(&self, timestamp: Timestamp) -> Balance {
+        let decay = match &self.params {
+            Some(params) => params.decay.0,
+            None => return 0,
+        };
+
+        let elapsed = timestamp.saturating_sub(self.updated_at);
+        let exponent = U256::from(elapsed) * U256::from(EXP_PRECISION)
+            / (U256::from(decay) * U256::from(NANOS_IN_SECOND));
+        let factor = spread::exp_neg(exponent);
+        (U256::from(self.index) * factor / U256::from(EXP_PRECISION)).as_u128()
+    }
+}
+
+/// Relative change between two rates with `SPREAD_DECIMAL` decimals.
+fn relative_change(prev: (u128, u8), next: (u128, u8)) -> Balance {
+    // Bring both rates to the same decimals: m0 * 10^d1 vs m1 * 10^d0.
+    let prev_value = U256::from(prev.0) * U256::from(10u128.pow(u32::from(next.1)));
+    let next_value = U256::from(next.0) * U256::from(10u128.pow(u32::from(prev.1)));
+
+    if prev_value.is_zero() {
+        return 0;
+    }
+
+    let diff = if next_value > prev_value {
+        next_value - prev_value
+    } else {
+        prev_value - next_value
+    };
+
+    let change = diff * U256::from(10u128.pow(SPREAD_DECIMAL as u32)) / prev_value;
+    std::cmp::min(change, U256::from(u128::MAX)).as_u128()
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Enables the volatility spread component, which is added to the spread of any kind.
+    /// `None` disables it.
+    pub fn set_volatility_spread(&mut self, params: Option<VolatilitySpreadParams>) {
+        self.assert_owner();
+
+        if let Some(params) = &params {
+            require!(
+                params.floor.0 <= params.cap.0,
+                "params.floor cannot be greater than params.cap"
+            );
+            if params.cap.0 > MAX_SPREAD {
+                env::panic_str(&format!("params.cap is greater than {}", MAX_SPREAD));
+            }
+            require!(params.decay.0 > 0, "params.decay must be positive");
+        }
+
+        // Apply the decay accumulated with the previous settings.
+        self.volatility.index = self.volatility.index_at(env::block_timestamp());
+        self.volatility.updated_at = env::block_timestamp();
+        self.volatility.params = params;
+    }
+
+    pub fn volatility_spread(&self) -> VolatilityView {
+        VolatilityView {
+            params: self.volatility.params.clone(),
+            index: self.volatility.index_at(env::block_timestamp()).into(),
+            spread: self.volatility.spread().into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn rate(multiplier: u128) -> ExchangeRate {
+        ExchangeRate::test_rate(multiplier, 28)
+    }
+
+    #[test]
+    fn test_relative_change() {
+        assert_eq!(relative_change((100000, 28), (101000, 28)), 10000);
+        assert_eq!(relative_change((100000, 28), (99000, 28)), 10000);
+        assert_eq!(relative_change((100000, 28), (1010000, 29)), 10000);
+        assert_eq!(relative_change((0, 28), (100000, 28)), 0);
+    }
+
+    #[test]
+    fn test_volatility_spread() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        contract.set_volatility_spread(Some(VolatilitySpreadParams {
+            floor: 100.into(),
+            cap: 40000.into(),
+            multiplier: 1_000_000.into(),
+            decay: 3600.into(),
+        }));
+        assert_eq!(contract.volatility_spread().spread.0, 100);
+
+        // +2%, -2%: the index is 4%.
+        contract.volatility.record(&rate(100000));
+        contract.volatility.record(&rate(102000));
+        contract.volatility.record(&rate(99960));
+        assert_eq!(contract.volatility_spread().index.0, 40000);
+        assert_eq!(contract.volatility_spread().spread.0, 40000);
+        assert_eq!(contract.spread(None, None).0, 5000 + 40000);
+
+        // The index decays by e times in an hour.
+        testing_env!(context.block_timestamp(3600 * NANOS_IN_SECOND).build());
+        assert_eq!(contract.volatility_spread().spread.0, 14715); // 40000 / e
+
+        contract.set_volatility_spread(Some(VolatilitySpreadParams {
+            floor: 100.into(),
+            cap: 5000.into(),
+            multiplier: 500_000.into(), // 0.5
+            decay: 3600.into(),
+        }));
+        assert_eq!(contract.volatility_spread().spread.0, 5000);
+        assert_eq!(contract.spread(None, None).0, 5000 + 5000);
+
+        contract.set_volatility_spread(None);
+        assert_eq!(contract.volatility_spread().index.0, 0);
+        assert_eq!(contract.spread(None, None).0, 5000);
+    }
+
+    #[test]
+    #[should_panic(expected = "params.floor cannot be greater than params.cap")]
+    fn test_volatility_spread_floor_gt_cap() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        contract.set_volatility_spread(Some(VolatilitySpreadParams {
+            floor: 2000.into(),
+            cap: 1000.into(),
+            multiplier: 1_000_000.into(),
+            decay: 3600.into(),
+        }));
+    }
+}
@@ -14,6 +14,8 @@ const config = {
   usdtPath: './tests/test_token.wasm',
   refPath: './tests/ref_exchange.wasm',
   priceoraclePath: './tests/price_oracle.wasm',
+  stakingPoolPath:
+    './tests/staking-pool/target/wasm32-unknown-unknown/release/staking_pool.wasm',
   priceoracleMultiplier: '111439',
   amount: new BN('300000000000000000000000000', 10), // 26 digits, 300 NEAR
   masterId: 'test.near',
@@ -21,6 +23,7 @@ const config = {
   usdtId: 'usdt.test.near',
   refId: 'ref.test.near',
   oracleId: 'priceoracle.test.near',
+  stakingPoolId: 'staking.test.near',
   aliceId: 'alice.test.near',
   bobId: 'bob.test.near',
 };
@@ -36,6 +39,47 @@ const usnMethods = {
     'owner',
     'ft_balance_of',
     'storage_balance_of',
+    'psm_asset',
+    'psm_assets',
+    'failed_payout',
+    'locked_balance',
+    'total_locked',
+    'operation',
+    'redemption',
+    'redemptions',
+    'redemption_queue',
+    'volatility_spread',
+    'effective_spread',
+    'fee_tier',
+    'volume_tiers',
+    'account_volume',
+    'treasury',
+    'collateral_ratio',
+    'collateral_policy',
+    'staking',
+    'reserves',
+    'reserves_report',
+    'supply_caps',
+    'minter_allowance',
+    'minters',
+    'flash_mint_policy',
+    'flash_mint_debt',
+    'flash_borrowers',
+    'vault',
+    'vaults',
+    'savings',
+    'savings_account',
+    'preview_savings_deposit',
+    'preview_savings_withdraw',
+    'preview_savings_redeem',
+    'peg_keeper',
+    'stable_pool_settings',
+    'stable_pool',
+    'stable_pools',
+    'liquidity_funds',
+    'settlement',
+    'settlement_payout',
+    'failed_settlement_payout',
   ],
   changeMethods: [
     'new',
@@ -47,6 +91,48 @@ const usnMethods = {
     'set_owner',
     'set_fixed_spread',
     'set_adaptive_spread',
+    'set_piecewise_spread',
+    'set_volatility_spread',
+    'set_fee_tier',
+    'set_volume_tiers',
+    'prune_volumes',
+    'set_treasury',
+    'withdraw_revenue',
+    'set_collateral_policy',
+    'set_max_supply',
+    'set_mint_cap',
+    'configure_minter',
+    'remove_minter',
+    'mint',
+    'burn',
+    'set_flash_mint_policy',
+    'add_flash_borrower',
+    'remove_flash_borrower',
+    'flash_mint',
+    'repay_flash_mint',
+    'set_vault_params',
+    'vault_deposit',
+    'vault_borrow',
+    'vault_repay',
+    'vault_withdraw',
+    'liquidate_vault',
+    'set_savings_policy',
+    'savings_deposit',
+    'savings_withdraw',
+    'savings_redeem',
+    'set_peg_keeper',
+    'update_peg',
+    'set_staking_pool',
+    'set_staking_policy',
+    'stake_reserves',
+    'unstake_reserves',
+    'withdraw_staked_reserves',
+    'harvest_staking_rewards',
+    'set_reserve_swap_pool',
+    'swap_near_to_usdt',
+    'swap_usdt_to_near',
+    'recover_reserves',
+    'set_wnear_address',
     'extend_guardians',
     'remove_guardians',
     'destroy_black_funds',
@@ -56,6 +142,26 @@ const usnMethods = {
     'sell',
     'ft_transfer',
     'transfer_stable_liquidity',
+    'withdraw_stable_liquidity',
+    'set_stable_pool_settings',
+    'add_stable_pool',
+    'remove_stable_pool',
+    'set_stable_pool_cap',
+    'psm_add_asset',
+    'psm_remove_asset',
+    'psm_set_fee',
+    'psm_set_debt_ceiling',
+    'psm_redeem',
+    'claim_failed_payout',
+    'top_up_reserve',
+    'process_redemptions',
+    'refresh_reserves_report',
+    'set_settlement_quorum',
+    'settle',
+    'vote_settlement',
+    'revoke_settlement_vote',
+    'redeem_settlement',
+    'claim_settlement_payout',
   ],
 };
 
@@ -65,7 +171,22 @@ const oracleMethods = {
 
 const usdtMethods = {
   viewMethods: ['ft_balance_of'],
-  changeMethods: ['new', 'mint', 'burn', 'ft_transfer'],
+  changeMethods: ['new', 'mint', 'burn', 'ft_transfer', 'ft_transfer_call'],
+};
+
+const stakingPoolMethods = {
+  viewMethods: [
+    'get_account_staked_balance',
+    'get_account_unstaked_balance',
+    'get_account_total_balance',
+  ],
+  changeMethods: [
+    'new',
+    'deposit_and_stake',
+    'unstake',
+    'withdraw',
+    'add_reward',
+  ],
 };
 
 const refMethods = {
@@ -109,12 +230,18 @@ async function sandboxSetup() {
   await masterAccount.createAccount(config.usdtId, pubKey, config.amount);
   await masterAccount.createAccount(config.refId, pubKey, config.amount);
   await masterAccount.createAccount(config.oracleId, pubKey, config.amount);
+  await masterAccount.createAccount(
+    config.stakingPoolId,
+    pubKey,
+    config.amount
+  );
   await masterAccount.createAccount(config.aliceId, pubKey, config.amount);
   await masterAccount.createAccount(config.bobId, pubKey, config.amount);
   keyStore.setKey(config.networkId, config.usnId, privKey);
   keyStore.setKey(config.networkId, config.usdtId, privKey);
   keyStore.setKey(config.networkId, config.refId, privKey);
   keyStore.setKey(config.networkId, config.oracleId, privKey);
+  keyStore.setKey(config.networkId, config.stakingPoolId, privKey);
   keyStore.setKey(config.networkId, config.aliceId, privKey);
   keyStore.setKey(config.networkId, config.bobId, privKey);
 
@@ -210,6 +337,22 @@ async function sandboxSetup() {
     },
   });
 
+  // Deploy the mock staking pool.
+  const wasmStakingPool = await fs.readFile(config.stakingPoolPath);
+  const stakingPoolAccount = new nearAPI.Account(
+    near.connection,
+    config.stakingPoolId
+  );
+  await stakingPoolAccount.deployContract(wasmStakingPool);
+
+  // Initialize the staking pool contract.
+  const stakingPoolContract = new nearAPI.Contract(
+    stakingPoolAccount,
+    config.stakingPoolId,
+    stakingPoolMethods
+  );
+  await stakingPoolContract.new({ args: {} });
+
   // Initialize other accounts connected to the contract for all test cases.
   const aliceAccount = new nearAPI.Account(near.connection, config.aliceId);
   const bobAccount = new nearAPI.Account(near.connection, config.bobId);
@@ -223,6 +366,11 @@ async function sandboxSetup() {
     config.usnId,
     usnMethods
   );
+  const aliceUsdt = new nearAPI.Contract(
+    aliceAccount,
+    config.usdtId,
+    usdtMethods
+  );
 
   // Setup a global test context.
   global.usnAccount = usnAccount;
@@ -230,9 +378,11 @@ async function sandboxSetup() {
   global.usdtContract = usdtContract;
   global.refContract = refContract;
   global.priceoracleContract = oracleContract;
+  global.stakingPoolContract = stakingPoolContract;
   global.aliceAccount = aliceAccount;
   global.aliceContract = aliceContract;
   global.bobContract = bobContract;
+  global.aliceUsdt = aliceUsdt;
 }
 
 async function sandboxTeardown() {
@@ -242,11 +392,16 @@ async function sandboxTeardown() {
   const bob = new nearAPI.Account(near.connection, config.bobId);
   const usn = new nearAPI.Account(near.connection, config.usnId);
   const oracle = new nearAPI.Account(near.connection, config.oracleId);
+  const stakingPool = new nearAPI.Account(
+    near.connection,
+    config.stakingPoolId
+  );
 
   await alice.deleteAccount(config.masterId);
   await bob.deleteAccount(config.masterId);
   await usn.deleteAccount(config.masterId);
   await oracle.deleteAccount(config.masterId);
+  await stakingPool.deleteAccount(config.masterId);
 }
 
 module.exports = { config, sandboxSetup, sandboxTeardown };
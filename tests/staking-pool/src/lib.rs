@@ -0,0 +1,90 @@
+//! Mock staking pool for sandbox tests.
+//!
+//! Implements the part of the `staking-pool` interface used by USN.
+//! Unstaked NEAR can be withdrawn right away, without waiting for 4 epochs,
+//! and `add_reward` increases the staked balance like epoch rewards do.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, require, AccountId, Balance, PanicOnDefault, Promise};
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Account {
+    staked: Balance,
+    unstaked: Balance,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    accounts: LookupMap<AccountId, Account>,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            accounts: LookupMap::new(b"a".to_vec()),
+        }
+    }
+
+    /// Stakes the attached NEAR.
+    #[payable]
+    pub fn deposit_and_stake(&mut self) {
+        let amount = env::attached_deposit();
+        require!(amount > 0, "Deposit must be positive");
+
+        let account_id = env::predecessor_account_id();
+        let mut account = self.internal_account(&account_id);
+        account.staked += amount;
+        self.accounts.insert(&account_id, &account);
+    }
+
+    pub fn unstake(&mut self, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        let mut account = self.internal_account(&account_id);
+        require!(amount.0 <= account.staked, "Not enough staked balance");
+        account.staked -= amount.0;
+        account.unstaked += amount.0;
+        self.accounts.insert(&account_id, &account);
+    }
+
+    pub fn withdraw(&mut self, amount: U128) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let mut account = self.internal_account(&account_id);
+        require!(amount.0 <= account.unstaked, "Not enough unstaked balance");
+        account.unstaked -= amount.0;
+        self.accounts.insert(&account_id, &account);
+
+        Promise::new(account_id).transfer(amount.0)
+    }
+
+    /// Adds the attached NEAR to the staked balance of the account as rewards.
+    #[payable]
+    pub fn add_reward(&mut self, account_id: AccountId) {
+        let mut account = self.internal_account(&account_id);
+        account.staked += env::attached_deposit();
+        self.accounts.insert(&account_id, &account);
+    }
+
+    pub fn get_account_staked_balance(&self, account_id: AccountId) -> U128 {
+        self.internal_account(&account_id).staked.into()
+    }
+
+    pub fn get_account_unstaked_balance(&self, account_id: AccountId) -> U128 {
+        self.internal_account(&account_id).unstaked.into()
+    }
+
+    pub fn get_account_total_balance(&self, account_id: AccountId) -> U128 {
+        let account = self.internal_account(&account_id);
+        (account.staked + account.unstaked).into()
+    }
+}
+
+impl Contract {
+    fn internal_account(&self, account_id: &AccountId) -> Account {
+        self.accounts.get(account_id).unwrap_or_default()
+    }
+}
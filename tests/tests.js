@@ -371,7 +371,7 @@ describe('Adaptive Spread', async function () {
 
   it('should be configurable', async () => {
     await global.usnContract.set_adaptive_spread({
-      args: { params: { min: 0.002, max: 0.006, scaler: 0.0001 } },
+      args: { params: { min: '2000', max: '6000', scaler: '100000000000000' } },
     });
 
     const amount = await global.aliceContract.buy({
@@ -386,35 +386,35 @@ describe('Adaptive Spread', async function () {
     // min <= max
     await assert.rejects(async () => {
       await global.usnContract.set_adaptive_spread({
-        args: { params: { min: 0.006, max: 0.002, scaler: 0.0001 } },
+        args: { params: { min: '6000', max: '2000', scaler: '100000000000000' } },
       });
     });
 
     // min < 0.05
     await assert.rejects(async () => {
       await global.usnContract.set_adaptive_spread({
-        args: { params: { min: 0.06, max: 0.01, scaler: 0.0001 } },
+        args: { params: { min: '60000', max: '10000', scaler: '100000000000000' } },
       });
     });
 
     // max < 0.05
     await assert.rejects(async () => {
       await global.usnContract.set_adaptive_spread({
-        args: { params: { min: 0.01, max: 0.06, scaler: 0.0001 } },
+        args: { params: { min: '10000', max: '60000', scaler: '100000000000000' } },
       });
     });
 
     // scaler < 0.4
     await assert.rejects(async () => {
       await global.usnContract.set_adaptive_spread({
-        args: { params: { min: 0.01, max: 0.03, scaler: 0.5 } },
+        args: { params: { min: '10000', max: '30000', scaler: '500000000000000000' } },
       });
     });
 
     // only positive
     await assert.rejects(async () => {
       await global.usnContract.set_adaptive_spread({
-        args: { params: { min: 0.001, max: 0.003, scaler: -0.4 } },
+        args: { params: { min: '1000', max: '3000', scaler: '-400000000000000000' } },
       });
     });
   });
@@ -452,6 +452,57 @@ describe('Fixed Spread', async function () {
   });
 });
 
+describe('Piecewise Spread', async function () {
+  this.timeout(15000);
+
+  before(async () => {
+    await global.usnContract.set_piecewise_spread({
+      args: {
+        buy: [{ amount: '0', spread: '10000' }], // 1%
+        sell: [{ amount: '0', spread: '20000' }], // 2%
+      },
+    });
+  });
+
+  it('should be different for buy and sell', async () => {
+    const buySpread = await global.aliceContract.spread({ side: 'Buy' });
+    const sellSpread = await global.aliceContract.spread({ side: 'Sell' });
+    assert.equal(buySpread, '10000');
+    assert.equal(sellSpread, '20000');
+  });
+
+  it('should be used to buy and sell USN', async () => {
+    const amount = await global.aliceContract.buy({
+      args: {},
+      amount: HUNDRED_NEARS,
+      gas: GAS_FOR_CALL,
+    });
+    assert.equal(amount, '1103246100000000000000'); // ~$1103
+
+    const near = await global.aliceContract.sell({
+      args: { amount },
+      amount: ONE_YOCTO,
+      gas: GAS_FOR_CALL,
+    });
+    assert.equal(near, '97020000000000000000000000'); // 97.02 NEAR
+  });
+
+  it('should be in limits', async () => {
+    await assert.rejects(async () => {
+      await global.usnContract.set_piecewise_spread({
+        args: {
+          buy: [{ amount: '0', spread: '60000' }],
+          sell: [{ amount: '0', spread: '10000' }],
+        },
+      });
+    });
+  });
+
+  after(async () => {
+    await global.usnContract.set_adaptive_spread({ args: {} });
+  });
+});
+
 describe('Stable Pool (USDT/USN)', async function () {
   this.timeout(17000);
 
@@ -477,31 +528,18 @@ describe('Stable Pool (USDT/USN)', async function () {
     });
   });
 
-  it('should finalize depositing after failure', async () => {
-    await global.usdtContract.ft_transfer({
-      args: { receiver_id: config.usnId, amount: '1000000000000' },
+  it('should unwind depositing after failure', async () => {
+    await global.usdtContract.ft_transfer_call({
+      args: {
+        receiver_id: config.usnId,
+        amount: '1000000000000',
+        msg: 'liquidity',
+      },
       amount: '1',
+      gas: GAS_FOR_CALL,
     });
 
-    // Should fail at the `add_stable_liquidity` cross-contract call.
-    // But deposit already belongs to the ref.finance account.
-    await assert.rejects(
-      async () => {
-        await dao.transfer_stable_liquidity({
-          args: { whole_amount: '1000000' },
-          amount: '2',
-          gas: GAS_FOR_CALL,
-        });
-      },
-      (err) => {
-        assert.match(
-          err.message,
-          /ERR_STORAGE_DEPOSIT need 780000000000000000000/
-        );
-        return true;
-      }
-    );
-
+    const poolInfo = await global.refContract.get_stable_pool({ pool_id: 0 });
     const refUsdt = await global.usdtContract.ft_balance_of({
       account_id: config.refId,
     });
@@ -509,34 +547,39 @@ describe('Stable Pool (USDT/USN)', async function () {
       account_id: config.refId,
     });
 
-    await assert.notEqual(refUsdt, '0');
-    await assert.notEqual(refUsn, '0');
-    await assert.equal(
+    // Fails at the `add_stable_liquidity` cross-contract call,
+    // then deposits are withdrawn back from the ref.finance account.
+    await dao.transfer_stable_liquidity({
+      args: { pool_id: 0, amounts: ['1000000000000'], min_shares: '0' },
+      amount: '2',
+      gas: GAS_FOR_CALL,
+    });
+
+    assert.equal(
+      await global.usdtContract.ft_balance_of({ account_id: config.refId }),
+      refUsdt
+    );
+    assert.equal(
+      await global.usnContract.ft_balance_of({ account_id: config.refId }),
+      refUsn
+    );
+    assert.equal(
       await global.usdtContract.ft_balance_of({ account_id: config.usnId }),
-      '0'
+      '1000000000000'
+    );
+    assert.deepEqual(
+      (await global.refContract.get_stable_pool({ pool_id: 0 })).amounts,
+      poolInfo.amounts
     );
-
-    const poolInfo = await global.refContract.get_stable_pool({ pool_id: 0 });
 
     await assert.doesNotReject(async () => {
       await dao.transfer_stable_liquidity({
-        args: { whole_amount: '1000000' },
+        args: { pool_id: 0, amounts: ['1000000000000'], min_shares: '0' },
         amount: MAX_TRANSFER_COST,
         gas: GAS_FOR_CALL,
       });
     });
 
-    const refUsdt2 = await global.usdtContract.ft_balance_of({
-      account_id: config.refId,
-    });
-    const refUsn2 = await global.usnContract.ft_balance_of({
-      account_id: config.refId,
-    });
-
-    // The second call of transfer_stable_liquidity should not mint additional money.
-    await assert.equal(refUsdt, refUsdt2);
-    await assert.equal(refUsn, refUsn2);
-
     const poolInfo2 = await global.refContract.get_stable_pool({ pool_id: 0 });
     assert.notDeepEqual(poolInfo.amounts, poolInfo2.amounts);
 
@@ -549,17 +592,25 @@ describe('Stable Pool (USDT/USN)', async function () {
   });
 
   it('should fail having not enough USDT', async () => {
-    // Should fail after the 1st ft_transfer_call.
+    // USDT of PSM reserves isn't spent on liquidity.
+    await global.usdtContract.ft_transfer({
+      args: { receiver_id: config.usnId, amount: '1000000000000' },
+      amount: '1',
+    });
+
     await assert.rejects(
       async () => {
         await dao.transfer_stable_liquidity({
-          args: { whole_amount: '1000000' }, // $1 mln.
+          args: { pool_id: 0, amounts: ['1000000000000'], min_shares: '0' }, // $1 mln.
           amount: MAX_TRANSFER_COST,
           gas: GAS_FOR_CALL,
         });
       },
       (err) => {
-        assert.match(err.message, /Not enough USDT/);
+        assert.match(
+          err.message,
+          /Not enough usdt.test.near in liquidity funds/
+        );
         return true;
       }
     );
@@ -569,7 +620,7 @@ describe('Stable Pool (USDT/USN)', async function () {
     await assert.rejects(
       async () => {
         await dao.transfer_stable_liquidity({
-          args: { whole_amount: '1000000' },
+          args: { pool_id: 0, amounts: ['1000000000000'], min_shares: '0' },
           amount: '1',
           gas: GAS_FOR_CALL,
         });
@@ -584,9 +635,288 @@ describe('Stable Pool (USDT/USN)', async function () {
     );
   });
 
+  it('should unwind having too few shares', async () => {
+    await global.usdtContract.ft_transfer_call({
+      args: {
+        receiver_id: config.usnId,
+        amount: '1000000000000',
+        msg: 'liquidity',
+      },
+      amount: '1',
+      gas: GAS_FOR_CALL,
+    });
+
+    const poolInfo = await global.refContract.get_stable_pool({ pool_id: 0 });
+    const usnSupply = await global.usnContract.ft_total_supply();
+
+    // About 1 share (18 decimals) per $1 could be minted.
+    await dao.transfer_stable_liquidity({
+      args: {
+        pool_id: 0,
+        amounts: ['1000000000000'],
+        min_shares: '2000000000000000000000000',
+      },
+      amount: MAX_TRANSFER_COST,
+      gas: GAS_FOR_CALL,
+    });
+
+    assert.deepEqual(
+      (await global.refContract.get_stable_pool({ pool_id: 0 })).amounts,
+      poolInfo.amounts
+    );
+    assert.equal(await global.usnContract.ft_total_supply(), usnSupply);
+    assert.equal(
+      await global.usdtContract.ft_balance_of({ account_id: config.usnId }),
+      '1000000000000'
+    );
+    assert.equal(
+      await dao.liquidity_funds({ token_id: config.usdtId }),
+      '1000000000000'
+    );
+  });
+
   after(async () => {
     await dao.set_owner({
       args: { owner_id: config.usnId },
     });
   });
 });
+
+describe('Peg Stability Module (USDT)', async function () {
+  this.timeout(15000);
+
+  before(async () => {
+    await global.usnContract.psm_add_asset({
+      args: {
+        asset_id: config.usdtId,
+        decimals: 6,
+        fee: '1000', // 0.1%
+        debt_ceiling: '1000000000000000000000000', // $1 mln.
+      },
+    });
+    await global.usdtContract.mint({
+      args: { account_id: config.aliceId, amount: '1000000000' }, // $1000
+    });
+  });
+
+  it('should mint USN for USDT', async () => {
+    const usnBefore = await global.aliceContract.ft_balance_of({
+      account_id: config.aliceId,
+    });
+
+    await global.aliceUsdt.ft_transfer_call({
+      args: { receiver_id: config.usnId, amount: '1000000000', msg: '' },
+      amount: ONE_YOCTO,
+      gas: GAS_FOR_CALL,
+    });
+
+    const usnAfter = await global.aliceContract.ft_balance_of({
+      account_id: config.aliceId,
+    });
+    assert.equal(
+      new BN(usnAfter, 10).sub(new BN(usnBefore, 10)).toString(),
+      '999000000000000000000' // $999
+    );
+
+    const asset = await global.aliceContract.psm_asset({
+      asset_id: config.usdtId,
+    });
+    assert.equal(asset.reserve, '1000000000');
+    assert.equal(asset.debt, '999000000000000000000');
+  });
+
+  it('should redeem USN for USDT', async () => {
+    const amount = await global.aliceContract.psm_redeem({
+      args: { asset_id: config.usdtId, amount: '500000000000000000000' },
+      amount: ONE_YOCTO,
+      gas: GAS_FOR_CALL,
+    });
+    assert.equal(amount, '499500000'); // $499.5
+
+    assert.equal(
+      await global.aliceUsdt.ft_balance_of({ account_id: config.aliceId }),
+      '499500000'
+    );
+  });
+
+  it('should NOT redeem more than the reserve', async () => {
+    await assert.rejects(
+      async () => {
+        await global.aliceContract.psm_redeem({
+          args: { asset_id: config.usdtId, amount: '600000000000000000000' },
+          amount: ONE_YOCTO,
+          gas: GAS_FOR_CALL,
+        });
+      },
+      (err) => {
+        assert.match(err.message, /Not enough usdt.test.near in the reserve/);
+        return true;
+      }
+    );
+  });
+});
+
+describe('Operation Journal', async function () {
+  this.timeout(15000);
+
+  it('should record a buy operation', async () => {
+    const amount = await global.aliceContract.buy({
+      args: { operation_id: 'buy-1' },
+      amount: ONE_NEAR,
+      gas: GAS_FOR_CALL,
+    });
+
+    const operation = await global.aliceContract.operation({
+      account_id: config.aliceId,
+      operation_id: 'buy-1',
+    });
+    assert.equal(operation.kind, 'Buy');
+    assert.equal(operation.status, 'Completed');
+    assert.equal(operation.near, ONE_NEAR);
+    assert.equal(operation.usn, amount);
+  });
+
+  it('should reject a repeated operation id', async () => {
+    await assert.rejects(
+      async () => {
+        await global.aliceContract.buy({
+          args: { operation_id: 'buy-1' },
+          amount: ONE_NEAR,
+          gas: GAS_FOR_CALL,
+        });
+      },
+      (err) => {
+        assert.match(err.message, /Operation 'buy-1' already exists/);
+        return true;
+      }
+    );
+  });
+
+  it('should record a failed buy operation', async () => {
+    await assert.rejects(async () => {
+      await global.aliceContract.buy({
+        args: {
+          expected: { multiplier: '111428', slippage: '10', decimals: 28 },
+          operation_id: 'buy-2',
+        },
+        amount: ONE_NEAR,
+        gas: GAS_FOR_CALL,
+      });
+    });
+
+    const operation = await global.aliceContract.operation({
+      account_id: config.aliceId,
+      operation_id: 'buy-2',
+    });
+    assert.equal(operation.status, 'Failed');
+  });
+
+  it('should record a sell operation', async () => {
+    const near = await global.aliceContract.sell({
+      args: { amount: '11032461000000000000', operation_id: 'sell-1' },
+      amount: ONE_YOCTO,
+      gas: GAS_FOR_CALL,
+    });
+
+    const operation = await global.aliceContract.operation({
+      account_id: config.aliceId,
+      operation_id: 'sell-1',
+    });
+    assert.equal(operation.kind, 'Sell');
+    assert.equal(operation.status, 'Completed');
+    assert.equal(operation.near, near);
+    assert.equal(operation.usn, '11032461000000000000');
+  });
+});
+
+describe('Staking Reserves', async function () {
+  this.timeout(20000);
+
+  const STAKED = '1000000000000000000000000'; // 1 NEAR
+  const REWARD = '100000000000000000000000'; // 0.1 NEAR
+  const TOTAL = '1100000000000000000000000'; // 1.1 NEAR
+
+  before(async () => {
+    await global.aliceContract.buy({
+      args: {},
+      amount: '10000000000000000000000000', // 10 NEAR
+      gas: GAS_FOR_CALL,
+    });
+    await global.usnContract.set_staking_pool({
+      args: { pool_id: config.stakingPoolId },
+    });
+    await global.usnContract.set_staking_policy({
+      args: { max_share: '500000', liquid_floor: '0' }, // 50%
+    });
+  });
+
+  it('should stake reserves', async () => {
+    const amount = await global.usnContract.stake_reserves({
+      args: { amount: STAKED },
+      gas: GAS_FOR_CALL,
+    });
+    assert.equal(amount, STAKED);
+
+    assert.equal(
+      await global.stakingPoolContract.get_account_staked_balance({
+        account_id: config.usnId,
+      }),
+      STAKED
+    );
+    assert.equal((await global.usnContract.staking()).principal, STAKED);
+  });
+
+  it('should harvest staking rewards', async () => {
+    await global.stakingPoolContract.add_reward({
+      args: { account_id: config.usnId },
+      amount: REWARD,
+    });
+
+    const rewards = await global.usnContract.harvest_staking_rewards({
+      args: {},
+      gas: GAS_FOR_CALL,
+    });
+    assert.equal(rewards, REWARD);
+    assert.equal((await global.usnContract.staking()).rewards, REWARD);
+  });
+
+  it('should unstake reserves', async () => {
+    const amount = await global.usnContract.unstake_reserves({
+      args: { amount: TOTAL },
+      gas: GAS_FOR_CALL,
+    });
+    assert.equal(amount, TOTAL);
+
+    assert.equal(
+      await global.stakingPoolContract.get_account_unstaked_balance({
+        account_id: config.usnId,
+      }),
+      TOTAL
+    );
+    assert.equal((await global.usnContract.staking()).unstaked, TOTAL);
+  });
+
+  it('should withdraw unstaked reserves', async () => {
+    const amount = await global.usnContract.withdraw_staked_reserves({
+      args: { amount: TOTAL },
+      gas: GAS_FOR_CALL,
+    });
+    assert.equal(amount, TOTAL);
+
+    assert.equal(
+      await global.stakingPoolContract.get_account_total_balance({
+        account_id: config.usnId,
+      }),
+      '0'
+    );
+
+    const staking = await global.usnContract.staking();
+    assert.equal(staking.principal, '0');
+    assert.equal(staking.rewards, '0');
+    assert.equal(staking.unstaked, '0');
+  });
+
+  after(async () => {
+    await global.usnContract.set_staking_pool({ args: { pool_id: null } });
+  });
+});